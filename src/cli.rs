@@ -0,0 +1,904 @@
+#![allow(clippy::upper_case_acronyms)]
+
+//! 命令行的输出，所有操作都通过 [`bcdown::Library`] 完成
+
+use bcdown::config::{Config, Direction, ImageFormat, Quality, SpreadMode};
+use bcdown::network::{self, UserInfo};
+use bcdown::{
+    CancelToken, CheckStatus, EpisodeState, Error, Event, Events, ExportOptions, ImportStatus,
+    JobKind, Journal, Library, ListOptions, Metrics, PrunePolicy, RemoteInfo, Summary,
+};
+use chrono::TimeZone;
+use colorful::Colorful;
+use indicatif::{HumanDuration, ProgressBar};
+use qrcode::QrCode;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process::exit;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::Receiver;
+
+fn bytes_with_unit(bytes: u64) -> String {
+    let mut bytes = bytes as f64;
+    let mut unit = "B";
+    if bytes > 1024. {
+        bytes /= 1024.;
+        unit = "KB";
+    }
+    if bytes > 1024. {
+        bytes /= 1024.;
+        unit = "MB";
+    }
+    if bytes > 1024. {
+        bytes /= 1024.;
+        unit = "GB";
+    }
+    if bytes > 1024. {
+        bytes /= 1024.;
+        unit = "TB";
+    }
+    format!("{:.2} {}", bytes, unit)
+}
+
+/// 输出错误并退出
+fn fail(e: Error) -> ! {
+    let mut log = paris::Logger::new();
+    log.error(e);
+    exit(1);
+}
+
+fn load_config() -> Config {
+    let mut log = paris::Logger::new();
+    match Config::path() {
+        Some(path) => log.info(format!("加载配置文件：{}", path.display())),
+        None => log.warn("未找到配置文件，将使用默认配置"),
+    };
+    Config::load().unwrap_or_else(|e| fail(e))
+}
+
+fn load_library() -> Library {
+    Library::new(load_config())
+}
+
+/// 加载配置，`limit` 不为空时覆盖配置中的限速
+fn load_config_with_limit(limit: Option<String>) -> Config {
+    let mut config = load_config();
+    if let Some(limit) = limit {
+        config.set_rate_limit(&limit).unwrap_or_else(|e| fail(e));
+    }
+    if let Some(rate) = config.current_rate_limit() {
+        paris::Logger::new().info(format!("下载限速：{} / s", bytes_with_unit(rate)));
+    }
+    config
+}
+
+fn parse_id_or_link(id_or_link: &str) -> u32 {
+    bcdown::parse_id(id_or_link).unwrap_or_else(|e| fail(e))
+}
+
+fn print_user_info(user_info: &UserInfo) {
+    let mut log = paris::Logger::new();
+    log.info("登录信息有效！");
+    log.info(format!("用户名：{}", user_info.name));
+    log.info(format!("漫币余额：{}", user_info.coin));
+}
+
+/// 输出配置信息
+pub async fn info() {
+    let library = load_library();
+    let mut log = paris::Logger::new();
+    log.info("bcdown 版本: 0.2.2");
+    match library.user_info().await {
+        Ok(Some(user_info)) => print_user_info(&user_info),
+        Ok(None) => {
+            log.warn("未登录或登录已过期");
+        }
+        Err(e) => fail(e),
+    }
+    let config = library.config();
+    log.info(format!("缓存目录：{}", config.cache_dir));
+    log.info(format!(
+        "缓存目录大小：{}",
+        bytes_with_unit(library.cache_size())
+    ));
+    log.info(format!("默认下载目录：{}", config.default_download_dir));
+    if let Ok(network) = config.network.with_env() {
+        if let Some(proxy) = &network.proxy {
+            log.info(format!("代理：{}", proxy));
+        }
+        for (scheme, proxy) in [("http", &network.http_proxy), ("https", &network.https_proxy)] {
+            if let Some(proxy) = proxy {
+                log.info(format!("{} 代理：{}", scheme, proxy));
+            }
+        }
+    }
+}
+
+/// 清空缓存
+pub fn clear() {
+    let library = load_library();
+    let mut log = paris::Logger::new();
+    log.info(format!("清空文件夹: {}", library.config().cache_dir));
+    if let Err(e) = library.clear() {
+        fail(e);
+    }
+}
+
+/// 清除一部漫画或其中几话的缓存
+pub fn clear_comic(id_or_link: String, range: String, keep_meta: bool) {
+    let id = parse_id_or_link(&id_or_link);
+    let library = load_library();
+    let mut log = paris::Logger::new();
+    let (episodes, freed) = library
+        .clear_comic(id, &range, keep_meta)
+        .unwrap_or_else(|e| fail(e));
+    if episodes == 0 && freed == 0 {
+        log.warn("范围内没有已缓存的章节");
+        return;
+    }
+    log.success(format!(
+        "已清除 {} 话，释放 {}",
+        episodes,
+        bytes_with_unit(freed)
+    ));
+}
+
+/// 把其他工具下载的漫画导入缓存
+pub async fn import(id_or_link: String, path: PathBuf, by_order: bool, range: String) {
+    let id = parse_id_or_link(&id_or_link);
+    let library = load_library();
+    let mut log = paris::Logger::new();
+    log.loading("正在导入...");
+    let report = library
+        .import(id, &path, by_order, &range)
+        .await
+        .unwrap_or_else(|e| {
+            log.done();
+            fail(e)
+        });
+    log.done();
+    log.success(format!("漫画标题：{}", report.title.bold()));
+    let mut imported = 0;
+    for item in &report.episodes {
+        let ep = &item.episode;
+        let status = match &item.status {
+            ImportStatus::Imported {
+                by_hash,
+                by_order,
+                missing,
+            } => {
+                imported += 1;
+                let mut status = format!("按 sha1 匹配 {} 张", by_hash);
+                if *by_order > 0 {
+                    status += &format!("，按顺序匹配 {} 张", by_order);
+                }
+                if *missing > 0 {
+                    status += &format!("，缺少 {} 张", missing).red().to_string();
+                }
+                status
+            }
+            ImportStatus::Cached => "已缓存，跳过".to_string(),
+            ImportStatus::Failed(reason) => format!("{}", reason.clone().red()),
+        };
+        println!(
+            "    {} - {} {} ({}) <- {}：{}",
+            ep.ord, ep.short_title, ep.title, ep.id, item.source, status
+        );
+    }
+    if !report.unmatched.is_empty() {
+        log.warn(format!(
+            "{} 个文件夹或压缩包没有对应的章节，可以尝试 --by-order：",
+            report.unmatched.len()
+        ));
+        for name in &report.unmatched {
+            println!("    {}", name);
+        }
+    }
+    log.success(format!("已导入 {} 话，缺少的图片可以通过 fetch 下载", imported));
+}
+
+/// 把缓存中的漫画打包
+pub fn export_bundle(id_or_link: String, range: String, output: Option<PathBuf>) {
+    let id = parse_id_or_link(&id_or_link);
+    let library = load_library();
+    let mut log = paris::Logger::new();
+    log.loading("正在打包...");
+    let (path, bundle) = library
+        .export_bundle(id, &range, output)
+        .unwrap_or_else(|e| {
+            log.done();
+            fail(e)
+        });
+    log.done();
+    let size = std::fs::metadata(&path).map(|meta| meta.len()).unwrap_or(0);
+    log.success(format!(
+        "已将 {} 的 {} 话 ({} 张图片) 打包至：{} ({})",
+        bundle.title,
+        bundle.episodes.len(),
+        bundle.pages,
+        path.display(),
+        bytes_with_unit(size)
+    ));
+}
+
+/// 把缓存包合并到缓存中
+pub fn import_bundle(path: PathBuf) {
+    let library = load_library();
+    let mut log = paris::Logger::new();
+    log.loading("正在导入...");
+    let report = library.import_bundle(&path).unwrap_or_else(|e| {
+        log.done();
+        fail(e)
+    });
+    log.done();
+    for name in &report.corrupted {
+        log.warn(format!("图片校验失败，已跳过：{}", name));
+    }
+    log.success(format!(
+        "已导入 {} ({})：{} 话，{} 张图片，跳过本地已有的 {} 张",
+        report.bundle.title,
+        report.bundle.comic_id,
+        report.episodes,
+        report.imported,
+        report.existing
+    ));
+}
+
+/// 按条件清理缓存
+pub fn prune(
+    keep: Option<usize>,
+    exported_days: Option<u64>,
+    unwatched: bool,
+    max_size: Option<String>,
+    dry_run: bool,
+) {
+    let mut log = paris::Logger::new();
+    let max_size = max_size.map(|size| bcdown::parse_size(&size).unwrap_or_else(|e| fail(e)));
+    let policy = PrunePolicy {
+        keep_latest: keep,
+        exported_days,
+        unwatched,
+        max_size,
+    };
+    if policy.is_empty() {
+        log.error("请至少指定一个清理条件：--keep --exported-days --unwatched --max-size");
+        return;
+    }
+    let library = load_library();
+    let report = library.prune(&policy, dry_run).unwrap_or_else(|e| fail(e));
+    for item in &report.items {
+        let name = match &item.episode {
+            Some(episode) => format!(
+                "{} - {} {} {}",
+                item.comic_title, episode.ord, episode.short_title, episode.title
+            ),
+            None => format!("{} ({})", item.comic_title, item.comic_id),
+        };
+        log.info(format!(
+            "{}：{} ({})",
+            name,
+            bytes_with_unit(item.bytes),
+            item.reason
+        ));
+    }
+    let verb = if report.dry_run { "将释放" } else { "已释放" };
+    log.success(format!(
+        "共 {} 项，{} {}，缓存大小 {} -> {}",
+        report.items.len(),
+        verb,
+        bytes_with_unit(report.freed()),
+        bytes_with_unit(report.before),
+        bytes_with_unit(report.before.saturating_sub(report.freed()))
+    ));
+}
+
+pub enum LoginMethod {
+    SESSDATA(String),
+    QRCODE,
+}
+
+pub async fn show_login_info() {
+    let library = load_library();
+    let mut log = paris::Logger::new();
+    match library.user_info().await {
+        Ok(Some(user_info)) => print_user_info(&user_info),
+        Ok(None) => {
+            log.info("登录信息无效或未登录！");
+        }
+        Err(e) => fail(e),
+    }
+}
+
+pub async fn login(method: LoginMethod) {
+    let mut log = paris::Logger::new();
+    let mut config = load_config();
+    match method {
+        LoginMethod::SESSDATA(sessdata) => {
+            config.sessdata = sessdata;
+            let session = network::Session::new(&config).unwrap_or_else(|e| fail(e));
+            match network::get_user_info(&session).await {
+                Ok(Some(user_info)) => {
+                    print_user_info(&user_info);
+                    config.save().unwrap_or_else(|e| fail(e));
+                }
+                Ok(None) => {
+                    log.error("登录信息无效！");
+                }
+                Err(e) => fail(e),
+            }
+        }
+        LoginMethod::QRCODE => {
+            log.loading("加载二维码");
+            let session = network::Session::new(&config).unwrap_or_else(|e| fail(e));
+            let (qr_data, oauth) = network::get_qr_data(&session).await.unwrap_or_else(|e| {
+                log.done();
+                fail(e)
+            });
+            log.done();
+            let code = QrCode::new(&qr_data).unwrap();
+            let image = code
+                .render::<qrcode::render::unicode::Dense1x2>()
+                .dark_color(qrcode::render::unicode::Dense1x2::Dark)
+                .light_color(qrcode::render::unicode::Dense1x2::Light)
+                .build();
+            println!("{}", image);
+            log.success("二维码已生成，请扫描二维码登录");
+            log.info(format!("如果显示错误，请手动访问：{}", qr_data));
+            log.loading("等待扫描...");
+            let mut last_status = "NotScan";
+            loop {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                let status = network::check_qr_status(&session, oauth.clone())
+                    .await
+                    .unwrap_or_else(|e| {
+                        log.done();
+                        fail(e)
+                    });
+                match status {
+                    network::QRStatus::NotScan => {
+                        if last_status != "NotScan" {
+                            log.done();
+                            log.loading("等待扫描...");
+                        }
+                        last_status = "NotScan";
+                    }
+                    network::QRStatus::Scanning => {
+                        if last_status != "Scanning" {
+                            log.done();
+                            log.loading("等待确认...");
+                        }
+                        last_status = "Scanning";
+                    }
+                    network::QRStatus::Complete(sessdata) => {
+                        log.done();
+                        log.success("登录成功！");
+
+                        config.sessdata = sessdata;
+                        config.save().unwrap_or_else(|e| fail(e));
+                        // 登录后的请求需要带上新的 SESSDATA
+                        let session = network::Session::new(&config).unwrap_or_else(|e| fail(e));
+                        match network::get_user_info(&session).await {
+                            Ok(Some(user_info)) => print_user_info(&user_info),
+                            Ok(None) => {
+                                log.warn("未登录或登录已过期");
+                            }
+                            Err(e) => fail(e),
+                        }
+
+                        return;
+                    }
+                    network::QRStatus::Invalid => {
+                        log.error("二维码已失效，请重新运行程序");
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// 使用保存的漫画信息时提示获取的时间
+fn warn_saved_info(info: &RemoteInfo, offline: bool) {
+    if !info.cached {
+        return;
+    }
+    let mut log = paris::Logger::new();
+    let age = HumanDuration(info.age());
+    if offline {
+        log.warn(format!("离线模式，使用 {} 前保存的漫画信息", age));
+    } else {
+        log.warn(format!("网络请求失败，使用 {} 前保存的漫画信息", age));
+    }
+    if info.is_stale() {
+        log.warn("保存的漫画信息已超过 7 天，章节和解锁状态可能已经变化");
+    }
+}
+
+/// 列出缓存中的漫画，`id_or_link` 为空时列出全部
+pub async fn list(id_or_link: Option<String>, mut options: ListOptions) {
+    options.id = id_or_link.map(|id_or_link| parse_id_or_link(&id_or_link));
+    let library = load_library();
+    let mut log = paris::Logger::new();
+    if options.remote && !options.offline {
+        log.loading("获取漫画信息...");
+    }
+    let comics = library.list(&options).await;
+    if options.remote && !options.offline {
+        log.done();
+    }
+    let comics = comics.unwrap_or_else(|e| fail(e));
+    for comic in &comics {
+        let mut summary = format!(
+            "{} - {}：{}，{}/{} 张图片",
+            comic.id,
+            comic.title,
+            bytes_with_unit(comic.size),
+            comic.pages,
+            comic.total_pages
+        );
+        if let Some(updated) = chrono::Local.timestamp_opt(comic.updated, 0).single().filter(|_| comic.updated > 0) {
+            summary.push_str(&format!("，更新于 {}", updated.format("%Y-%m-%d %H:%M")));
+        }
+        if options.remote {
+            match comic.new_episodes {
+                Some(0) => summary.push_str("，没有新章节"),
+                Some(count) => summary.push_str(&format!("，有 {} 话新章节", count)),
+                None => summary.push_str("，无法获取漫画信息"),
+            }
+        }
+        log.info(summary);
+        match &comic.remote {
+            Some(remote) if options.remote => warn_saved_info(remote, options.offline),
+            Some(remote) if remote.is_stale() => {
+                log.warn("保存的漫画信息已超过 7 天，未缓存章节的解锁状态可能已经变化");
+            }
+            _ => {}
+        }
+        let episodes = comic
+            .episodes
+            .iter()
+            .map(|e| {
+                let state = match e.state {
+                    EpisodeState::Downloaded => e.state.to_string().green(),
+                    EpisodeState::Partial => format!("{} {}/{}", e.state, e.pages, e.total_pages).yellow(),
+                    EpisodeState::Missing | EpisodeState::Locked => e.state.to_string().red(),
+                };
+                format!("    {} - {} {} ({}) - {}", e.ord, e.short_title, e.title, e.id, state)
+            })
+            .collect::<Vec<_>>();
+        println!("{}", episodes.join("\n"));
+    }
+}
+
+pub async fn check(id_or_link: String, range: String, offline: bool) {
+    let id = parse_id_or_link(&id_or_link);
+    let mut log = paris::Logger::new();
+    let library = load_library();
+    let (remote_info, results) = match library.check(id, &range, offline).await {
+        Ok(result) => result,
+        Err(Error::NotCached(_)) => {
+            println!("Doesn't find any cache!");
+            return;
+        }
+        Err(e) => fail(e),
+    };
+    warn_saved_info(&remote_info, offline);
+    let comic_info = &remote_info.comic;
+    log.success(format!("漫画标题：{}", comic_info.title.as_str().bold()));
+    log.success(format!(
+        "漫画作者 / 出版社：{}",
+        comic_info.author_name.join(",")
+    ));
+    log.success(format!("漫画标签：{}", comic_info.styles.join(",")));
+
+    let mut count = 0;
+    let mut err_episodes: Vec<String> = Vec::new();
+    let mut err_info: Vec<String> = Vec::new();
+    let episodes: Vec<String> = results
+        .iter()
+        .map(|result| {
+            let ep = &result.episode;
+            let lock = if ep.is_locked {
+                "锁定".red()
+            } else {
+                "已解锁".green()
+            };
+            let line = match &result.status {
+                CheckStatus::Ok => {
+                    return format!("    {} - {} {} ({}) - {}", ep.ord, ep.short_title, ep.title, ep.id, "OK".green());
+                }
+                CheckStatus::Corrupted(pages) => {
+                    for page in pages {
+                        err_info.push(format!("{} {} {} {} 页{} {} - {} {}", "错误：".red(), ep.ord, ep.short_title, ep.title, page.index, page.expected, page.actual, false));
+                    }
+                    format!("    {} - {} {} ({}) - {} {}", ep.ord, ep.short_title, ep.title, ep.id, lock, "损坏".red())
+                }
+                CheckStatus::Missing => {
+                    format!("    {} - {} {} ({}) - {} {}", ep.ord, ep.short_title, ep.title, ep.id, lock, "未下载".yellow())
+                }
+            };
+            count += 1;
+            err_episodes.push(line.clone());
+            line
+        })
+        .collect();
+    log.success("漫画章节：\n");
+    println!("{}", episodes.join("\n"));
+    println!("错误信息：\n{}", err_info.join("\n"));
+    println!("存在问题的章节：{}", count);
+    println!("{}", err_episodes.join("\n"));
+}
+
+pub async fn search(id_or_link: String, offline: bool) {
+    let id = parse_id_or_link(&id_or_link);
+    let mut log = paris::Logger::new();
+    let library = load_library();
+    log.loading("获取漫画信息...");
+    let remote_info = library.remote_info(id, offline).await.unwrap_or_else(|e| {
+        log.done();
+        fail(e)
+    });
+    log.done();
+    warn_saved_info(&remote_info, offline);
+    let comic_info = remote_info.comic;
+    log.success(format!("漫画标题：{}", comic_info.title.bold()));
+    log.success(format!(
+        "漫画作者 / 出版社：{}",
+        comic_info.author_name.join(",")
+    ));
+    log.success(format!("漫画标签：{}", comic_info.styles.join(",")));
+
+    let episodes: Vec<String> = comic_info
+        .ep_list
+        .iter()
+        .map(|ep| {
+            if ep.is_locked {
+                format!("    {} - {} - {} {}", ep.ord, "锁定".red(), ep.short_title, ep.title)
+            } else {
+                format!("    {} - {} - {} {}", ep.ord, "可用".green(), ep.short_title, ep.title)
+            }
+        })
+        .collect();
+    log.success("漫画章节：\n");
+    println!("{}", episodes.join("\n"));
+}
+
+/// 第一次按下 Ctrl+C 时取消任务，等待进行中的工作完成；再次按下时直接退出
+fn cancel_on_ctrlc(cancel: CancelToken) {
+    ctrlc::set_handler(move || {
+        if cancel.is_cancelled() {
+            exit(130);
+        }
+        cancel.cancel();
+        paris::Logger::new().warn("正在停止，再次按下 Ctrl+C 强制退出");
+    })
+        .expect("无法设置 ctrl+c 处理函数");
+}
+
+pub async fn fetch(
+    id_or_link: String,
+    range: String,
+    limit: Option<String>,
+    quality: Option<Quality>,
+    format: Option<ImageFormat>,
+) {
+    let id = parse_id_or_link(&id_or_link);
+    let mut config = load_config_with_limit(limit);
+    if let Some(quality) = quality {
+        config.quality = quality;
+    }
+    if let Some(format) = format {
+        config.image_format = format;
+    }
+    if !config.variant().is_original() {
+        paris::Logger::new().info(format!("图片尺寸和格式：{}", config.variant()));
+    }
+    let library = Library::new(config);
+    let events = Events::new();
+    let cancel = CancelToken::new();
+    let receiver = events.subscribe();
+    let task_cancel = cancel.clone();
+    // 下载结束后通道随之关闭，报告线程才能退出
+    let task = async move { library.fetch(id, &range, &events, &task_cancel).await };
+    report_fetch(receiver, cancel, task).await;
+}
+
+/// 显示下载进度，直到下载完成或被取消
+async fn report_fetch(
+    mut receiver: Receiver<Event>,
+    cancel: CancelToken,
+    task: impl Future<Output = bcdown::Result<usize>>,
+) {
+    let mut log = paris::Logger::new();
+    let style = indicatif::ProgressStyle::default_bar()
+        .template("[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}")
+        .unwrap()
+        .progress_chars("##-");
+
+    let bar_overall = ProgressBar::new(0);
+    bar_overall.set_style(style);
+    cancel_on_ctrlc(cancel);
+
+    let bar = bar_overall.clone();
+    let reporter = tokio::task::spawn(async move {
+        let mut log = paris::Logger::new();
+        let mut metrics = Metrics::new();
+        bar.set_message("计算下载速度...");
+        loop {
+            let event = match receiver.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            };
+            metrics.record(&event);
+            match event {
+                Event::FetchPlanned { episodes, .. } => {
+                    bar.suspend(|| {
+                        log.info("将要下载的漫画章节：\n");
+                        let episodes: Vec<String> = episodes
+                            .iter()
+                            .map(|ep| format!("    {} - {} {}", ep.ord, ep.short_title, ep.title))
+                            .collect();
+                        println!("{}", episodes.join("\n"));
+                        log.info("启动下载线程...");
+                    });
+                }
+                Event::Progress { done, total, .. } => {
+                    bar.set_length(total as u64);
+                    bar.set_position(done as u64);
+                    let rate = metrics.rate();
+                    bar.set_message(format!(
+                        "{} / s {:.1} 页/s 剩余 {}",
+                        bytes_with_unit(rate.bytes_per_second as u64),
+                        rate.pages_per_second,
+                        rate.eta
+                            .map(|eta| HumanDuration(eta).to_string())
+                            .unwrap_or_else(|| "-".to_string())
+                    ));
+                }
+                Event::EpisodeFailed { episode, reason } => {
+                    bar.println(format!("任务 {} 失败：{}，3s后重试!", episode, reason));
+                }
+                _ => {}
+            }
+        }
+        metrics.summary()
+    });
+
+    match task.await {
+        Ok(0) => {
+            log.warn("没有需要下载的章节");
+        }
+        Ok(_) => {
+            let summary = reporter.await.ok();
+            bar_overall.finish();
+            log.success("下载完成");
+            summary.iter().for_each(print_summary);
+        }
+        Err(Error::Cancelled(job)) => {
+            let summary = reporter.await.ok();
+            bar_overall.abandon();
+            log.warn("用户取消下载");
+            summary.iter().for_each(print_summary);
+            log.info(format!("使用 bcdown resume {} 继续下载", job));
+        }
+        Err(e) => fail(e),
+    }
+}
+
+/// 输出下载的统计信息
+fn print_summary(summary: &Summary) {
+    let mut log = paris::Logger::new();
+    let seconds = summary.duration.as_secs_f64();
+    log.info(format!(
+        "完成 {}/{} 话，共 {} 张图片，{}，用时 {}，平均 {} / s",
+        summary.finished,
+        summary.planned,
+        summary.pages,
+        bytes_with_unit(summary.bytes),
+        HumanDuration(summary.duration),
+        bytes_with_unit(if seconds > 0. {
+            (summary.bytes as f64 / seconds) as u64
+        } else {
+            0
+        })
+    ));
+    if !summary.retries.is_empty() {
+        log.warn(format!("失败 {} 次：", summary.failures()));
+        for (ep, count) in &summary.retries {
+            println!("    {} - {} {}：{} 次", ep.ord, ep.short_title, ep.title, count);
+        }
+    }
+    if !summary.locked.is_empty() {
+        log.warn(format!("跳过 {} 话未解锁的章节：", summary.locked.len()));
+        for ep in &summary.locked {
+            println!("    {} - {} {}", ep.ord, ep.short_title, ep.title);
+        }
+    }
+}
+
+/// 输出导出时实际使用的配置
+fn print_export_settings(library: &Library, id: u32, options: &ExportOptions) {
+    let mut log = paris::Logger::new();
+    let config = options.apply(library.config()).unwrap_or_else(|e| fail(e));
+    let comic = library.comic(id).unwrap_or_else(|| fail(Error::NotCached(id)));
+    log.info(format!("开始导出漫画：{}", comic.title));
+    log.info(format!(
+        "导出质量：{}",
+        if let Some(dpi) = config.dpi {
+            format!("{}dpi", dpi)
+        } else {
+            "最佳".to_string()
+        }
+    ));
+    if let Some(profile) = &config.profile {
+        log.info(format!("设备配置：{}", profile));
+    }
+    if let Some(ratio) = config.webtoon {
+        log.info(format!("条漫模式：按 {:.2} 的高宽比重新切分", ratio));
+    }
+    match config.spread {
+        SpreadMode::Keep => {}
+        SpreadMode::Split => {
+            log.info(format!(
+                "跨页处理：拆分为两页，{}",
+                if config.direction == Direction::Rtl {
+                    "从右到左"
+                } else {
+                    "从左到右"
+                }
+            ));
+        }
+        SpreadMode::Rotate => {
+            log.info("跨页处理：旋转为纵向");
+        }
+    }
+
+}
+
+pub fn export(id_or_link: String, options: ExportOptions) {
+    let id = parse_id_or_link(&id_or_link);
+    let library = load_library();
+    print_export_settings(&library, id, &options);
+    report_export(|events, cancel| library.export(id, &options, events, cancel));
+}
+
+/// 显示导出进度，直到导出完成或被取消
+fn report_export(task: impl FnOnce(&Events, &CancelToken) -> bcdown::Result<PathBuf>) {
+    let mut log = paris::Logger::new();
+    let bar = ProgressBar::new(0);
+    bar.set_style(
+        indicatif::ProgressStyle::default_bar()
+            .template("[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}")
+            .unwrap()
+            .progress_chars("##-"),
+    );
+    let events = Events::new();
+    let cancel = CancelToken::new();
+    cancel_on_ctrlc(cancel.clone());
+    let mut receiver = events.subscribe();
+    let reporter = bar.clone();
+    let reporter = std::thread::spawn(move || {
+        let bar = reporter;
+        let mut file = String::new();
+        loop {
+            match futures::executor::block_on(receiver.recv()) {
+                Ok(Event::ExportPlanned { episodes, .. }) => bar.set_length(episodes as u64),
+                Ok(Event::ExportStarted { file: started }) => {
+                    file = started;
+                    bar.set_message(format!("导出 {}...", file));
+                }
+                Ok(Event::Progress { done, eta, .. }) => {
+                    bar.set_position(done as u64);
+                    bar.set_message(format!("导出 {}... 剩余 {}", file, HumanDuration(eta)));
+                }
+                Ok(_) | Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+    let result = task(&events, &cancel);
+    drop(events);
+    reporter.join().ok();
+    bar.finish_and_clear();
+    match result {
+        Ok(out_dir) => {
+            log.success(format!("漫画导出至: {}", out_dir.display()));
+        }
+        Err(Error::Cancelled(job)) => {
+            log.warn("用户取消导出");
+            log.info(format!("使用 bcdown resume {} 继续导出", job));
+        }
+        Err(e) => fail(e),
+    }
+}
+
+fn print_journal(journal: &Journal) {
+    let description = match &journal.job {
+        JobKind::Fetch { range, pending, .. } => format!(
+            "下载 {}{}，剩余 {} 话",
+            journal.comic_id,
+            if range.is_empty() {
+                String::new()
+            } else {
+                format!(" ({})", range)
+            },
+            pending.len()
+        ),
+        JobKind::Export { options, finished } => format!(
+            "导出 {} 为 {}，已完成 {} 个文件",
+            journal.comic_id,
+            options.format,
+            finished.len()
+        ),
+    };
+    println!("    {} - {} - {}", journal.id, journal.created, description);
+}
+
+/// 继续一个未完成的任务，未指定ID时继续最近的一个
+pub async fn resume(job: Option<u64>, list: bool, discard: bool, limit: Option<String>) {
+    let mut log = paris::Logger::new();
+    let library = Library::new(load_config_with_limit(limit));
+    let journals = library.journals();
+    if list {
+        if journals.is_empty() {
+            log.info("没有未完成的任务");
+        } else {
+            log.info("未完成的任务：");
+            journals.iter().for_each(print_journal);
+        }
+        return;
+    }
+    let journal = match job {
+        Some(job) => library.journal(job).unwrap_or_else(|e| fail(e)),
+        None => match journals.into_iter().last() {
+            Some(journal) => journal,
+            None => {
+                log.info("没有未完成的任务");
+                return;
+            }
+        },
+    };
+    if discard {
+        library.discard(journal.id).unwrap_or_else(|e| fail(e));
+        log.success(format!("已放弃任务 {}", journal.id));
+        return;
+    }
+    log.info("继续任务：");
+    print_journal(&journal);
+    match &journal.job {
+        JobKind::Fetch { .. } => {
+            let events = Events::new();
+            let cancel = CancelToken::new();
+            let receiver = events.subscribe();
+            let task_cancel = cancel.clone();
+            let task = async move { library.resume_fetch(journal, &events, &task_cancel).await };
+            report_fetch(receiver, cancel, task).await;
+        }
+        JobKind::Export { options, .. } => {
+            print_export_settings(&library, journal.comic_id, options);
+            report_export(|events, cancel| library.resume_export(journal, events, cancel));
+        }
+    }
+}
+
+/// 启动本地漫画库服务器，`opds_only` 时只提供 OPDS 目录
+pub async fn serve(addr: SocketAddr, opds_only: bool) {
+    let mut log = paris::Logger::new();
+    let library = load_library();
+    let server = library.serve(addr, opds_only).unwrap_or_else(|e| {
+        log.error(format!("无法监听 {}：{}", addr, e));
+        exit(1);
+    });
+    if opds_only {
+        log.success(format!("OPDS 目录已启动：http://{}/opds", addr));
+    } else {
+        log.success(format!("漫画库已启动：http://{}", addr));
+        log.info(format!("OPDS 目录：http://{}/opds", addr));
+    }
+    if let Err(e) = server.await {
+        fail(e);
+    }
+}
@@ -0,0 +1,253 @@
+//! 缓存包，用于在不同电脑之间移动已经下载的漫画
+//!
+//! 缓存包是一个 zip 文件，结构如下：
+//!
+//! ```text
+//! bundle.toml          缓存包的说明，见 [`Bundle`]
+//! meta.toml            漫画信息
+//! cover.jpg            封面，可能没有
+//! <章节ID>/meta.toml   每一话的信息和图片列表
+//! pages/<文件名>       图片，多话共用的图片只保存一份
+//! ```
+
+use crate::cache::{self, ComicCache, EpisodeCache};
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::network::fix_episode_title;
+use crate::range::apply_range;
+use crate::{store, write_atomic};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+/// 当前的缓存包版本
+const VERSION: u32 = 1;
+
+/// 缓存包的说明，保存在 `bundle.toml` 中
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Bundle {
+    pub version: u32,
+    pub comic_id: u32,
+    pub title: String,
+    /// 创建时间 (unix 时间戳)
+    pub created: i64,
+    /// 包含的章节ID，按顺序排列
+    pub episodes: Vec<u32>,
+    /// 包含的图片数量
+    pub pages: usize,
+}
+
+/// 导入缓存包的结果
+#[derive(Clone, Debug)]
+pub struct BundleReport {
+    pub bundle: Bundle,
+    /// 写入的章节数量，本地已经下载完成的章节不会被覆盖
+    pub episodes: usize,
+    /// 导入的图片数量
+    pub imported: usize,
+    /// 本地已有而跳过的图片数量
+    pub existing: usize,
+    /// 文件名与 SHA-1 不符而跳过的图片
+    pub corrupted: Vec<String>,
+}
+
+/// 把缓存中的漫画写入缓存包，`range` 为空时包含所有章节
+pub(crate) fn export(config: &Config, id: u32, range: &str, path: &Path) -> Result<Bundle> {
+    let comic_root = Path::new(&config.cache_dir).join(id.to_string());
+    let comic = ComicCache::load(&comic_root).ok_or(Error::NotCached(id))?;
+    let mut episodes = comic.episodes.values().collect::<Vec<_>>();
+    episodes.sort_by(|a, b| a.ord.partial_cmp(&b.ord).unwrap());
+    let episodes = apply_range(episodes, range)?;
+    if episodes.is_empty() {
+        return Err(Error::NothingToExport);
+    }
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let bundle = Bundle {
+        version: VERSION,
+        comic_id: id,
+        title: comic.title.clone(),
+        created: chrono::Local::now().timestamp(),
+        episodes: episodes.iter().map(|episode| episode.id).collect(),
+        pages: 0,
+    };
+    // 先写入临时文件，失败时不会留下不完整的缓存包
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp = path.with_file_name(format!(".{}.tmp", file_name));
+    let result = write_zip(&tmp, &comic_root, &episodes, bundle).and_then(|bundle| {
+        std::fs::rename(&tmp, path)?;
+        Ok(bundle)
+    });
+    if result.is_err() {
+        std::fs::remove_file(&tmp).ok();
+    }
+    result
+}
+
+/// 写入缓存包的全部内容，`bundle` 中的图片数量在写入后更新
+fn write_zip(
+    path: &Path,
+    comic_root: &Path,
+    episodes: &[&EpisodeCache],
+    mut bundle: Bundle,
+) -> Result<Bundle> {
+    let mut zip = ZipWriter::new(BufWriter::new(File::create(path)?));
+    let text = FileOptions::default();
+    // 图片已经压缩过，直接存储
+    let stored = FileOptions::default().compression_method(CompressionMethod::Stored);
+    zip.start_file("meta.toml", text)?;
+    zip.write_all(&std::fs::read(comic_root.join("meta.toml"))?)?;
+    let cover = comic_root.join("cover.jpg");
+    if cover.is_file() {
+        zip.start_file("cover.jpg", stored)?;
+        zip.write_all(&std::fs::read(cover)?)?;
+    }
+    let mut written = HashSet::new();
+    for episode in episodes {
+        zip.start_file(format!("{}/meta.toml", episode.id), text)?;
+        zip.write_all(&std::fs::read(episode.root_dir.join("meta.toml"))?)?;
+        for name in episode.blob_names() {
+            let blob = store::blob_path(&episode.store, &name);
+            if written.contains(&name) || !blob.is_file() {
+                continue;
+            }
+            zip.start_file(format!("pages/{}", name), stored)?;
+            zip.write_all(&std::fs::read(blob)?)?;
+            written.insert(name);
+        }
+    }
+    bundle.pages = written.len();
+    zip.start_file("bundle.toml", text)?;
+    zip.write_all(toml::to_string(&bundle).unwrap().as_bytes())?;
+    zip.finish()?.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    Ok(bundle)
+}
+
+/// 把缓存包合并到缓存中
+///
+/// 本地没有或没有下载完成的章节使用缓存包中的信息，图片只导入本地缺少的部分
+pub(crate) fn import(config: &Config, path: &Path) -> Result<BundleReport> {
+    let mut zip = ZipArchive::new(File::open(path)?)?;
+    let bundle: Bundle = toml::from_str(&read_text(&mut zip, "bundle.toml")?)
+        .map_err(|e| Error::InvalidBundle(format!("bundle.toml：{}", e)))?;
+    if bundle.version > VERSION {
+        return Err(Error::InvalidBundle(format!(
+            "缓存包版本为 {}，当前只支持 {} 及以下的版本，请更新 bcdown",
+            bundle.version, VERSION
+        )));
+    }
+
+    let comic_root = Path::new(&config.cache_dir).join(bundle.comic_id.to_string());
+    let comic_meta = comic_root.join("meta.toml");
+    if !comic_meta.is_file() {
+        let text = read_text(&mut zip, "meta.toml")?;
+        if !cache::is_comic_meta(&text) {
+            return Err(Error::InvalidBundle("漫画信息无法解析".to_string()));
+        }
+        std::fs::create_dir_all(&comic_root)?;
+        write_atomic(comic_meta, text.as_bytes())?;
+    }
+    let cover = comic_root.join("cover.jpg");
+    if !cover.is_file() {
+        if let Ok(mut file) = zip.by_name("cover.jpg") {
+            let mut bytes = Vec::new();
+            file.read_to_end(&mut bytes)?;
+            write_atomic(cover, &bytes)?;
+        }
+    }
+
+    let mut episodes = 0;
+    for id in &bundle.episodes {
+        let ep_root = comic_root.join(id.to_string());
+        // 本地已经下载完成的章节可能使用了不同的尺寸，保留本地的版本
+        let complete = EpisodeCache::load(&ep_root)
+            .is_some_and(|episode| episode.not_downloaded_uncheck().is_empty());
+        if complete {
+            continue;
+        }
+        let text = read_text(&mut zip, &format!("{}/meta.toml", id))?;
+        if !cache::is_episode_meta(&text) {
+            return Err(Error::InvalidBundle(format!("第 {} 话的信息无法解析", id)));
+        }
+        std::fs::create_dir_all(&ep_root)?;
+        write_atomic(ep_root.join("meta.toml"), text.as_bytes())?;
+        episodes += 1;
+    }
+
+    // 只导入合并后的章节用到的图片
+    let comic = ComicCache::load(&comic_root).ok_or(Error::NotCached(bundle.comic_id))?;
+    let needed = bundle
+        .episodes
+        .iter()
+        .filter_map(|id| comic.get_episode(*id))
+        .flat_map(|episode| episode.blob_names())
+        .collect::<Vec<_>>();
+    let store_dir = Path::new(&config.cache_dir).join(store::STORE_DIR);
+    let mut report = BundleReport {
+        bundle,
+        episodes,
+        imported: 0,
+        existing: 0,
+        corrupted: Vec::new(),
+    };
+    for index in 0..zip.len() {
+        let mut file = zip.by_index(index)?;
+        let name = match file.name().strip_prefix("pages/") {
+            Some(name) if !name.is_empty() && !name.contains(['/', '\\']) && !name.starts_with('.') => {
+                name.to_string()
+            }
+            _ => continue,
+        };
+        if !needed.contains(&name) {
+            continue;
+        }
+        let blob = store::blob_path(&store_dir, &name);
+        if blob.is_file() {
+            report.existing += 1;
+            continue;
+        }
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+        if !verify(&bytes, &name) {
+            report.corrupted.push(name);
+            continue;
+        }
+        if let Some(parent) = blob.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        write_atomic(&blob, &bytes)?;
+        report.imported += 1;
+    }
+    Ok(report)
+}
+
+/// 默认的缓存包路径
+pub(crate) fn default_path(config: &Config, title: &str) -> PathBuf {
+    Path::new(&config.default_download_dir).join(format!("{}.bcdown", fix_episode_title(title)))
+}
+
+fn read_text(zip: &mut ZipArchive<File>, name: &str) -> Result<String> {
+    let mut file = zip
+        .by_name(name)
+        .map_err(|_| Error::InvalidBundle(format!("缺少 {}", name)))?;
+    let mut text = String::new();
+    file.read_to_string(&mut text)?;
+    Ok(text)
+}
+
+/// 原图和按顺序导入的图片的文件名就是它的 sha1，缩放或转换后的图片 (文件名中带有 `@`) 无法校验
+fn verify(bytes: &[u8], name: &str) -> bool {
+    if name.contains('@') {
+        return true;
+    }
+    let expected = name.split('.').next().unwrap_or_default();
+    let actual = format!("{:x}", Sha1::digest(bytes));
+    expected == actual
+}
@@ -1,10 +1,11 @@
-use super::config::Config;
-use std::collections::HashMap;
+use super::config::{Config, ImageFormat, Quality, Variant};
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
-use std::io::{Read, Write};
+use std::io::Read;
 use std::path::{Path, PathBuf};
 use sha1::Sha1;
-use crate::lib::create_hash;
+use crate::{create_hash, write_atomic};
+use crate::store;
 
 #[derive(serde::Serialize, serde::Deserialize)]
 struct EpisodeMeta {
@@ -15,6 +16,20 @@ struct EpisodeMeta {
     paths: Vec<String>,
     // 页码顺序
     host: String,
+    // 图片的尺寸和格式，旧版本的缓存都是原图
+    #[serde(default)]
+    quality: Quality,
+    #[serde(default)]
+    format: ImageFormat,
+    // 最近一次导出的时间 (unix 时间戳)，清理缓存时使用
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    exported: Option<i64>,
+    // 旧版本的 bcdown import 把按顺序导入的图片保存在原图的文件名下
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    unverified: bool,
+    // 按顺序导入的图片：原图的文件名 -> 以图片自身的 sha1 命名的文件名
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    imported: BTreeMap<String, String>,
 }
 
 #[derive(Debug, Clone)]
@@ -24,12 +39,22 @@ pub struct EpisodeCache {
     pub short_title: String,
     pub title: String,
     pub files: Vec<String>,
-    // 真实文件列表 xx.jpg, xx.jpg, xx.jpg, ...
+    // 存储中已有的文件列表 xx.jpg, xx.jpg, xx.jpg, ...
     pub paths: Vec<String>,
     // 文件顺序
     pub host: String,
     pub ord: f64,
     pub root_dir: PathBuf,
+    // 图片的尺寸和格式
+    pub variant: Variant,
+    // 最近一次导出的时间 (unix 时间戳)
+    pub exported: Option<i64>,
+    // 图片所在的存储目录，所有漫画共用
+    pub store: PathBuf,
+    // 旧版本按顺序导入的图片与原图不同，无法按 sha1 校验
+    pub unverified: bool,
+    // 按顺序导入、与原图不同的图片在存储中的文件名，键为原图的文件名
+    pub imported: BTreeMap<String, String>,
 }
 
 // impl AsRef<EpisodeInfo> for EpisodeCache {
@@ -43,10 +68,18 @@ pub struct EpisodeCache {
 //     }
 // }
 
-impl crate::lib::HasOrd for &'_ EpisodeCache {
+impl crate::range::Selectable for &'_ EpisodeCache {
     fn ord(&self) -> f64 {
         self.ord
     }
+
+    fn id(&self) -> u32 {
+        self.id
+    }
+
+    fn titles(&self) -> [&str; 2] {
+        [&self.short_title, &self.title]
+    }
 }
 
 impl EpisodeCache {
@@ -56,50 +89,112 @@ impl EpisodeCache {
         let mut buf = String::new();
         meta_file.read_to_string(&mut buf).ok()?;
         let meta: EpisodeMeta = toml::from_str(&buf).ok()?;
-        let files = std::fs::read_dir(path.as_ref())
-            .ok()?
-            .map(|entry| entry.unwrap().path())
-            .filter(|path| path.is_file())
-            .filter(|path| {
-                path.extension() == Some("jpg".as_ref()) || path.extension() == Some("png".as_ref())
-            })
-            .map(|path| path.file_name().unwrap().to_str().unwrap().to_string())
-            .collect::<Vec<_>>();
-        Some(EpisodeCache {
+        let store = store::dir_of(path.as_ref());
+        let mut episode = EpisodeCache {
             id: path.as_ref().file_name()?.to_str()?.parse::<u32>().ok()?,
             short_title: meta.short_title,
             title: meta.title,
-            files,
+            files: Vec::new(),
             paths: meta.paths,
             host: meta.host,
             ord: meta.ord,
             root_dir: path.as_ref().to_path_buf(),
-        })
+            variant: Variant {
+                quality: meta.quality,
+                format: meta.format,
+            },
+            exported: meta.exported,
+            store,
+            unverified: meta.unverified,
+            imported: meta.imported,
+        };
+        episode.files = episode
+            .blob_names()
+            .into_iter()
+            .filter(|name| store::blob_path(&episode.store, name).is_file())
+            .collect();
+        Some(episode)
     }
-    pub fn sync<T: AsRef<Path>>(&self, path: T) {
+    pub fn sync<T: AsRef<Path>>(&self, path: T) -> std::io::Result<()> {
         if (!path.as_ref().is_dir()) || (!path.as_ref().exists()) {
-            std::fs::create_dir_all(path.as_ref()).unwrap();
+            std::fs::create_dir_all(path.as_ref())?;
         }
         // 写入 meta.toml
         let meta_path = path.as_ref().join("meta.toml");
-        let mut meta_file = std::fs::File::create(&meta_path).unwrap();
         let meta = EpisodeMeta {
             short_title: self.short_title.clone(),
             title: self.title.clone(),
             ord: self.ord,
             paths: self.paths.clone(),
             host: self.host.clone(),
+            quality: self.variant.quality,
+            format: self.variant.format,
+            exported: self.exported,
+            unverified: self.unverified,
+            imported: self.imported.clone(),
         };
         let meta_str = toml::to_string(&meta).unwrap();
-        meta_file.write_all(meta_str.as_bytes()).unwrap();
+        write_atomic(meta_path, meta_str.as_bytes())
+    }
+
+    /// 记录这一话的导出时间
+    pub fn mark_exported(&self) -> std::io::Result<()> {
+        let mut episode = self.clone();
+        episode.exported = Some(chrono::Local::now().timestamp());
+        episode.sync(&self.root_dir)
+    }
+
+    /// 最近一次下载或导出的时间 (unix 时间戳)
+    pub fn last_used(&self) -> i64 {
+        let modified = fs::metadata(self.root_dir.join("meta.toml"))
+            .and_then(|meta| meta.modified())
+            .ok()
+            .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|time| time.as_secs() as i64)
+            .unwrap_or(0);
+        modified.max(self.exported.unwrap_or(0))
+    }
+
+    /// 图片在缓存中的文件名，不是原图时带有 `@1100w.webp` 这样的后缀，
+    /// 按顺序导入的图片使用它自身的 sha1
+    pub fn file_name(&self, path: &str) -> String {
+        let name = self.upstream_name(path);
+        self.imported.get(&name).cloned().unwrap_or(name)
+    }
+
+    /// 服务器上的图片的文件名，原图的文件名就是它的 sha1
+    pub fn upstream_name(&self, path: &str) -> String {
+        let url = self.variant.apply(path);
+        url.rsplit('/').next().unwrap().to_string()
+    }
+
+    /// 图片能否按文件名中的 sha1 校验，缩放或转换后的图片和旧版本按顺序导入的图片不能
+    pub fn verifiable(&self) -> bool {
+        self.variant.is_original() && !self.unverified
+    }
+
+    /// 这一话用到的图片在存储中的文件名，不含重复的图片
+    pub fn blob_names(&self) -> Vec<String> {
+        let mut names = Vec::new();
+        for path in &self.paths {
+            let name = self.file_name(path);
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+        names
+    }
+
+    /// 图片在存储中的路径
+    pub fn blob_path(&self, path: &str) -> PathBuf {
+        store::blob_path(&self.store, &self.file_name(path))
     }
 
     pub fn not_downloaded_uncheck(&self)  -> Vec<String> {
         // 返回未下载的文件名
         let mut not_downloaded = Vec::new();
         for path in &self.paths {
-            let end = path.split('/').last().unwrap();
-            if !self.files.contains(&end.to_string()) {
+            if !self.files.contains(&self.file_name(path)) {
                 not_downloaded.push(path.to_owned());
             }
         }
@@ -109,13 +204,19 @@ impl EpisodeCache {
     pub fn not_downloaded(&self) -> Vec<String> {
         // 返回未下载的文件名
         let mut not_downloaded = Vec::new();
+        let mut checked = Vec::new();
         for path in &self.paths {
-            let end = path.split('/').last().unwrap();
-            if !self.files.contains(&end.to_string()) {
+            let file_name = self.file_name(path);
+            // 同一张图片在一话中出现多次时只下载和校验一次
+            if checked.contains(&file_name) {
+                continue;
+            }
+            checked.push(file_name.clone());
+            if !self.files.contains(&file_name) {
                 not_downloaded.push(path.to_owned());
-            } else {
-                let file_name = path.split('/').last().unwrap();
-                let path_buf = self.root_dir.join(file_name);
+            } else if self.verifiable() {
+                // 原图的文件名就是它的 sha1，缩放或转换后的图片无法校验
+                let path_buf = self.blob_path(path);
                 let original_sha1_value = path_buf.file_stem().unwrap().to_str().unwrap().to_string();
                 let result = create_hash(&path_buf, Sha1::default());
                 let current_sha1_value = format!("{:x}", result.as_ref()).to_string();
@@ -132,14 +233,36 @@ impl EpisodeCache {
     pub fn get_paths(&self) -> Vec<PathBuf> {
         self.paths
             .iter()
-            .map(|link| {
-                let file_name = link.split('/').last().unwrap();
-                self.root_dir.join(file_name)
-            })
+            .map(|link| self.blob_path(link))
             .collect()
     }
 }
 
+/// 以 . 开头的文件夹保存任务记录等数据，不是漫画
+fn is_cache_dir(path: &Path) -> bool {
+    !path
+        .file_name()
+        .is_some_and(|name| name.to_string_lossy().starts_with('.'))
+}
+
+/// 缓存中的图片文件
+pub(crate) fn is_image(path: &Path) -> bool {
+    matches!(
+        path.extension().and_then(|extension| extension.to_str()),
+        Some("jpg") | Some("png") | Some("webp")
+    )
+}
+
+/// meta.toml 的内容是否为有效的章节信息，导入缓存包时使用
+pub(crate) fn is_episode_meta(text: &str) -> bool {
+    toml::from_str::<EpisodeMeta>(text).is_ok()
+}
+
+/// meta.toml 的内容是否为有效的漫画信息，导入缓存包时使用
+pub(crate) fn is_comic_meta(text: &str) -> bool {
+    toml::from_str::<ComicMeta>(text).is_ok()
+}
+
 #[derive(serde::Serialize, serde::Deserialize)]
 struct ComicMeta {
     title: String,
@@ -162,18 +285,14 @@ impl ComicCache {
         meta_file.read_to_string(&mut buf).ok()?;
         let meta: ComicMeta = toml::from_str(&buf).ok()?;
         let mut episodes = HashMap::new();
-        for entry in std::fs::read_dir(path.as_ref()).ok()? {
-            let entry = entry.ok()?;
+        // 加载时只读取，无效的文件夹可能是其他进程正在下载的章节，由 prune 清理
+        for entry in std::fs::read_dir(path.as_ref()).ok()?.flatten() {
             let entry_path = entry.path();
-            if entry_path.is_dir() {
-                let episode_id = entry_path.file_name()?.to_str()?.parse::<u32>().ok()?;
-                let episode_cache = EpisodeCache::load(&entry_path);
-                if let Some(episode_cache) = episode_cache {
-                    episodes.insert(episode_id, episode_cache);
-                } else {
-                    // 清理无效文件夹
-                    std::fs::remove_dir_all(entry_path).ok()?;
-                }
+            if !entry_path.is_dir() {
+                continue;
+            }
+            if let Some(episode_cache) = EpisodeCache::load(&entry_path) {
+                episodes.insert(episode_cache.id, episode_cache);
             }
         }
         Some(ComicCache {
@@ -187,24 +306,23 @@ impl ComicCache {
         self.episodes.get(&id)
     }
 
-    pub fn sync(&self, path: &Path) {
+    pub fn sync(&self, path: &Path) -> std::io::Result<()> {
         if (!path.is_dir()) || (!path.exists()) {
-            std::fs::create_dir_all(path).unwrap();
+            std::fs::create_dir_all(path)?;
         }
         // 写入 meta.toml
         let meta_path = path.join("meta.toml");
-
-        let mut meta_file = std::fs::File::create(&meta_path).unwrap();
         let meta = ComicMeta {
             title: self.title.clone(),
         };
         let meta_str = toml::to_string(&meta).unwrap();
-        meta_file.write_all(meta_str.as_bytes()).unwrap();
+        write_atomic(meta_path, meta_str.as_bytes())?;
         // 写入每个章节
         for (id, episode) in &self.episodes {
             let episode_path = path.join(id.to_string());
-            episode.sync(episode_path);
+            episode.sync(episode_path)?;
         }
+        Ok(())
     }
 }
 
@@ -216,27 +334,49 @@ pub struct Cache {
 impl Cache {
     pub fn load(config: &Config) -> Cache {
         let root_dir = Path::new(&config.cache_dir);
-        if (!root_dir.exists()) || !root_dir.is_dir() {
-            std::fs::create_dir_all(&root_dir).unwrap();
-            return Cache {
-                comics: HashMap::new(),
-            };
-        }
-        // 遍历文件夹
         let mut comics = HashMap::new();
-        for entry in root_dir.read_dir().expect("无法读取缓存文件夹").flatten() {
-            let entry_path = entry.path();
-            if entry_path.is_dir() {
+        let entries = match root_dir.read_dir() {
+            Ok(entries) => entries,
+            Err(_) => return Cache { comics },
+        };
+        // 遍历文件夹
+        for entry_path in entries.flatten().map(|entry| entry.path()) {
+            if entry_path.is_dir() && is_cache_dir(&entry_path) {
                 if let Some(comic_cache) = ComicCache::load(&entry_path) {
                     comics.insert(comic_cache.id, comic_cache);
-                } else {
-                    // 清理无效文件夹
-                    std::fs::remove_dir_all(entry_path).unwrap();
                 }
             }
         }
         Cache { comics }
     }
+
+    /// 没有有效 meta.toml 的漫画和章节文件夹，通常是下载中断留下的
+    ///
+    /// 正在下载的漫画也可能暂时没有 meta.toml，只能在持有整个缓存目录的独占锁时删除
+    pub(crate) fn invalid_dirs(config: &Config) -> Vec<PathBuf> {
+        let mut invalid = Vec::new();
+        let entries = match Path::new(&config.cache_dir).read_dir() {
+            Ok(entries) => entries,
+            Err(_) => return invalid,
+        };
+        for comic_dir in entries.flatten().map(|entry| entry.path()) {
+            if !comic_dir.is_dir() || !is_cache_dir(&comic_dir) {
+                continue;
+            }
+            if ComicCache::load(&comic_dir).is_none() {
+                invalid.push(comic_dir);
+                continue;
+            }
+            for entry in comic_dir.read_dir().into_iter().flatten().flatten() {
+                let episode_dir = entry.path();
+                if episode_dir.is_dir() && EpisodeCache::load(&episode_dir).is_none() {
+                    invalid.push(episode_dir);
+                }
+            }
+        }
+        invalid
+    }
+
     pub fn get_comic(&self, id: u32) -> Option<&ComicCache> {
         self.comics.get(&id)
     }
@@ -1,8 +1,15 @@
 #![allow(clippy::upper_case_acronyms)]
+use crate::error::{Error, Result};
+use crate::limit::{self, RateLimiter};
+use crate::proxy::NetworkConfig;
+use crate::write_atomic;
 use reqwest::header::HeaderMap;
-use std::io::{Read, Write};
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::io::Read;
 use std::path::{Path, PathBuf};
-use std::process::exit;
+use std::str::FromStr;
+use std::time::Duration;
 
 #[derive(serde::Deserialize, serde::Serialize, Clone)]
 pub struct Config {
@@ -10,68 +17,358 @@ pub struct Config {
     pub cache_dir: String,
     pub default_download_dir: String,
     pub dpi: Option<f64>,
+    /// 导出时使用的设备配置名称，为空则原样导出图片
+    pub profile: Option<String>,
+    /// 条漫模式：将每一话拼接后按该高宽比重新切分，为空则不处理
+    pub webtoon: Option<f64>,
+    /// 跨页图片的处理方式
+    #[serde(default)]
+    pub spread: SpreadMode,
+    /// 阅读方向，决定跨页拆分后的页面顺序
+    #[serde(default)]
+    pub direction: Direction,
+    /// 宽高比超过该值的图片视为跨页，默认为 1.2
+    pub spread_ratio: Option<f64>,
+    /// 下载限速 (每秒字节数)，如 "2M" "512K"，为空则不限速
+    pub rate_limit: Option<String>,
+    /// 每次获取图片链接的数量，默认为 10，链接在下载前才获取，避免过期
+    pub token_batch: Option<usize>,
+    /// 缓存正被其他 bcdown 进程使用时最多等待的秒数，默认为 30，0 为不等待
+    pub lock_timeout: Option<u64>,
+    /// 下载图片的尺寸，由 CDN 缩放
+    #[serde(default)]
+    pub quality: Quality,
+    /// 下载图片的格式，由 CDN 转换
+    #[serde(default)]
+    pub image_format: ImageFormat,
+    /// 关注的漫画ID，`cache prune --unwatched` 会删除其他漫画的缓存
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub watchlist: Vec<u32>,
+    /// 代理、证书和超时设置
+    #[serde(default)]
+    pub network: NetworkConfig,
+    /// 用户自定义的设备配置，会覆盖同名的内置配置
+    #[serde(default)]
+    pub profiles: HashMap<String, DeviceProfile>,
+    /// 按时间段设置的限速，在时间段内代替 `rate_limit`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rate_schedule: Vec<RateWindow>,
+    /// 所有下载共用的限速器
+    #[serde(skip)]
+    limiter: RateLimiter,
 }
 
-fn mkdir<T: AsRef<Path>>(path: T) {
-    if std::fs::create_dir_all(&path).is_err() {
-        let mut log = paris::Logger::new();
-        log.error(format!("无法创建目录：{}", path.as_ref().display()));
+/// 一个时间段内的限速
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub struct RateWindow {
+    /// 开始时间，如 "09:00"
+    pub from: String,
+    /// 结束时间，早于开始时间时表示到第二天
+    pub to: String,
+    /// 这段时间内的限速，为空则不限速
+    pub limit: Option<String>,
+}
+
+/// 跨页图片的处理方式
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SpreadMode {
+    /// 保持跨页，PDF中使用横向页面
+    #[default]
+    Keep,
+    /// 从中间拆分为两页
+    Split,
+    /// 旋转为纵向
+    Rotate,
+}
+
+impl FromStr for SpreadMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(SpreadMode::Keep),
+            "split" => Ok(SpreadMode::Split),
+            "rotate" => Ok(SpreadMode::Rotate),
+            _ => Err(()),
+        }
+    }
+}
+
+/// 阅读方向
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// 从左到右
+    #[default]
+    Ltr,
+    /// 从右到左，日漫常见
+    Rtl,
+}
+
+impl FromStr for Direction {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "ltr" => Ok(Direction::Ltr),
+            "rtl" => Ok(Direction::Rtl),
+            _ => Err(()),
+        }
+    }
+}
+
+/// 下载图片的尺寸
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Quality {
+    /// 原图
+    #[default]
+    Original,
+    /// 宽度不超过 1600 像素
+    High,
+    /// 宽度不超过 1100 像素
+    Medium,
+    /// 宽度不超过 660 像素
+    Low,
+}
+
+impl Quality {
+    /// CDN 缩放后的最大宽度，原图为空
+    pub fn width(&self) -> Option<u32> {
+        match self {
+            Quality::Original => None,
+            Quality::High => Some(1600),
+            Quality::Medium => Some(1100),
+            Quality::Low => Some(660),
+        }
+    }
+}
+
+impl FromStr for Quality {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "original" => Ok(Quality::Original),
+            "high" => Ok(Quality::High),
+            "medium" => Ok(Quality::Medium),
+            "low" => Ok(Quality::Low),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Display for Quality {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Quality::Original => "original",
+            Quality::High => "high",
+            Quality::Medium => "medium",
+            Quality::Low => "low",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// 下载图片的格式
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageFormat {
+    /// 与原图相同
+    #[default]
+    Original,
+    Jpg,
+    /// 体积较小，导出时会按需转换
+    Webp,
+}
+
+impl FromStr for ImageFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "original" => Ok(ImageFormat::Original),
+            "jpg" => Ok(ImageFormat::Jpg),
+            "webp" => Ok(ImageFormat::Webp),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Display for ImageFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            ImageFormat::Original => "original",
+            ImageFormat::Jpg => "jpg",
+            ImageFormat::Webp => "webp",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// 缓存中图片的尺寸和格式
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Variant {
+    #[serde(default)]
+    pub quality: Quality,
+    #[serde(default)]
+    pub format: ImageFormat,
+}
+
+impl Variant {
+    pub fn is_original(&self) -> bool {
+        self.quality == Quality::Original && self.format == ImageFormat::Original
+    }
+
+    /// 图片在 CDN 上的路径，不是原图时加上 `@1100w.webp` 这样的后缀
+    pub fn apply(&self, path: &str) -> String {
+        if self.is_original() {
+            return path.to_string();
+        }
+        let extension = match self.format {
+            ImageFormat::Original => path.rsplit('.').next().unwrap_or("jpg"),
+            ImageFormat::Jpg => "jpg",
+            ImageFormat::Webp => "webp",
+        };
+        match self.quality.width() {
+            Some(width) => format!("{}@{}w.{}", path, width, extension),
+            None => format!("{}@.{}", path, extension),
+        }
+    }
+}
+
+impl Display for Variant {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.is_original() {
+            write!(f, "原图")
+        } else {
+            write!(f, "{} {}", self.quality, self.format)
+        }
+    }
+}
+
+/// 导出图片时的处理参数
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, Default)]
+pub struct DeviceProfile {
+    /// 最大宽度 (像素)
+    pub max_width: Option<u32>,
+    /// 最大高度 (像素)
+    pub max_height: Option<u32>,
+    /// 转换为灰度图
+    #[serde(default)]
+    pub grayscale: bool,
+    /// 灰度图的伽马校正，大于1时画面变亮
+    pub gamma: Option<f32>,
+    /// JPEG 压缩质量 1-100
+    pub quality: Option<u8>,
+    /// 将 PNG 转换为 JPEG
+    #[serde(default)]
+    pub png_to_jpeg: bool,
+    /// 输出为 WebP (需要启用 webp 特性)
+    #[serde(default)]
+    pub webp: bool,
+}
+
+impl DeviceProfile {
+    /// 内置的设备配置
+    pub fn builtin(name: &str) -> Option<DeviceProfile> {
+        let (max_width, max_height) = match name {
+            "kindle" => (1072, 1448),
+            "kindle-paperwhite" => (1236, 1648),
+            "kindle-scribe" => (1860, 2480),
+            "kobo-clara" => (1072, 1448),
+            "kobo-libra" => (1264, 1680),
+            "remarkable" => (1404, 1872),
+            _ => return None,
+        };
+        Some(DeviceProfile {
+            max_width: Some(max_width),
+            max_height: Some(max_height),
+            grayscale: true,
+            gamma: Some(1.8),
+            quality: Some(85),
+            png_to_jpeg: true,
+            webp: false,
+        })
+    }
+
+    /// 页面的高宽比
+    pub fn ratio(&self) -> Option<f64> {
+        Some(self.max_height? as f64 / self.max_width? as f64)
+    }
+
+    /// 是否需要对图片做任何处理
+    pub fn is_passthrough(&self) -> bool {
+        self.max_width.is_none()
+            && self.max_height.is_none()
+            && !self.grayscale
+            && self.quality.is_none()
+            && !self.png_to_jpeg
+            && !self.webp
+    }
+}
+
+/// 配置文件的备份，与配置文件在同一目录
+fn backup_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".bak");
+    path.with_file_name(name)
+}
+
+/// 默认的配置文件路径、缓存目录和下载目录
+fn default_paths() -> (PathBuf, PathBuf, PathBuf) {
+    if let Some(user_dir) = directories::UserDirs::new() {
+        // 无法获取用户文档目录时使用用户根目录
+        let (root, hidden) = match user_dir.document_dir() {
+            Some(document_dir) => (document_dir.to_path_buf(), "bcdown"),
+            None => (user_dir.home_dir().to_path_buf(), ".bcdown"),
+        };
+        let config_path = root.join(hidden).join("config.toml");
+        let cache_dir = root.join(hidden).join("cache");
+        let tmp = root.join(hidden).join("download");
+        let default_download_dir = user_dir.download_dir().unwrap_or(tmp.as_path());
+        (config_path, cache_dir, default_download_dir.to_path_buf())
+    } else {
+        // 无法定位用户目录时使用当前工作目录作为工具根目录
+        let current_dir = std::env::current_dir().unwrap();
+        let config_path = current_dir.join("config.toml");
+        let cache_dir = current_dir.join("bcdown_cache");
+        let default_download_dir = current_dir.join("bcdown_download");
+        (config_path, cache_dir, default_download_dir)
     }
 }
 
 impl Default for Config {
     fn default() -> Self {
-        let mut log = paris::Logger::new();
-        log.info("初始化默认配置");
-
-        let (config_path, cache_dir, default_download_dir) =
-            if let Some(user_dir) = directories::UserDirs::new() {
-                if let Some(document_dir) = user_dir.document_dir() {
-                    let config_path = document_dir.join("bcdown/config.toml");
-                    let cache_dir = document_dir.join("bcdown/cache");
-                    let tmp = document_dir.join("bcdown/download");
-                    let default_download_dir = user_dir.download_dir().unwrap_or(tmp.as_path());
-                    (config_path, cache_dir, default_download_dir.to_path_buf())
-                } else {
-                    log.warn("无法获取用户文档目录，将使用用户根目录");
-                    let home_dir = user_dir.home_dir();
-                    let config_path = home_dir.join(".bcdown/config.toml");
-                    let cache_dir = home_dir.join(".bcdown/cache");
-                    let tmp = home_dir.join(".bcdown/download");
-                    let default_download_dir = user_dir.download_dir().unwrap_or(tmp.as_path());
-                    (config_path, cache_dir, default_download_dir.to_path_buf())
-                }
-            } else {
-                log.warn("无法定位用户目录！将使用当前工作目录作为工具根目录");
-                let current_dir = std::env::current_dir().unwrap();
-                let config_path = current_dir.join("config.toml");
-                let cache_dir = current_dir.join("bcdown_cache");
-                let default_download_dir = current_dir.join("bcdown_download");
-                (config_path, cache_dir, default_download_dir)
-            };
-
-        log.info(format!("配置文件路径：{}", config_path.display()));
-        log.info(format!("缓存目录：{}", cache_dir.display()));
-        log.info(format!("默认下载目录：{}", default_download_dir.display()));
-        mkdir(cache_dir.as_path());
-        mkdir(default_download_dir.as_path());
-
-        let config = Config {
+        let (_, cache_dir, default_download_dir) = default_paths();
+        Config {
             sessdata: "".to_string(),
             cache_dir: cache_dir.to_string_lossy().to_string(),
             default_download_dir: default_download_dir.to_string_lossy().to_string(),
             dpi: None,
-        };
-
-        let mut config_file = std::fs::File::create(config_path).unwrap();
-        let config_toml = toml::to_string(&config).unwrap();
-        config_file.write_all(config_toml.as_bytes()).unwrap();
-        config
+            profile: None,
+            webtoon: None,
+            spread: SpreadMode::default(),
+            direction: Direction::default(),
+            spread_ratio: None,
+            rate_limit: None,
+            token_batch: None,
+            lock_timeout: None,
+            quality: Quality::default(),
+            image_format: ImageFormat::default(),
+            watchlist: Vec::new(),
+            network: NetworkConfig::default(),
+            profiles: HashMap::new(),
+            rate_schedule: Vec::new(),
+            limiter: RateLimiter::default(),
+        }
     }
 }
 
 impl Config {
-    fn path() -> Option<PathBuf> {
+    /// 当前使用的配置文件路径，不存在时返回 `None`
+    pub fn path() -> Option<PathBuf> {
         // 按照顺序加载配置文件
         // 1. 工作目录下的 config.toml
         // 2. 用户目录 .bcdown/config.toml
@@ -82,14 +379,12 @@ impl Config {
             user_dir.home_dir().join(".bcdown/config.toml")
         } else {
             // 随便整一个不存在的路径
-            current_dir.join("/114514/1919810/")
+            PathBuf::from("/114514/1919810/")
         };
-        let document_config_path = if let Some(user_dir) = directories::UserDirs::new() {
-            user_dir.document_dir().unwrap().join("bcdown/config.toml")
-        } else {
+        let document_config_path = directories::UserDirs::new()
+            .and_then(|user_dir| user_dir.document_dir().map(|dir| dir.join("bcdown/config.toml")))
             // 随便整一个不存在的路径
-            current_dir.join("/114514/1919810/")
-        };
+            .unwrap_or_else(|| PathBuf::from("/114514/1919810/"));
         // 顺序检查文件是否存在
         if current_config_path.exists() {
             Some(current_config_path)
@@ -102,29 +397,134 @@ impl Config {
         }
     }
 
-    pub fn load() -> Self {
-        let mut log = paris::Logger::new();
-        if let Some(path) = Config::path() {
-            log.info(format!("加载配置文件：{}", path.display()));
-            if let Ok(mut file) = std::fs::File::open(path) {
-                let mut config_toml = String::new();
-                file.read_to_string(&mut config_toml).unwrap();
-                if let Ok(config) = toml::from_str(&config_toml) {
-                    config
-                } else {
-                    log.error("配置文件解析失败！");
-                    exit(1);
-                }
+    /// 从 [`Config::path`] 加载配置，未找到配置文件时创建默认配置
+    pub fn load() -> Result<Self> {
+        match Config::path() {
+            Some(path) => Config::load_from(path),
+            None => Config::init(),
+        }
+    }
+
+    /// 创建默认配置文件以及缓存、下载目录
+    fn init() -> Result<Self> {
+        let config = Config::default();
+        let (config_path, _, _) = default_paths();
+        std::fs::create_dir_all(&config.cache_dir)?;
+        std::fs::create_dir_all(&config.default_download_dir)?;
+        if let Some(parent) = config_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let config_toml = toml::to_string(&config).map_err(|e| Error::Config(e.to_string()))?;
+        write_atomic(config_path, config_toml.as_bytes())?;
+        Ok(config)
+    }
+
+    pub fn load_from<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut file = std::fs::File::open(path.as_ref())?;
+        let mut config_toml = String::new();
+        file.read_to_string(&mut config_toml)?;
+        let config: Config = toml::from_str(&config_toml).map_err(|e| {
+            let backup = backup_path(path.as_ref());
+            if backup.is_file() {
+                Error::Config(format!("{}，上一次保存前的配置备份在 {}", e, backup.display()))
             } else {
-                log.error("配置文件打开失败！");
-                exit(1);
+                Error::Config(e.to_string())
             }
-        } else {
-            log.warn("未找到配置文件，将使用默认配置");
-            Config::default()
+        })?;
+        config.check()?;
+        Ok(config)
+    }
+
+    /// 读取超时，考虑环境变量
+    pub(crate) fn read_timeout(&self) -> Duration {
+        self.network
+            .with_env()
+            .map(|network| network.read_timeout())
+            .unwrap_or_else(|_| self.network.read_timeout())
+    }
+
+    /// 检查限速和时间段的格式
+    fn check(&self) -> Result<()> {
+        if let Some(rate) = &self.rate_limit {
+            limit::parse_rate(rate)?;
+        }
+        for window in &self.rate_schedule {
+            limit::parse_time(&window.from)?;
+            limit::parse_time(&window.to)?;
+            if let Some(rate) = &window.limit {
+                limit::parse_rate(rate)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 使用固定的限速，忽略按时间段设置的限速，0 表示不限速
+    pub fn set_rate_limit(&mut self, rate: &str) -> Result<()> {
+        limit::parse_rate(rate)?;
+        self.rate_limit = Some(rate.to_string());
+        self.rate_schedule.clear();
+        Ok(())
+    }
+
+    /// 当前时间的限速 (每秒字节数)，不限速时返回 `None`
+    pub fn current_rate_limit(&self) -> Option<u64> {
+        let now = chrono::Local::now().time();
+        let window = self.rate_schedule.iter().find(|window| {
+            match (limit::parse_time(&window.from), limit::parse_time(&window.to)) {
+                (Ok(from), Ok(to)) => limit::contains(from, to, now),
+                _ => false,
+            }
+        });
+        let rate = match window {
+            Some(window) => window.limit.as_ref(),
+            None => self.rate_limit.as_ref(),
+        };
+        rate.and_then(|rate| limit::parse_rate(rate).ok())
+            .filter(|rate| *rate > 0)
+    }
+
+    /// 下载了 `bytes` 字节后调用，按当前的限速等待
+    pub(crate) async fn throttle(&self, bytes: usize) {
+        self.limiter.acquire(self.current_rate_limit(), bytes).await;
+    }
+
+    /// 下载图片使用的尺寸和格式
+    pub fn variant(&self) -> Variant {
+        Variant {
+            quality: self.quality,
+            format: self.image_format,
+        }
+    }
+
+    /// 每次获取图片链接的数量
+    pub fn token_batch(&self) -> usize {
+        self.token_batch.unwrap_or(10).max(1)
+    }
+
+    /// 等待其他进程释放缓存锁的时间
+    pub fn lock_timeout(&self) -> Duration {
+        Duration::from_secs(self.lock_timeout.unwrap_or(30))
+    }
+
+    /// 当前选择的设备配置，用户配置优先于内置配置
+    pub fn get_profile(&self) -> Result<Option<DeviceProfile>> {
+        let name = match &self.profile {
+            Some(name) => name,
+            None => return Ok(None),
+        };
+        if let Some(profile) = self.profiles.get(name) {
+            return Ok(Some(profile.clone()));
+        }
+        match DeviceProfile::builtin(name) {
+            Some(profile) => Ok(Some(profile)),
+            None => Err(Error::UnknownProfile(name.to_string())),
         }
     }
-    pub fn get_client(&self) -> reqwest::Client {
+
+    /// 创建网络客户端，代理和证书设置有误时返回错误
+    ///
+    /// 客户端自带连接池，应尽量复用，见 [`crate::network::Session`]
+    pub fn get_client(&self) -> Result<reqwest::Client> {
         let mut headers = HeaderMap::new();
         headers.insert(
             "User-Agent",
@@ -150,27 +550,30 @@ impl Config {
                 format!("SESSDATA={}", self.sessdata).parse().unwrap()
             },
         );
-        let client = reqwest::ClientBuilder::new().default_headers(headers);
-        client.build().unwrap()
+        // 图片较大时自动调整 HTTP/2 的窗口，空闲连接保持一段时间供后续请求复用
+        let client = reqwest::ClientBuilder::new()
+            .default_headers(headers)
+            .http2_adaptive_window(true)
+            .pool_idle_timeout(Duration::from_secs(90))
+            .tcp_keepalive(Duration::from_secs(60));
+        let client = self.network.with_env()?.apply(client)?;
+        client.build().map_err(|e| Error::Client(e.to_string()))
     }
 
-    pub fn save(&self) {
+    /// 保存配置，原来的配置文件备份为 `config.toml.bak`
+    pub fn save(&self) -> Result<()> {
         let path = if let Some(path) = Self::path() {
-            // 删除原来的配置文件
-            std::fs::remove_file(&path).unwrap();
+            std::fs::copy(&path, backup_path(&path))?;
             path
         } else {
             // 找一个最合适的路径
-            if let Some(user_dir) = directories::UserDirs::new() {
-                user_dir.document_dir().unwrap().join("bcdown/config.toml")
-            } else if let Some(user_dir) = directories::UserDirs::new() {
-                user_dir.home_dir().join(".bcdown/config.toml")
-            } else {
-                std::env::current_dir().unwrap().join("config.toml")
-            }
+            default_paths().0
         };
-        let mut file = std::fs::File::create(path).unwrap();
-        let config_toml = toml::to_string(&self).unwrap();
-        file.write_all(config_toml.as_bytes()).unwrap();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let config_toml = toml::to_string(&self).map_err(|e| Error::Config(e.to_string()))?;
+        write_atomic(path, config_toml.as_bytes())?;
+        Ok(())
     }
 }
@@ -0,0 +1,145 @@
+use std::fmt::{Display, Formatter};
+
+/// 库中所有操作的错误类型
+#[derive(Debug)]
+pub enum Error {
+    /// 无法解析的漫画ID或链接
+    InvalidId(String),
+    /// 网络请求失败
+    Network(reqwest::Error),
+    /// 服务器返回了错误或无法解析的数据
+    Api(String),
+    Io(std::io::Error),
+    /// 配置文件无法解析
+    Config(String),
+    /// 找不到设备配置
+    UnknownProfile(String),
+    /// 不支持的导出格式
+    UnknownFormat(String),
+    /// 当前版本未启用所需的编译特性
+    MissingFeature(&'static str),
+    /// 本地缓存中没有该漫画
+    NotCached(u32),
+    /// 范围内没有可以导出的章节
+    NothingToExport,
+    /// 本地服务器启动失败
+    Server(hyper::Error),
+    /// 任务被取消，参数为任务记录的ID
+    Cancelled(u64),
+    /// 找不到任务记录
+    UnknownJob(u64),
+    /// 无法解析的限速
+    InvalidRate(String),
+    /// 无法解析的大小
+    InvalidSize(String),
+    /// 按关注列表清理缓存时关注列表为空
+    EmptyWatchlist,
+    /// 代理、证书或超时设置有误，无法创建网络客户端
+    Client(String),
+    /// 缓存包无法读取或内容不完整
+    InvalidBundle(String),
+    /// 要导入的文件夹或压缩包中没有图片
+    NothingToImport(String),
+    /// 等待超时，缓存仍被其他进程使用，参数为被占用的内容
+    Locked(String),
+    /// 离线或网络请求失败，并且没有保存这部漫画的信息
+    NoRemoteInfo(u32),
+    /// 无法解析的章节范围
+    InvalidRange(String),
+    /// 生成 PDF、EPUB 等文件失败，或图片无法解码
+    Export(String),
+    /// 服务器收到的输出目录不是下载目录下的相对路径
+    InvalidOutput(String),
+    /// 下载重试次数用完，参数为任务记录的ID和最后一次失败的原因
+    FetchFailed(u64, String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::InvalidId(id) => write!(f, "指定的id或链接无效：{}", id),
+            Error::Network(e) => write!(f, "网络请求失败，请检查网络：{}", e),
+            Error::Api(message) => write!(f, "{}", message),
+            Error::Io(e) => write!(f, "文件读写失败：{}", e),
+            Error::Config(message) => write!(f, "配置文件解析失败：{}", message),
+            Error::UnknownProfile(name) => write!(f, "找不到设备配置：{}", name),
+            Error::UnknownFormat(format) => {
+                write!(f, "目前只支持导出 epub | pdf | zip | vol.zip | html 格式，而不是 {}", format)
+            }
+            Error::MissingFeature(feature) => {
+                write!(f, "当前版本未启用 {} 特性，请使用 --features {} 重新编译", feature, feature)
+            }
+            Error::NotCached(id) => write!(f, "在本地缓存中找不到漫画：{}", id),
+            Error::NothingToExport => write!(f, "没有可以导出的章节"),
+            Error::Server(e) => write!(f, "服务器错误：{}", e),
+            Error::Cancelled(id) => write!(f, "任务 {} 已取消", id),
+            Error::UnknownJob(id) => write!(f, "找不到未完成的任务：{}", id),
+            Error::InvalidRate(rate) => write!(f, "无效的限速：{}，应为 2M 512K 这样的格式", rate),
+            Error::InvalidSize(size) => write!(f, "无效的大小：{}，应为 10G 500M 这样的格式", size),
+            Error::EmptyWatchlist => write!(f, "关注列表为空，请先在配置文件中设置 watchlist"),
+            Error::Client(message) => write!(f, "无法创建网络客户端：{}", message),
+            Error::InvalidBundle(message) => write!(f, "无效的缓存包：{}", message),
+            Error::NothingToImport(path) => write!(f, "在 {} 中没有找到可以导入的图片", path),
+            Error::InvalidRange(message) => write!(f, "无效的章节范围 {}", message),
+            Error::Export(message) => write!(f, "导出失败：{}", message),
+            Error::InvalidOutput(output) => {
+                write!(f, "输出目录只能是下载目录下的相对路径，不能包含 ..：{}", output)
+            }
+            Error::FetchFailed(id, reason) => write!(
+                f,
+                "下载失败：{}，可以通过 bcdown resume {} 继续",
+                reason, id
+            ),
+            Error::NoRemoteInfo(id) => write!(
+                f,
+                "没有保存漫画 {} 的信息，请先在联网时运行一次 search 或 fetch",
+                id
+            ),
+            Error::Locked(what) => write!(
+                f,
+                "{}正在被其他 bcdown 进程使用 (如下载、导出或清理)，请等待完成后重试，或在配置文件中增大 lock_timeout",
+                what
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Network(e) => Some(e),
+            Error::Io(e) => Some(e),
+            Error::Server(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Error::Network(e)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<hyper::Error> for Error {
+    fn from(e: hyper::Error) -> Self {
+        Error::Server(e)
+    }
+}
+
+impl From<zip::result::ZipError> for Error {
+    fn from(e: zip::result::ZipError) -> Self {
+        match e {
+            zip::result::ZipError::Io(e) => Error::Io(e),
+            e => Error::InvalidBundle(e.to_string()),
+        }
+    }
+}
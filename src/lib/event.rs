@@ -0,0 +1,107 @@
+use crate::network::EpisodeInfo;
+use serde::{Serialize, Serializer};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+
+/// 通道中最多缓存的事件数量，订阅者落后太多时会丢失较早的事件
+const CAPACITY: usize = 1024;
+
+/// 下载和导出过程中的进度事件
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// 即将下载的章节，`locked` 为范围内因未解锁而跳过的章节
+    FetchPlanned {
+        episodes: Vec<EpisodeInfo>,
+        locked: Vec<EpisodeInfo>,
+    },
+    /// 开始下载一话，`pages` 为这一话还需要下载的图片数量
+    EpisodeStarted { episode: u32, pages: usize },
+    /// 下载完成一张图片
+    PageDownloaded { episode: u32, bytes: usize },
+    /// 一话下载完成
+    EpisodeFinished { episode: u32 },
+    /// 一话下载失败，稍后会重试
+    EpisodeFailed { episode: u32, reason: String },
+    /// 即将导出的文件和章节数量
+    ExportPlanned { files: usize, episodes: usize },
+    /// 开始导出一个文件
+    ExportStarted { file: String },
+    /// 一话已经写入正在导出的文件
+    EpisodeExported { episode: u32 },
+    /// 一个文件导出完成
+    ExportFinished { file: String },
+    /// 整体进度，下载以图片为单位，导出以话为单位
+    Progress {
+        done: usize,
+        total: usize,
+        /// 按已用时间估算的剩余时间
+        #[serde(serialize_with = "as_secs")]
+        eta: Duration,
+    },
+}
+
+fn as_secs<S: Serializer>(eta: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    eta.as_secs_f64().serialize(serializer)
+}
+
+/// 进度事件的广播通道，终端、JSON 输出或网页等前端都可以订阅
+#[derive(Clone)]
+pub struct Events {
+    sender: broadcast::Sender<Event>,
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Events::new()
+    }
+}
+
+impl Events {
+    pub fn new() -> Events {
+        let (sender, _) = broadcast::channel(CAPACITY);
+        Events { sender }
+    }
+
+    /// 订阅之后发出的事件
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+
+    /// 发送事件，没有订阅者时直接丢弃
+    pub(crate) fn send(&self, event: Event) {
+        self.sender.send(event).ok();
+    }
+}
+
+/// 统计整体进度并估算剩余时间
+pub(crate) struct Tracker {
+    done: AtomicUsize,
+    total: AtomicUsize,
+    started: Instant,
+}
+
+impl Tracker {
+    pub fn new(total: usize) -> Tracker {
+        Tracker {
+            done: AtomicUsize::new(0),
+            total: AtomicUsize::new(total),
+            started: Instant::now(),
+        }
+    }
+
+    /// 增加总量，用于下载时逐话得知图片数量
+    pub fn add(&self, count: usize) {
+        self.total.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// 完成一个单位，返回新的进度事件
+    pub fn advance(&self) -> Event {
+        let done = self.done.fetch_add(1, Ordering::Relaxed) + 1;
+        let total = self.total.load(Ordering::Relaxed).max(done);
+        let elapsed = self.started.elapsed();
+        let eta = elapsed.mul_f64((total - done) as f64 / done as f64);
+        Event::Progress { done, total, eta }
+    }
+}
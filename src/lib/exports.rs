@@ -1,11 +1,14 @@
 #![allow(clippy::upper_case_acronyms)]
+use super::pages::{is_passthrough, load_pages};
 use super::pdf;
-use crate::lib::cache::EpisodeCache;
-use crate::lib::config::Config;
+use crate::cache::EpisodeCache;
+use crate::config::Config;
 use epub_builder::{EpubBuilder, EpubContent, ZipLibrary};
-use indicatif::{MultiProgress, ProgressBar};
+use crate::error::{Error, Result};
+use crate::event::{Event, Events, Tracker};
+use crate::job::{CancelToken, JobKind, Journal};
 use std::fs::File;
-use std::io::{BufReader, BufWriter, Read, Write};
+use std::io::{BufWriter, Write};
 use std::path::Path;
 use zip::write::FileOptions;
 use zip::{CompressionMethod, ZipWriter};
@@ -14,16 +17,25 @@ use zip::{CompressionMethod, ZipWriter};
 pub trait ExportFormat {
     fn get_extension(&self) -> &'static str;
     /// 导出单话
-    fn export_single<P: AsRef<Path>>(&self, episode: &EpisodeCache, path: P, config: &Config);
-    /// 导出多话且合并
+    fn export_single<P: AsRef<Path>>(&self, episode: &EpisodeCache, path: P, config: &Config) -> Result<()>;
+    /// 导出多话且合并，每写入一话调用一次 `exported`
     fn export_multiple<P: AsRef<Path>>(
         &self,
         episodes: Vec<&EpisodeCache>,
         title: &str,
         path: P,
         config: &Config,
-        bar: &ProgressBar,
-    );
+        exported: &dyn Fn(&EpisodeCache) -> Result<()>,
+    ) -> Result<()>;
+    /// 全部导出完成后调用，`files` 为本次导出的文件名
+    fn finish<P: AsRef<Path>>(&self, _title: &str, _out_dir: P, _files: &[String], _config: &Config) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// PDF、EPUB 库返回的错误
+fn export_error(e: impl std::fmt::Display) -> Error {
+    Error::Export(e.to_string())
 }
 
 #[enum_dispatch::enum_dispatch]
@@ -31,7 +43,8 @@ pub enum ExportFormatEnum {
     Epub,
     PDF,
     Zip,
-    Vol
+    Vol,
+    Html,
 }
 
 pub struct PDF;
@@ -41,12 +54,12 @@ impl ExportFormat for PDF {
         "pdf"
     }
 
-    fn export_single<P: AsRef<Path>>(&self, episode: &EpisodeCache, path: P, config: &Config) {
-        let paths = episode.get_paths();
-        let doc = pdf::from_images(paths, &episode.title, &episode.title, config.dpi);
-        let file = File::create(path.as_ref()).unwrap();
+    fn export_single<P: AsRef<Path>>(&self, episode: &EpisodeCache, path: P, config: &Config) -> Result<()> {
+        let pages = load_pages(episode, config)?;
+        let doc = pdf::from_images(pages, &episode.title, &episode.title, config.dpi)?;
+        let file = File::create(path.as_ref())?;
         let mut buf = BufWriter::new(file);
-        doc.save(&mut buf).unwrap();
+        doc.save(&mut buf).map_err(export_error)
     }
 
     fn export_multiple<P: AsRef<Path>>(
@@ -55,21 +68,21 @@ impl ExportFormat for PDF {
         title: &str,
         path: P,
         config: &Config,
-        bar: &ProgressBar,
-    ) {
+        exported: &dyn Fn(&EpisodeCache) -> Result<()>,
+    ) -> Result<()> {
         let mut pdf = None;
-        for (i, episode) in episodes.iter().enumerate() {
-            let paths = episode.get_paths();
-            if i == 0 {
-                pdf = Some(pdf::from_images(paths, title, &episode.title, config.dpi));
-            } else {
-                pdf = Some(pdf::append(pdf.unwrap(), paths, &episode.title, config.dpi));
-            }
-            bar.inc(1);
+        for episode in episodes {
+            let pages = load_pages(episode, config)?;
+            pdf = Some(match pdf {
+                None => pdf::from_images(pages, title, &episode.title, config.dpi)?,
+                Some(doc) => pdf::append(doc, pages, &episode.title, config.dpi)?,
+            });
+            exported(episode)?;
         }
-        let file = File::create(path.as_ref()).unwrap();
+        let doc = pdf.ok_or(Error::NothingToExport)?;
+        let file = File::create(path.as_ref())?;
         let mut buf = BufWriter::new(file);
-        pdf.unwrap().save(&mut buf).unwrap();
+        doc.save(&mut buf).map_err(export_error)
     }
 }
 
@@ -155,17 +168,23 @@ const CONTENT_TEMPLATE: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
 const STYLE: &str = "body { margin: 0; padding: 0; } img { width: 100%; height: auto; }";
 
 impl Epub {
-    fn make_builder(&self, title: &str) -> EpubBuilder<ZipLibrary> {
-        let zip = ZipLibrary::new().unwrap();
-        let mut builder = EpubBuilder::new(zip).unwrap();
+    fn make_builder(&self, title: &str) -> Result<EpubBuilder<ZipLibrary>> {
+        let zip = ZipLibrary::new().map_err(export_error)?;
+        let mut builder = EpubBuilder::new(zip).map_err(export_error)?;
         if let Some(cover) = self.cover.as_ref() {
             builder
                 .add_cover_image("images/cover.jpg", cover.as_slice(), "image/jpeg")
-                .unwrap();
+                .map_err(export_error)?;
         }
-        builder.metadata("title", title).unwrap();
-        builder.stylesheet(STYLE.as_bytes()).unwrap();
-        builder
+        builder.metadata("title", title).map_err(export_error)?;
+        builder.stylesheet(STYLE.as_bytes()).map_err(export_error)?;
+        Ok(builder)
+    }
+
+    fn generate<P: AsRef<Path>>(builder: &mut EpubBuilder<ZipLibrary>, path: P) -> Result<()> {
+        let file = File::create(path)?;
+        let mut buf_writer = BufWriter::new(file);
+        builder.generate(&mut buf_writer).map_err(export_error)
     }
 
     fn guess_mime(path: &str) -> &str {
@@ -173,6 +192,8 @@ impl Epub {
             "image/jpeg"
         } else if path.ends_with(".png") {
             "image/png"
+        } else if path.ends_with(".webp") {
+            "image/webp"
         } else if path.ends_with(".gif") {
             "image/gif"
         } else if path.ends_with(".svg") {
@@ -188,22 +209,18 @@ impl ExportFormat for Epub {
         "epub"
     }
 
-    fn export_single<P: AsRef<Path>>(&self, episode: &EpisodeCache, path: P, _config: &Config) {
-        let mut builder = self.make_builder(&episode.title);
-        for (i, path) in episode.get_paths().iter().enumerate() {
-            let file = File::open(path).unwrap();
-            let mut buf_reader = BufReader::new(file);
-            let mut buf = Vec::new();
-            buf_reader.read_to_end(&mut buf).unwrap();
-            let file_name = path.file_name().unwrap().to_str().unwrap();
+    fn export_single<P: AsRef<Path>>(&self, episode: &EpisodeCache, path: P, config: &Config) -> Result<()> {
+        let mut builder = self.make_builder(&episode.title)?;
+        for (i, page) in load_pages(episode, config)?.iter().enumerate() {
+            let file_name = page.name.as_str();
             let mime = Self::guess_mime(file_name);
             builder
                 .add_resource(
                     format!("images/{}/{}", episode.id, file_name),
-                    buf.as_slice(),
+                    page.data.as_slice(),
                     mime,
                 )
-                .unwrap();
+                .map_err(export_error)?;
 
             builder
                 .add_content(EpubContent::new(
@@ -213,11 +230,9 @@ impl ExportFormat for Epub {
                         .replace("{alt}", file_name)
                         .as_bytes(),
                 ))
-                .unwrap();
+                .map_err(export_error)?;
         }
-        let file = File::create(path).unwrap();
-        let mut buf_writer = BufWriter::new(file);
-        builder.generate(&mut buf_writer).unwrap();
+        Self::generate(&mut builder, path)
     }
 
     fn export_multiple<P: AsRef<Path>>(
@@ -225,25 +240,21 @@ impl ExportFormat for Epub {
         episodes: Vec<&EpisodeCache>,
         title: &str,
         path: P,
-        _config: &Config,
-        bar: &ProgressBar,
-    ) {
-        let mut builder = self.make_builder(title);
+        config: &Config,
+        exported: &dyn Fn(&EpisodeCache) -> Result<()>,
+    ) -> Result<()> {
+        let mut builder = self.make_builder(title)?;
         for ep in episodes {
-            for (i, path) in ep.get_paths().iter().enumerate() {
-                let file = File::open(path).unwrap();
-                let mut buf_reader = BufReader::new(file);
-                let mut buf = Vec::new();
-                buf_reader.read_to_end(&mut buf).unwrap();
-                let file_name = path.file_name().unwrap().to_str().unwrap();
+            for (i, page) in load_pages(ep, config)?.iter().enumerate() {
+                let file_name = page.name.as_str();
                 let mime = Self::guess_mime(file_name);
                 builder
                     .add_resource(
                         format!("images/{}/{}", ep.id, file_name),
-                        buf.as_slice(),
+                        page.data.as_slice(),
                         mime,
                     )
-                    .unwrap();
+                    .map_err(export_error)?;
                 if i == 0 {
                     builder
                         .add_content(
@@ -256,7 +267,7 @@ impl ExportFormat for Epub {
                             )
                             .title(&ep.title),
                         )
-                        .unwrap();
+                        .map_err(export_error)?;
                 } else {
                     builder
                         .add_content(
@@ -269,14 +280,12 @@ impl ExportFormat for Epub {
                             )
                             .level(2),
                         )
-                        .unwrap();
+                        .map_err(export_error)?;
                 }
             }
-            bar.inc(1);
+            exported(ep)?;
         }
-        let file = File::create(path).unwrap();
-        let mut buf_writer = BufWriter::new(file);
-        builder.generate(&mut buf_writer).unwrap();
+        Self::generate(&mut builder, path)
     }
 }
 //
@@ -402,19 +411,20 @@ impl Zip {
         FileOptions::default().compression_method(CompressionMethod::Stored)
     }
 
-    fn write_single_episode(&self, episode: &EpisodeCache, zip: &mut ZipWriter<BufWriter<File>>) {
-        for (i, path) in episode.get_paths().iter().enumerate() {
-            let file_ext = path.extension().unwrap().to_str().unwrap();
-            let mut file = File::open(path).unwrap();
-            let mut buf = Vec::new();
-            file.read_to_end(&mut buf).unwrap();
+    fn write_single_episode(
+        &self,
+        episode: &EpisodeCache,
+        zip: &mut ZipWriter<BufWriter<File>>,
+        config: &Config,
+    ) -> Result<()> {
+        for (i, page) in load_pages(episode, config)?.iter().enumerate() {
             zip.start_file(
-                format!("{:04} {} {}/{}.{}", episode.ord, episode.short_title, episode.title, i, file_ext),
+                format!("{:04} {} {}/{}.{}", episode.ord, episode.short_title, episode.title, i, page.extension()),
                 Zip::make_options(),
-            )
-            .unwrap();
-            zip.write_all(&buf).unwrap();
+            )?;
+            zip.write_all(&page.data)?;
         }
+        Ok(())
     }
 }
 
@@ -423,12 +433,13 @@ impl ExportFormat for Zip {
         "zip"
     }
 
-    fn export_single<P: AsRef<Path>>(&self, episode: &EpisodeCache, path: P, _config: &Config) {
-        let file = File::create(path).unwrap();
+    fn export_single<P: AsRef<Path>>(&self, episode: &EpisodeCache, path: P, config: &Config) -> Result<()> {
+        let file = File::create(path)?;
         let writer = BufWriter::new(file);
         let mut zip = ZipWriter::new(writer);
-        self.write_single_episode(episode, &mut zip);
-        zip.finish().unwrap();
+        self.write_single_episode(episode, &mut zip, config)?;
+        zip.finish()?;
+        Ok(())
     }
 
     fn export_multiple<P: AsRef<Path>>(
@@ -436,17 +447,18 @@ impl ExportFormat for Zip {
         episodes: Vec<&EpisodeCache>,
         _title: &str,
         path: P,
-        _config: &Config,
-        bar: &ProgressBar,
-    ) {
-        let file = File::create(path).unwrap();
+        config: &Config,
+        exported: &dyn Fn(&EpisodeCache) -> Result<()>,
+    ) -> Result<()> {
+        let file = File::create(path)?;
         let writer = BufWriter::new(file);
         let mut zip = ZipWriter::new(writer);
         for episode in episodes {
-            self.write_single_episode(episode, &mut zip);
-            bar.inc(1);
+            self.write_single_episode(episode, &mut zip, config)?;
+            exported(episode)?;
         }
-        zip.finish().unwrap();
+        zip.finish()?;
+        Ok(())
     }
 }
 
@@ -454,38 +466,42 @@ pub struct Vol;
 
 impl Vol {
 
-    fn write_single_vol(&self, episode: &EpisodeCache, zip: &mut ZipWriter<BufWriter<File>>) {
-        for (i, path) in episode.get_paths().iter().enumerate() {
-            let file_ext = path.extension().unwrap().to_str().unwrap();
-            let mut file = File::open(path).unwrap();
-            let mut buf = Vec::new();
-            file.read_to_end(&mut buf).unwrap();
+    fn write_single_vol(
+        &self,
+        episode: &EpisodeCache,
+        zip: &mut ZipWriter<BufWriter<File>>,
+        config: &Config,
+    ) -> Result<()> {
+        for (i, page) in load_pages(episode, config)?.iter().enumerate() {
             zip.start_file(
-                format!("pic_{}.{}", i, file_ext),
+                format!("pic_{}.{}", i, page.extension()),
                 Zip::make_options(),
-            )
-                .unwrap();
-            zip.write_all(&buf).unwrap();
+            )?;
+            zip.write_all(&page.data)?;
         }
+        Ok(())
     }
 
-    fn write_multiple_vol(&self, episodes: Vec<&EpisodeCache>, zip: &mut ZipWriter<BufWriter<File>>, bar: &ProgressBar) {
+    fn write_multiple_vol(
+        &self,
+        episodes: Vec<&EpisodeCache>,
+        zip: &mut ZipWriter<BufWriter<File>>,
+        config: &Config,
+        exported: &dyn Fn(&EpisodeCache) -> Result<()>,
+    ) -> Result<()> {
         let mut index = 1;
         for episode in episodes {
-            for path in episode.get_paths().iter() {
-                let file_ext = path.extension().unwrap().to_str().unwrap();
-                let mut file = File::open(path).unwrap();
-                let mut buf = Vec::new();
-                file.read_to_end(&mut buf).unwrap();
+            for page in load_pages(episode, config)?.iter() {
                 zip.start_file(
-                    format!("pic_{}.{}", index, file_ext),
+                    format!("pic_{}.{}", index, page.extension()),
                     Zip::make_options(),
-                ).unwrap();
-                zip.write_all(&buf).unwrap();
-                index = index + 1;
+                )?;
+                zip.write_all(&page.data)?;
+                index += 1;
             }
-            bar.inc(1);
+            exported(episode)?;
         }
+        Ok(())
     }
 }
 
@@ -494,12 +510,13 @@ impl ExportFormat for Vol {
         "zip"
     }
 
-    fn export_single<P: AsRef<Path>>(&self, episode: &EpisodeCache, path: P, _config: &Config) {
-        let file = File::create(path).unwrap();
+    fn export_single<P: AsRef<Path>>(&self, episode: &EpisodeCache, path: P, config: &Config) -> Result<()> {
+        let file = File::create(path)?;
         let writer = BufWriter::new(file);
         let mut zip = ZipWriter::new(writer);
-        self.write_single_vol(episode, &mut zip);
-        zip.finish().unwrap();
+        self.write_single_vol(episode, &mut zip, config)?;
+        zip.finish()?;
+        Ok(())
     }
 
     fn export_multiple<P: AsRef<Path>>(
@@ -507,14 +524,15 @@ impl ExportFormat for Vol {
         episodes: Vec<&EpisodeCache>,
         _title: &str,
         path: P,
-        _config: &Config,
-        bar: &ProgressBar,
-    ) {
-        let file = File::create(path).unwrap();
+        config: &Config,
+        exported: &dyn Fn(&EpisodeCache) -> Result<()>,
+    ) -> Result<()> {
+        let file = File::create(path)?;
         let writer = BufWriter::new(file);
         let mut zip = ZipWriter::new(writer);
-        self.write_multiple_vol(episodes, &mut zip, bar);
-        zip.finish().unwrap();
+        self.write_multiple_vol(episodes, &mut zip, config, exported)?;
+        zip.finish()?;
+        Ok(())
     }
 }
 
@@ -574,6 +592,286 @@ impl ExportFormat for Vol {
 //     }
 // }
 
+pub struct Html {
+    pub(crate) cover: Option<Vec<u8>>,
+}
+
+const HTML_STYLE: &str = r#"
+body { margin: 0; background: #222; color: #ddd; font-family: sans-serif; }
+a { color: #8cf; }
+a[href=""] { visibility: hidden; }
+.list { max-width: 640px; margin: 0 auto; padding: 16px; }
+.list img.cover { max-width: 240px; display: block; margin: 0 auto 16px; }
+.list li { margin: 6px 0; }
+.bar { position: sticky; top: 0; display: flex; gap: 12px; align-items: center; padding: 6px 12px; background: #111; z-index: 1; }
+.bar .title { flex: 1; overflow: hidden; white-space: nowrap; text-overflow: ellipsis; }
+.pages img { display: block; margin: 0 auto; max-width: 100%; }
+.paged .pages img { display: none; max-height: calc(100vh - 40px); }
+.paged .pages img.current { display: block; }
+"#;
+
+const HTML_INDEX_TEMPLATE: &str = r#"<!DOCTYPE html>
+<html lang="zh-CN">
+<head>
+<meta charset="utf-8" />
+<meta name="viewport" content="width=device-width, initial-scale=1" />
+<title>{title}</title>
+<style>{style}</style>
+</head>
+<body>
+<div class="list">
+{cover}
+<h1>{title}</h1>
+<ol>
+{items}
+</ol>
+</div>
+</body>
+</html>
+"#;
+
+const HTML_READER_TEMPLATE: &str = r#"<!DOCTYPE html>
+<html lang="zh-CN">
+<head>
+<meta charset="utf-8" />
+<meta name="viewport" content="width=device-width, initial-scale=1" />
+<title>{title}</title>
+<style>{style}</style>
+</head>
+<body>
+<div class="bar">
+<a href="{index}">目录</a>
+<a href="{prev}" id="prev">上一话</a>
+<span class="title">{title}</span>
+<select id="mode">
+<option value="scroll">纵向滚动</option>
+<option value="ltr">从左到右</option>
+<option value="rtl">从右到左</option>
+</select>
+<a href="{next}" id="next">下一话</a>
+</div>
+<div class="pages">
+{images}
+</div>
+<script>
+(function () {
+  var images = Array.prototype.slice.call(document.querySelectorAll(".pages img"));
+  var select = document.getElementById("mode");
+  var mode = localStorage.getItem("bcdown-mode") || "scroll";
+  var current = 0;
+  function go(delta) {
+    var target = current + delta;
+    if (target < 0) {
+      if (document.getElementById("prev").getAttribute("href")) location.href = document.getElementById("prev").href;
+      return;
+    }
+    if (target >= images.length) {
+      if (document.getElementById("next").getAttribute("href")) location.href = document.getElementById("next").href;
+      return;
+    }
+    images[current].classList.remove("current");
+    current = target;
+    images[current].classList.add("current");
+    images[current].loading = "eager";
+    window.scrollTo(0, 0);
+  }
+  function apply() {
+    select.value = mode;
+    document.body.classList.toggle("paged", mode !== "scroll");
+    images.forEach(function (img, i) { img.classList.toggle("current", i === current); });
+    localStorage.setItem("bcdown-mode", mode);
+  }
+  select.addEventListener("change", function () { mode = select.value; apply(); });
+  document.addEventListener("keydown", function (e) {
+    if (mode === "scroll") {
+      if (e.key === "ArrowRight" || e.key === "n") go(images.length);
+      if (e.key === "ArrowLeft" || e.key === "p") go(-current - 1);
+      return;
+    }
+    var forward = mode === "ltr" ? "ArrowRight" : "ArrowLeft";
+    var backward = mode === "ltr" ? "ArrowLeft" : "ArrowRight";
+    if (e.key === forward || e.key === " " || e.key === "ArrowDown") { e.preventDefault(); go(1); }
+    if (e.key === backward || e.key === "ArrowUp") { e.preventDefault(); go(-1); }
+  });
+  document.querySelector(".pages").addEventListener("click", function (e) {
+    if (mode === "scroll") return;
+    var left = e.clientX < window.innerWidth / 2;
+    go((left === (mode === "rtl")) ? 1 : -1);
+  });
+  apply();
+})();
+</script>
+</body>
+</html>
+"#;
+
+pub(crate) fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// 对链接中的每一段进行百分号编码
+pub(crate) fn encode_href(path: &str) -> String {
+    let mut result = String::new();
+    for byte in path.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-_.~/".contains(&byte) {
+            result.push(byte as char);
+        } else {
+            result.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    result
+}
+
+/// 优先使用硬链接，跨分区等情况下退回复制
+fn link_or_copy(from: &Path, to: &Path) -> Result<()> {
+    if to.exists() {
+        std::fs::remove_file(to)?;
+    }
+    if std::fs::hard_link(from, to).is_err() {
+        std::fs::copy(from, to)?;
+    }
+    Ok(())
+}
+
+impl Html {
+    /// 写出一组章节，`path` 为这一组的目录页，图片和阅读页放在同名文件夹中
+    fn write_episodes<P: AsRef<Path>>(
+        &self,
+        episodes: Vec<&EpisodeCache>,
+        title: &str,
+        path: P,
+        config: &Config,
+        exported: &dyn Fn(&EpisodeCache) -> Result<()>,
+    ) -> Result<()> {
+        let path = path.as_ref();
+        let dir = path.with_extension("");
+        let dir_name = dir.file_name().unwrap_or_default().to_string_lossy().to_string();
+        std::fs::create_dir_all(&dir)?;
+
+        let passthrough = is_passthrough(config);
+        let mut items = Vec::new();
+        for (i, ep) in episodes.iter().enumerate() {
+            let image_dir = dir.join("images").join(ep.id.to_string());
+            if image_dir.exists() {
+                std::fs::remove_dir_all(&image_dir)?;
+            }
+            std::fs::create_dir_all(&image_dir)?;
+            let file_names = if passthrough {
+                ep.get_paths()
+                    .iter()
+                    .map(|from| {
+                        let file_name = from.file_name().unwrap_or_default().to_string_lossy().to_string();
+                        link_or_copy(from, &image_dir.join(&file_name))?;
+                        Ok(file_name)
+                    })
+                    .collect::<Result<Vec<_>>>()?
+            } else {
+                load_pages(ep, config)?
+                    .into_iter()
+                    .map(|page| {
+                        std::fs::write(image_dir.join(&page.name), &page.data)?;
+                        Ok(page.name)
+                    })
+                    .collect::<Result<Vec<_>>>()?
+            };
+
+            let images = file_names
+                .iter()
+                .enumerate()
+                .map(|(i, file_name)| {
+                    format!(
+                        r#"<img src="images/{}/{}" alt="{}" loading="{}" />"#,
+                        ep.id,
+                        encode_href(file_name),
+                        i + 1,
+                        if i < 2 { "eager" } else { "lazy" }
+                    )
+                })
+                .collect::<Vec<_>>();
+            let link = |ep: Option<&&EpisodeCache>| {
+                ep.map(|ep| format!("{}.html", ep.id)).unwrap_or_default()
+            };
+            let ep_title = format!("{} {}", ep.short_title, ep.title);
+            let reader = HTML_READER_TEMPLATE
+                .replace("{style}", HTML_STYLE)
+                .replace("{index}", &format!("../{}", encode_href(&format!("{}.html", dir_name))))
+                .replace("{prev}", &link(if i > 0 { episodes.get(i - 1) } else { None }))
+                .replace("{next}", &link(episodes.get(i + 1)))
+                .replace("{images}", &images.join("\n"))
+                .replace("{title}", &escape_html(&ep_title));
+            std::fs::write(dir.join(format!("{}.html", ep.id)), reader)?;
+
+            items.push(format!(
+                r#"<li><a href="{}/{}.html">{}</a></li>"#,
+                encode_href(&dir_name),
+                ep.id,
+                escape_html(&ep_title)
+            ));
+            exported(ep)?;
+        }
+
+        let index = HTML_INDEX_TEMPLATE
+            .replace("{style}", HTML_STYLE)
+            .replace("{cover}", "")
+            .replace("{items}", &items.join("\n"))
+            .replace("{title}", &escape_html(title));
+        std::fs::write(path, index)?;
+        Ok(())
+    }
+}
+
+impl ExportFormat for Html {
+    fn get_extension(&self) -> &'static str {
+        "html"
+    }
+
+    fn export_single<P: AsRef<Path>>(&self, episode: &EpisodeCache, path: P, config: &Config) -> Result<()> {
+        let title = format!("{} {}", episode.short_title, episode.title);
+        self.write_episodes(vec![episode], &title, path, config, &|_| Ok(()))
+    }
+
+    fn export_multiple<P: AsRef<Path>>(
+        &self,
+        episodes: Vec<&EpisodeCache>,
+        title: &str,
+        path: P,
+        config: &Config,
+        exported: &dyn Fn(&EpisodeCache) -> Result<()>,
+    ) -> Result<()> {
+        self.write_episodes(episodes, title, path, config, exported)
+    }
+
+    fn finish<P: AsRef<Path>>(&self, title: &str, out_dir: P, files: &[String], _config: &Config) -> Result<()> {
+        let out_dir = out_dir.as_ref();
+        let cover = if let Some(cover) = self.cover.as_ref() {
+            std::fs::write(out_dir.join("cover.jpg"), cover)?;
+            r#"<img class="cover" src="cover.jpg" alt="cover" />"#
+        } else {
+            ""
+        };
+        let items = files
+            .iter()
+            .map(|file| {
+                format!(
+                    r#"<li><a href="{}">{}</a></li>"#,
+                    encode_href(file),
+                    escape_html(file.trim_end_matches(".html"))
+                )
+            })
+            .collect::<Vec<_>>();
+        let index = HTML_INDEX_TEMPLATE
+            .replace("{style}", HTML_STYLE)
+            .replace("{cover}", cover)
+            .replace("{items}", &items.join("\n"))
+            .replace("{title}", &escape_html(title));
+        std::fs::write(out_dir.join("index.html"), index)?;
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 pub enum Item<'a> {
     Single(&'a EpisodeCache),
@@ -595,7 +893,7 @@ fn get_min_max_ord(episodes: &Vec<&EpisodeCache>) -> (f64, f64) {
 }
 
 impl Item<'_> {
-    fn make_file_name(&self) -> String {
+    pub(crate) fn make_file_name(&self) -> String {
         match self {
             Item::Single(ep) => format!("{}. {} {}", ep.ord, ep.short_title, ep.title),
             Item::Group(eps) => {
@@ -617,49 +915,82 @@ impl Item<'_> {
     }
 }
 
+/// 导出单个项目到 `path`，每写入一话调用一次 `exported`
+pub(crate) fn export_item(
+    comic_name: &str,
+    item: Item,
+    config: &Config,
+    path: &Path,
+    format: &ExportFormatEnum,
+    exported: &dyn Fn(&EpisodeCache) -> Result<()>,
+) -> Result<()> {
+    match item {
+        Item::Single(episode) => {
+            format.export_single(episode, path, config)?;
+            exported(episode)
+        }
+        Item::Group(episodes) => {
+            format.export_multiple(episodes, comic_name, path, config, exported)
+        }
+    }
+}
+
+/// 依次导出全部项目，进度发送到 `events`
+///
+/// 记录中已经导出的文件会被跳过，每导出一个文件更新一次记录，取消时在两个文件之间停止
+#[allow(clippy::too_many_arguments)]
 pub fn export(
     comic_name: &str,
     items: Vec<Item>,
     config: &Config,
     out_dir: &Path,
     format: &ExportFormatEnum,
-) {
-    let m = MultiProgress::new();
-
-    let bar_style = indicatif::ProgressStyle::default_bar()
-        .template("[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}")
-        .unwrap()
-        .progress_chars("##-");
-    let overall_bar = m.add(ProgressBar::new(items.len() as u64));
-    let bar = m.add(ProgressBar::new(1));
-    bar.set_style(bar_style.clone());
-    bar.set_message("等待线程状态...");
-    overall_bar.set_style(bar_style);
-    bar.set_position(0);
-    for item in items {
-        let file_name = format!("{}.{}", item.make_file_name(), format.get_extension());
-        overall_bar.set_message(format!("导出 {}...", &file_name));
-        let path = out_dir.join(&file_name);
-
-        match item {
-            Item::Single(episode) => {
-                bar.set_length(1);
-                bar.set_position(0);
-                format.export_single(episode, path, config);
-                bar.inc(1);
-                bar.finish();
-            }
-            Item::Group(episodes) => {
-                bar.set_length(episodes.len() as u64);
-                bar.set_position(0);
-                // TODO
-                format.export_multiple(episodes, comic_name, path, config, &bar);
-                bar.finish();
-            }
+    events: &Events,
+    cancel: &CancelToken,
+    journal: &mut Journal,
+) -> Result<()> {
+    let mut files = match &journal.job {
+        JobKind::Export { finished, .. } => finished.clone(),
+        _ => Vec::new(),
+    };
+    let items = items
+        .into_iter()
+        .map(|item| {
+            let file = format!("{}.{}", item.make_file_name(), format.get_extension());
+            (file, item)
+        })
+        .filter(|(file, _)| !files.contains(file))
+        .collect::<Vec<_>>();
+    let episodes = items
+        .iter()
+        .map(|(_, item)| match item {
+            Item::Single(_) => 1,
+            Item::Group(episodes) => episodes.len(),
+        })
+        .sum();
+    events.send(Event::ExportPlanned {
+        files: items.len(),
+        episodes,
+    });
+    let tracker = Tracker::new(episodes);
+    let exported = |episode: &EpisodeCache| {
+        episode.mark_exported()?;
+        events.send(Event::EpisodeExported {
+            episode: episode.id,
+        });
+        events.send(tracker.advance());
+        Ok(())
+    };
+    for (file, item) in items {
+        if cancel.is_cancelled() {
+            return Err(Error::Cancelled(journal.id));
         }
-        overall_bar.inc(1);
+        events.send(Event::ExportStarted { file: file.clone() });
+        export_item(comic_name, item, config, &out_dir.join(&file), format, &exported)?;
+        events.send(Event::ExportFinished { file: file.clone() });
+        journal.add_finished(&file)?;
+        files.push(file);
     }
-    overall_bar.finish_and_clear();
-    bar.finish_and_clear();
-    m.clear().unwrap();
+    format.finish(comic_name, out_dir, &files, config)?;
+    Ok(())
 }
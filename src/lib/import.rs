@@ -0,0 +1,393 @@
+//! 把其他工具下载的漫画导入缓存
+//!
+//! 来源可以是文件夹或 zip / cbz 压缩包，其中的每个子文件夹或压缩包是一话。
+//! 图片先按 sha1 与图片索引中的文件名匹配，剩下的图片数量与索引一致时再按顺序匹配，
+//! 按顺序匹配的图片与原图不同，以图片自身的 sha1 保存
+
+use crate::cache::{ComicCache, EpisodeCache};
+use crate::config::{Config, Variant};
+use crate::error::{Error, Result};
+use crate::network::{self, down_to, ComicInfo, EpisodeInfo, Session};
+use crate::range::apply_range;
+use crate::{store, write_atomic};
+use sha1::{Digest, Sha1};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use zip::ZipArchive;
+
+/// 导入的结果
+#[derive(Clone, Debug)]
+pub struct ImportReport {
+    pub comic_id: u32,
+    pub title: String,
+    pub episodes: Vec<ImportedEpisode>,
+    /// 找不到对应章节的文件夹或压缩包
+    pub unmatched: Vec<String>,
+}
+
+/// 导入的一话
+#[derive(Clone, Debug)]
+pub struct ImportedEpisode {
+    pub episode: EpisodeInfo,
+    /// 来源的文件夹或压缩包名称
+    pub source: String,
+    pub status: ImportStatus,
+}
+
+#[derive(Clone, Debug)]
+pub enum ImportStatus {
+    /// 导入完成，`missing` 为仍然缺少、之后可以通过 fetch 下载的图片数量
+    Imported {
+        by_hash: usize,
+        by_order: usize,
+        missing: usize,
+    },
+    /// 本地已经下载完成，没有导入
+    Cached,
+    /// 无法获取图片索引，如未购买的章节
+    Failed(String),
+}
+
+/// 来源中的一话
+struct Unit {
+    name: String,
+    source: Source,
+}
+
+enum Source {
+    /// 文件夹中的图片
+    Dir(PathBuf),
+    /// 压缩包中的图片，文件夹为空时包含压缩包中的所有图片
+    Zip(PathBuf, Option<String>),
+}
+
+impl Source {
+    /// 读取全部图片，按文件名排序
+    fn load(&self) -> Result<Vec<(String, Vec<u8>)>> {
+        let mut pages = Vec::new();
+        match self {
+            Source::Dir(dir) => {
+                for path in fs::read_dir(dir)?.flatten().map(|entry| entry.path()) {
+                    let name = path.file_name().unwrap().to_string_lossy().to_string();
+                    if path.is_file() && is_image(&name) {
+                        pages.push((name, fs::read(path)?));
+                    }
+                }
+            }
+            Source::Zip(path, folder) => {
+                let mut archive = ZipArchive::new(File::open(path)?)?;
+                for index in 0..archive.len() {
+                    let mut file = archive.by_index(index)?;
+                    let name = file.name().to_string();
+                    if !is_image(&name) || folder.as_ref().is_some_and(|folder| parent(&name) != folder) {
+                        continue;
+                    }
+                    let mut bytes = Vec::new();
+                    file.read_to_end(&mut bytes)?;
+                    pages.push((name, bytes));
+                }
+            }
+        }
+        pages.sort_by(|a, b| natural_cmp(&a.0, &b.0));
+        Ok(pages)
+    }
+}
+
+/// 把 `path` 中的漫画导入缓存
+///
+/// `by_order` 时按顺序把文件夹或压缩包对应到 `range` 范围内的章节，否则按名称中的第一个数字对应章节的序号
+pub(crate) async fn import(
+    config: &Config,
+    session: &Session,
+    id: u32,
+    comic_info: ComicInfo,
+    path: &Path,
+    by_order: bool,
+    range: &str,
+) -> Result<ImportReport> {
+    let units = find_units(path)?;
+    if units.is_empty() {
+        return Err(Error::NothingToImport(path.display().to_string()));
+    }
+    let comic_root = Path::new(&config.cache_dir).join(id.to_string());
+    ComicCache {
+        id,
+        title: comic_info.title.clone(),
+        episodes: HashMap::new(),
+    }
+    .sync(&comic_root)?;
+    let cover_path = comic_root.join("cover.jpg");
+    if !cover_path.is_file() {
+        // 封面不影响导入，下载失败时之后 fetch 会再次尝试
+        down_to(session, comic_info.vertical_cover.clone(), &cover_path).await;
+    }
+
+    let episodes = apply_range(comic_info.ep_list.clone(), range)?;
+    let mut report = ImportReport {
+        comic_id: id,
+        title: comic_info.title.clone(),
+        episodes: Vec::new(),
+        unmatched: Vec::new(),
+    };
+    for (index, unit) in units.into_iter().enumerate() {
+        let episode = if by_order {
+            episodes.get(index)
+        } else {
+            episode_number(&unit.name).and_then(|ord| episodes.iter().find(|ep| ep.ord == ord))
+        };
+        let episode = match episode {
+            Some(episode) => episode.clone(),
+            None => {
+                report.unmatched.push(unit.name);
+                continue;
+            }
+        };
+        let ep_root = comic_root.join(episode.id.to_string());
+        let status = if EpisodeCache::load(&ep_root)
+            .is_some_and(|cached| cached.not_downloaded_uncheck().is_empty())
+        {
+            ImportStatus::Cached
+        } else {
+            match import_episode(session, &episode, &ep_root, &unit.source).await {
+                Ok(status) => status,
+                Err(e @ (Error::Network(_) | Error::Api(_))) => ImportStatus::Failed(e.to_string()),
+                Err(e) => return Err(e),
+            }
+        };
+        report.episodes.push(ImportedEpisode {
+            episode,
+            source: unit.name,
+            status,
+        });
+    }
+    Ok(report)
+}
+
+async fn import_episode(
+    session: &Session,
+    ep: &EpisodeInfo,
+    ep_root: &Path,
+    source: &Source,
+) -> Result<ImportStatus> {
+    let indexes = network::get_episode_images(session, ep.id).await?;
+    let mut episode = EpisodeCache {
+        id: ep.id,
+        short_title: ep.short_title.to_owned(),
+        title: ep.title.to_owned(),
+        files: vec![],
+        paths: indexes.paths,
+        host: indexes.host,
+        ord: ep.ord,
+        root_dir: ep_root.to_path_buf(),
+        variant: Variant::default(),
+        exported: None,
+        store: store::dir_of(ep_root),
+        unverified: false,
+        imported: BTreeMap::new(),
+    };
+    let pages = source.load()?;
+    let hashes = pages
+        .iter()
+        .map(|(_, bytes)| format!("{:x}", Sha1::digest(bytes)))
+        .collect::<Vec<_>>();
+
+    let mut used = vec![false; pages.len()];
+    let mut unmatched = Vec::new();
+    let mut by_hash = 0;
+    let names = episode.blob_names();
+    for name in &names {
+        let stem = name.split('.').next().unwrap_or_default();
+        match hashes.iter().position(|hash| hash == stem) {
+            Some(index) => {
+                used[index] = true;
+                write_blob(&episode.store, name, &pages[index].1)?;
+                by_hash += 1;
+            }
+            None => unmatched.push(name),
+        }
+    }
+    // 其他下载器可能重新编码了图片，数量一致时按顺序对应
+    let rest = pages
+        .iter()
+        .zip(used)
+        .filter(|(_, used)| !used)
+        .map(|(page, _)| page)
+        .collect::<Vec<_>>();
+    let mut by_order = 0;
+    if !unmatched.is_empty() && rest.len() == unmatched.len() {
+        // 与原图不同的图片以自身的 sha1 命名，不会占用原图的文件名
+        for (name, (file_name, bytes)) in unmatched.iter().zip(rest) {
+            let local = local_name(name, file_name, bytes);
+            write_blob(&episode.store, &local, bytes)?;
+            episode.imported.insert(name.to_string(), local);
+        }
+        by_order = unmatched.len();
+    }
+    let missing = episode
+        .blob_names()
+        .iter()
+        .filter(|name| !store::blob_path(&episode.store, name).is_file())
+        .count();
+    episode.sync(ep_root)?;
+    Ok(ImportStatus::Imported {
+        by_hash,
+        by_order,
+        missing,
+    })
+}
+
+/// 按顺序导入的图片在存储中的文件名，与原图一样由 sha1 和扩展名组成
+fn local_name(name: &str, file_name: &str, bytes: &[u8]) -> String {
+    let extension = Path::new(file_name)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .or_else(|| name.rsplit_once('.').map(|(_, extension)| extension))
+        .unwrap_or("jpg")
+        .to_lowercase();
+    format!("{:x}.{}", Sha1::digest(bytes), extension)
+}
+
+/// 写入存储，已有的图片不会被覆盖，中断时不会留下不完整的图片
+fn write_blob(store: &Path, name: &str, bytes: &[u8]) -> Result<()> {
+    let path = store::blob_path(store, name);
+    if path.is_file() {
+        return Ok(());
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    write_atomic(path, bytes)?;
+    Ok(())
+}
+
+/// 找出来源中的每一话，按名称排序
+///
+/// 文件夹中的每个子文件夹和压缩包是一话，没有的话文件夹本身是一话；
+/// 压缩包中的每个包含图片的文件夹是一话，根目录下的图片也算一话
+fn find_units(path: &Path) -> Result<Vec<Unit>> {
+    let mut units = Vec::new();
+    if path.is_file() {
+        if !is_archive(path) {
+            return Err(Error::NothingToImport(path.display().to_string()));
+        }
+        let archive = ZipArchive::new(File::open(path)?)?;
+        let mut folders = Vec::new();
+        for name in archive.file_names().filter(|name| is_image(name)) {
+            let folder = parent(name).to_string();
+            if !folders.contains(&folder) {
+                folders.push(folder);
+            }
+        }
+        for folder in folders {
+            let name = match folder.rsplit('/').next() {
+                Some(name) if !name.is_empty() => name.to_string(),
+                _ => file_stem(path),
+            };
+            units.push(Unit {
+                name,
+                source: Source::Zip(path.to_path_buf(), Some(folder)),
+            });
+        }
+    } else {
+        let mut has_images = false;
+        for entry in fs::read_dir(path)?.flatten() {
+            let entry_path = entry.path();
+            let name = entry.file_name().to_string_lossy().to_string();
+            if entry_path.is_dir() {
+                units.push(Unit {
+                    name,
+                    source: Source::Dir(entry_path),
+                });
+            } else if is_archive(&entry_path) {
+                units.push(Unit {
+                    name: file_stem(&entry_path),
+                    source: Source::Zip(entry_path, None),
+                });
+            } else if is_image(&name) {
+                has_images = true;
+            }
+        }
+        if units.is_empty() && has_images {
+            units.push(Unit {
+                name: file_stem(path),
+                source: Source::Dir(path.to_path_buf()),
+            });
+        }
+    }
+    units.sort_by(|a, b| natural_cmp(&a.name, &b.name));
+    Ok(units)
+}
+
+fn is_image(name: &str) -> bool {
+    let name = name.to_lowercase();
+    [".jpg", ".jpeg", ".png", ".webp"]
+        .iter()
+        .any(|extension| name.ends_with(extension))
+}
+
+fn is_archive(path: &Path) -> bool {
+    path.is_file()
+        && path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .is_some_and(|extension| matches!(extension.to_lowercase().as_str(), "zip" | "cbz"))
+}
+
+/// 压缩包中文件所在的文件夹，根目录为空字符串
+fn parent(name: &str) -> &str {
+    name.rsplit_once('/').map(|(parent, _)| parent).unwrap_or("")
+}
+
+fn file_stem(path: &Path) -> String {
+    path.file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+/// 名称中的第一个数字，如 `第12话` `012 标题` `1.5`
+fn episode_number(name: &str) -> Option<f64> {
+    let start = name.find(|c: char| c.is_ascii_digit())?;
+    let rest = &name[start..];
+    let end = rest
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(rest.len());
+    rest[..end].trim_end_matches('.').parse().ok()
+}
+
+/// 按名称中数字的大小比较，使 `2.jpg` 排在 `10.jpg` 前面
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let mut a = a.chars().peekable();
+    let mut b = b.chars().peekable();
+    loop {
+        match (a.peek().copied(), b.peek().copied()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                match take_number(&mut a).cmp(&take_number(&mut b)) {
+                    Ordering::Equal => continue,
+                    ordering => return ordering,
+                }
+            }
+            (Some(x), Some(y)) => {
+                if x != y {
+                    return x.cmp(&y);
+                }
+                a.next();
+                b.next();
+            }
+        }
+    }
+}
+
+fn take_number(chars: &mut std::iter::Peekable<std::str::Chars>) -> u64 {
+    let mut number = 0u64;
+    while let Some(digit) = chars.peek().and_then(|c| c.to_digit(10)) {
+        number = number.saturating_mul(10).saturating_add(digit as u64);
+        chars.next();
+    }
+    number
+}
@@ -0,0 +1,175 @@
+//! 任务的取消和记录
+//!
+//! 下载和导出任务开始时会在缓存目录的 `.jobs` 文件夹中写入一份记录，
+//! 进度变化时更新，任务完成后删除。任务被取消或中断后可以根据记录继续
+
+use crate::config::{Config, Variant};
+use crate::error::Result;
+use crate::network::EpisodeInfo;
+use crate::ExportOptions;
+use serde::{Deserialize, Serialize};
+use std::fs::OpenOptions;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::sync::Notify;
+
+/// 取消任务的信号，克隆出的所有副本共享同一个状态
+///
+/// 取消后正在下载的图片和正在导出的文件会先完成，之后任务返回 [`crate::Error::Cancelled`]
+#[derive(Clone, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+    notify: Arc<Notify>,
+}
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        self.notify.notify_waiters();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// 等待任务被取消
+    pub async fn cancelled(&self) {
+        let notified = self.notify.notified();
+        if self.is_cancelled() {
+            return;
+        }
+        notified.await;
+    }
+}
+
+/// 还没有下载完成的一话，继续时会重新检查缺少的图片
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PendingEpisode {
+    pub episode: EpisodeInfo,
+}
+
+/// 任务的内容和进度
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum JobKind {
+    Fetch {
+        range: String,
+        /// 还没有完成的章节
+        pending: Vec<PendingEpisode>,
+        /// 范围内未解锁的章节
+        #[serde(default)]
+        locked: Vec<EpisodeInfo>,
+        /// 下载图片使用的尺寸和格式
+        #[serde(default)]
+        variant: Variant,
+    },
+    Export {
+        options: ExportOptions,
+        /// 已经导出的文件名
+        finished: Vec<String>,
+    },
+}
+
+/// 一个未完成任务的记录
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Journal {
+    pub id: u64,
+    pub comic_id: u32,
+    /// 任务创建的时间
+    pub created: String,
+    pub job: JobKind,
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+impl Journal {
+    pub(crate) fn create(config: &Config, comic_id: u32, job: JobKind) -> Result<Journal> {
+        let now = chrono::Local::now();
+        let dir = dir(config);
+        std::fs::create_dir_all(&dir)?;
+        // 同一毫秒内创建的任务 (如服务器和命令行同时运行) 依次使用下一个ID
+        let mut id = now.timestamp_millis() as u64;
+        let path = loop {
+            let path = dir.join(format!("{}.json", id));
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(_) => break path,
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => id += 1,
+                Err(e) => return Err(e.into()),
+            }
+        };
+        let journal = Journal {
+            id,
+            comic_id,
+            created: now.format("%Y-%m-%d %H:%M:%S").to_string(),
+            job,
+            path,
+        };
+        journal.save()?;
+        Ok(journal)
+    }
+
+    /// 缓存中全部未完成的任务，按创建时间排列
+    pub(crate) fn list(config: &Config) -> Vec<Journal> {
+        let entries = match std::fs::read_dir(dir(config)) {
+            Ok(entries) => entries,
+            Err(_) => return Vec::new(),
+        };
+        let mut journals = entries
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| !crate::is_temp_file(path))
+            .filter_map(|path| Journal::load(&path))
+            .collect::<Vec<_>>();
+        journals.sort_by_key(|journal| journal.id);
+        journals
+    }
+
+    fn load(path: &Path) -> Option<Journal> {
+        let content = std::fs::read_to_string(path).ok()?;
+        let mut journal: Journal = serde_json::from_str(&content).ok()?;
+        journal.path = path.to_path_buf();
+        Some(journal)
+    }
+
+    pub(crate) fn save(&self) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        crate::write_atomic(&self.path, serde_json::to_string_pretty(self).unwrap().as_bytes())?;
+        Ok(())
+    }
+
+    /// 任务完成，删除记录
+    pub(crate) fn remove(&self) -> Result<()> {
+        if self.path.is_file() {
+            std::fs::remove_file(&self.path)?;
+        }
+        Ok(())
+    }
+
+    /// 某一话的图片全部下载完成，从记录中移除
+    pub(crate) fn finish_episode(&mut self, episode: u32) -> Result<()> {
+        if let JobKind::Fetch { pending, .. } = &mut self.job {
+            pending.retain(|pending| pending.episode.id != episode);
+        }
+        self.save()
+    }
+
+    /// 记录一个已经导出的文件
+    pub(crate) fn add_finished(&mut self, file: &str) -> Result<()> {
+        if let JobKind::Export { finished, .. } = &mut self.job {
+            finished.push(file.to_string());
+        }
+        self.save()
+    }
+}
+
+fn dir(config: &Config) -> PathBuf {
+    Path::new(&config.cache_dir).join(".jobs")
+}
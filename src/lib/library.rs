@@ -0,0 +1,897 @@
+use crate::bundle::{self, Bundle, BundleReport};
+use crate::cache::{Cache, ComicCache, EpisodeCache};
+use crate::config::{Config, Direction, SpreadMode, Variant};
+use crate::error::{Error, Result};
+use crate::event::{Event, Events, Tracker};
+use crate::job::{CancelToken, JobKind, Journal, PendingEpisode};
+use crate::exports::{self, Item};
+use crate::import::{self, ImportReport};
+use crate::listing::{self, ComicListing, ListOptions};
+use crate::lock;
+use crate::network::{
+    self, down_to, fix_episode_title, ComicInfo, DownloadError, EpisodeInfo, Session, UserInfo,
+};
+use crate::prune::{self, PrunePolicy, PruneReport};
+use crate::remote::{self, RemoteInfo};
+use crate::range::{apply_range, EpisodeRange};
+use crate::{create_hash, delete_all_files, get_dir_size, make_groups, pages, pdf, server, store};
+use serde::{Deserialize, Deserializer, Serialize};
+use sha1::Sha1;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// 漫画库，所有操作都使用创建时传入的配置
+#[derive(Clone)]
+pub struct Library {
+    config: Config,
+    /// 第一次访问网络时创建，之后所有请求共用
+    session: Arc<Mutex<Option<Session>>>,
+    /// 每一话下载失败后的重试次数，为 `None` 时一直重试直到取消
+    retries: Option<u32>,
+}
+
+/// 导出格式
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Epub,
+    Pdf,
+    Zip,
+    /// 不含文件夹、按顺序命名图片的 zip
+    #[serde(rename = "vol.zip")]
+    VolZip,
+    /// 可以离线浏览的网页
+    Html,
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "epub" => Ok(Format::Epub),
+            "pdf" => Ok(Format::Pdf),
+            "zip" => Ok(Format::Zip),
+            "vol.zip" => Ok(Format::VolZip),
+            "html" => Ok(Format::Html),
+            _ => Err(Error::UnknownFormat(s.to_string())),
+        }
+    }
+}
+
+impl Display for Format {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Format::Epub => "epub",
+            Format::Pdf => "pdf",
+            Format::Zip => "zip",
+            Format::VolZip => "vol.zip",
+            Format::Html => "html",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// 导出参数
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ExportOptions {
+    pub range: String,
+    /// 分组导出每组包含的章节数量，0为不分组
+    pub grouping: usize,
+    /// 每一话输出一个文件
+    pub split_episodes: bool,
+    /// 为空时使用配置中的默认下载目录
+    pub export_dir: Option<PathBuf>,
+    pub format: Format,
+    /// 覆盖配置文件中的设备配置
+    pub profile: Option<String>,
+    /// 条漫模式，内层为空时自动选择高宽比
+    #[serde(default, skip_serializing_if = "Option::is_none", deserialize_with = "some")]
+    pub webtoon: Option<Option<f64>>,
+    /// 覆盖配置文件中的跨页处理方式
+    pub spread: Option<SpreadMode>,
+    /// 覆盖配置文件中的阅读方向
+    pub direction: Option<Direction>,
+}
+
+/// 字段存在时即为 `Some`，用于区分 `Option<Option<T>>` 的两种空值
+fn some<'de, D: Deserializer<'de>, T: Deserialize<'de>>(deserializer: D) -> std::result::Result<Option<T>, D::Error> {
+    T::deserialize(deserializer).map(Some)
+}
+
+impl ExportOptions {
+    /// 将导出参数应用到配置上，返回导出时实际使用的配置
+    pub fn apply(&self, config: &Config) -> Result<Config> {
+        let mut config = config.clone();
+        if let Some(profile) = &self.profile {
+            config.profile = Some(profile.to_string());
+        }
+        if let Some(spread) = self.spread {
+            config.spread = spread;
+        }
+        if let Some(direction) = self.direction {
+            config.direction = direction;
+        }
+        // 提前检查配置是否存在
+        pages::check(&config)?;
+        if let Some(ratio) = self.webtoon {
+            // 未指定比例时使用设备屏幕的比例，没有设备配置则使用A4纸的比例
+            let ratio = ratio
+                .or_else(|| config.get_profile().ok().flatten().and_then(|p| p.ratio()))
+                .unwrap_or(pdf::H / pdf::W);
+            config.webtoon = Some(ratio);
+        }
+        Ok(config)
+    }
+}
+
+/// 某一话的检查结果
+pub struct EpisodeCheck {
+    pub episode: EpisodeInfo,
+    pub status: CheckStatus,
+}
+
+pub enum CheckStatus {
+    Ok,
+    /// 未下载
+    Missing,
+    /// 校验失败的图片，已经从缓存中删除
+    Corrupted(Vec<CorruptedPage>),
+}
+
+pub struct CorruptedPage {
+    /// 页码，从0开始
+    pub index: usize,
+    /// 文件名中记录的 SHA-1
+    pub expected: String,
+    pub actual: String,
+}
+
+impl Library {
+    pub fn new(config: Config) -> Library {
+        Library {
+            config,
+            session: Arc::default(),
+            retries: None,
+        }
+    }
+
+    /// 限制下载失败后的重试次数，用完后任务以 [`Error::FetchFailed`] 结束
+    pub fn with_retries(mut self, retries: u32) -> Library {
+        self.retries = Some(retries);
+        self
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// 共用的网络会话，代理和证书设置有误时返回错误
+    pub fn session(&self) -> Result<Session> {
+        let mut session = self.session.lock().unwrap();
+        if let Some(session) = &*session {
+            return Ok(session.clone());
+        }
+        let created = Session::new(&self.config)?;
+        *session = Some(created.clone());
+        Ok(created)
+    }
+
+    /// 登录用户的信息，未登录时返回 `None`
+    pub async fn user_info(&self) -> Result<Option<UserInfo>> {
+        network::get_user_info(&self.session()?).await
+    }
+
+    /// 获取漫画信息，章节按顺序排列
+    ///
+    /// 获取成功后会保存一份副本，离线时可以通过 [`Library::remote_info`] 查看
+    pub async fn comic_info(&self, id: u32) -> Result<ComicInfo> {
+        let mut comic_info = network::get_comic_info(&self.session()?, id).await?;
+        comic_info
+            .ep_list
+            .sort_by(|a, b| a.ord.partial_cmp(&b.ord).unwrap());
+        remote::save(&self.config, &RemoteInfo::fresh(comic_info.clone()))?;
+        Ok(comic_info)
+    }
+
+    /// 获取漫画信息，`offline` 或网络请求失败时使用保存的副本
+    pub async fn remote_info(&self, id: u32, offline: bool) -> Result<RemoteInfo> {
+        if offline {
+            return self.saved_info(id).ok_or(Error::NoRemoteInfo(id));
+        }
+        match self.comic_info(id).await {
+            Ok(comic_info) => Ok(RemoteInfo::fresh(comic_info)),
+            Err(e @ (Error::Network(_) | Error::Client(_))) => self.saved_info(id).ok_or(e),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// 保存在本地的漫画信息，不会访问网络
+    pub fn saved_info(&self, id: u32) -> Option<RemoteInfo> {
+        remote::load(&self.config, id)
+    }
+
+    /// 加载本地缓存
+    pub fn cache(&self) -> Cache {
+        Cache::load(&self.config)
+    }
+
+    /// 本地缓存中的漫画
+    pub fn comic(&self, id: u32) -> Option<ComicCache> {
+        ComicCache::load(Path::new(&self.config.cache_dir).join(id.to_string()))
+    }
+
+    /// 缓存目录的大小 (字节)
+    pub fn cache_size(&self) -> u64 {
+        get_dir_size(&self.config.cache_dir)
+    }
+
+    /// 列出缓存中的漫画
+    ///
+    /// `remote` 时获取最新的漫画信息，否则使用保存的信息，用于列出还没有缓存的章节
+    pub async fn list(&self, options: &ListOptions) -> Result<Vec<ComicListing>> {
+        store::migrate(&self.config)?;
+        let _lock = lock::shared(&self.config)?;
+        let comics = match options.id {
+            Some(id) => vec![self.comic(id).ok_or(Error::NotCached(id))?],
+            None => self.cache().comics.into_values().collect(),
+        };
+        let mut listings = Vec::new();
+        for comic in &comics {
+            let remote = if options.remote {
+                self.remote_info(comic.id, options.offline).await.ok()
+            } else {
+                None
+            };
+            let remote = remote.or_else(|| self.saved_info(comic.id));
+            listings.push(listing::comic_listing(&self.config, comic, remote));
+        }
+        Ok(listing::filter_and_sort(listings, options))
+    }
+
+    /// 清空缓存
+    pub fn clear(&self) -> Result<()> {
+        let _lock = lock::exclusive(&self.config)?;
+        // 锁文件需要保留，其他进程可能正在等待
+        for entry in std::fs::read_dir(&self.config.cache_dir)? {
+            let path = entry?.path();
+            if path.file_name().is_some_and(|name| name == lock::LOCK_DIR) {
+                continue;
+            }
+            if path.is_dir() {
+                delete_all_files(&path)?;
+                std::fs::remove_dir(path)?;
+            } else {
+                std::fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 清除一部漫画在 `range` 范围内的缓存，`range` 为空时清除整部漫画
+    ///
+    /// `keep_meta` 时只删除图片，保留 meta.toml。返回清除的章节数量和释放的空间 (字节)
+    pub fn clear_comic(&self, id: u32, range: &str, keep_meta: bool) -> Result<(usize, u64)> {
+        store::migrate(&self.config)?;
+        let _lock = lock::exclusive(&self.config)?;
+        let comic = self.comic(id).ok_or(Error::NotCached(id))?;
+        let comic_root = Path::new(&self.config.cache_dir).join(id.to_string());
+        let before = self.cache_size();
+        let mut episodes = comic.episodes.values().collect::<Vec<_>>();
+        episodes.sort_by(|a, b| a.ord.partial_cmp(&b.ord).unwrap());
+        let episodes = apply_range(episodes, range)?;
+        // 图片可能被其他章节共用，删除章节后再清理存储中不再使用的图片
+        let mut excluded = Vec::new();
+        if keep_meta {
+            excluded.extend(episodes.iter().map(|episode| episode.root_dir.clone()));
+        } else if range.is_empty() {
+            std::fs::remove_dir_all(&comic_root)?;
+        } else {
+            for episode in &episodes {
+                std::fs::remove_dir_all(&episode.root_dir)?;
+            }
+        }
+        store::sweep(&self.config, &excluded)?;
+        Ok((episodes.len(), before.saturating_sub(self.cache_size())))
+    }
+
+    /// 把其他工具下载的文件夹或压缩包导入为漫画 `id` 的缓存
+    ///
+    /// `by_order` 时按顺序对应 `range` 范围内的章节，否则按名称中的数字对应章节的序号
+    pub async fn import(&self, id: u32, path: &Path, by_order: bool, range: &str) -> Result<ImportReport> {
+        let comic_info = self.comic_info(id).await?;
+        store::migrate(&self.config)?;
+        let _lock = lock::comic(&self.config, id, true).await?;
+        import::import(&self.config, &self.session()?, id, comic_info, path, by_order, range).await
+    }
+
+    /// 把缓存中的漫画打包，`path` 为空时保存到默认下载目录，返回缓存包的路径
+    pub fn export_bundle(&self, id: u32, range: &str, path: Option<PathBuf>) -> Result<(PathBuf, Bundle)> {
+        store::migrate(&self.config)?;
+        let _lock = lock::comic_blocking(&self.config, id, false)?;
+        let comic = self.comic(id).ok_or(Error::NotCached(id))?;
+        let path = path.unwrap_or_else(|| bundle::default_path(&self.config, &comic.title));
+        let bundle = bundle::export(&self.config, id, range, &path)?;
+        Ok((path, bundle))
+    }
+
+    /// 把缓存包合并到缓存中
+    pub fn import_bundle(&self, path: &Path) -> Result<BundleReport> {
+        store::migrate(&self.config)?;
+        let _lock = lock::exclusive(&self.config)?;
+        bundle::import(&self.config, path)
+    }
+
+    /// 按策略清理缓存，`dry_run` 时只计算会释放的空间
+    pub fn prune(&self, policy: &PrunePolicy, dry_run: bool) -> Result<PruneReport> {
+        store::migrate(&self.config)?;
+        let _lock = if dry_run {
+            lock::shared(&self.config)?
+        } else {
+            lock::exclusive(&self.config)?
+        };
+        prune::prune(&self.config, policy, dry_run)
+    }
+
+    /// 校验本地缓存中的图片，删除损坏的图片
+    ///
+    /// 章节列表来自 [`Library::remote_info`]，`offline` 时不访问网络。`range` 为空时校验全部章节
+    pub async fn check(&self, id: u32, range: &str, offline: bool) -> Result<(RemoteInfo, Vec<EpisodeCheck>)> {
+        let selection = range.parse::<EpisodeRange>()?;
+        let remote_info = self.remote_info(id, offline).await?;
+        // 校验时会删除损坏的图片
+        store::migrate(&self.config)?;
+        let _lock = lock::comic(&self.config, id, true).await?;
+        let comic = self.comic(id).ok_or(Error::NotCached(id))?;
+        let episodes = selection
+            .apply(remote_info.comic.ep_list.clone())?
+            .into_iter()
+            .map(|ep| {
+                let status = match comic.get_episode(ep.id) {
+                    Some(episode) => {
+                        let corrupted = check_episode(episode)?;
+                        if corrupted.is_empty() {
+                            CheckStatus::Ok
+                        } else {
+                            CheckStatus::Corrupted(corrupted)
+                        }
+                    }
+                    None => CheckStatus::Missing,
+                };
+                Ok(EpisodeCheck {
+                    episode: ep,
+                    status,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok((remote_info, episodes))
+    }
+
+    /// 下载漫画到缓存，进度发送到 `events`
+    ///
+    /// 返回需要下载的章节数量，为0时说明没有需要下载的章节。
+    /// 被取消时返回 [`Error::Cancelled`]，之后可以通过 [`Library::resume_fetch`] 继续
+    pub async fn fetch(
+        &self,
+        id: u32,
+        range: &str,
+        events: &Events,
+        cancel: &CancelToken,
+    ) -> Result<usize> {
+        let config = &self.config;
+        // 先检查范围，避免无效的范围在获取漫画信息后才报错
+        let selection = range.parse::<EpisodeRange>()?;
+        // 范围需要在排好序的章节上计算
+        let comic_info = self.comic_info(id).await?;
+        store::migrate(&self.config)?;
+        let _lock = lock::comic(config, id, true).await?;
+        let cache = Cache::load(config);
+        let cache_root = Path::new(&config.cache_dir);
+        if !cache_root.join(format!("{}", id)).is_dir() {
+            std::fs::create_dir_all(cache_root.join(format!("{}", id)))?;
+        }
+        let cover_path = &cache_root.join(format!("{}", id)).join("cover.jpg");
+
+        let comic_cache = if let Some(comic) = cache.get_comic(id) {
+            let mut comic = comic.clone();
+            comic.title = comic_info.title.to_owned();
+            comic
+        } else {
+            // 并没有这个漫画的缓存，则创建一个
+            // 保存漫画封面
+            ComicCache {
+                id,
+                title: comic_info.title.to_owned(),
+                episodes: HashMap::new(),
+            }
+        };
+        if !cover_path.is_file()
+            && (down_to(&self.session()?, comic_info.vertical_cover.clone(), cover_path).await)
+                .is_none()
+        {
+            return Err(Error::Api("漫画封面下载失败".to_string()));
+        }
+        comic_cache.sync(&cache_root.join(format!("{}", id)))?;
+        // 获取全部可用章节
+
+        let variant = config.variant();
+        // 先在全部章节上选择范围，latest:N 等才是相对于整部漫画
+        let selected = selection.apply(comic_info.ep_list.clone())?;
+        let mut ep_list = selected.clone();
+        ep_list.retain(|ep| {
+            if ep.is_locked {
+                false
+            } else if let Some(ep_cache) = comic_cache.get_episode(ep.id) {
+                // 尺寸或格式不同时重新下载整话
+                ep_cache.variant != variant || !ep_cache.not_downloaded().is_empty()
+            } else {
+                true
+            }
+        });
+        if ep_list.is_empty() {
+            return Ok(0);
+        }
+        let locked = selected.into_iter().filter(|ep| ep.is_locked).collect();
+
+        let pending = ep_list
+            .into_iter()
+            .map(|episode| PendingEpisode { episode })
+            .collect();
+        let journal = Journal::create(
+            config,
+            id,
+            JobKind::Fetch {
+                range: range.to_string(),
+                pending,
+                locked,
+                variant,
+            },
+        )?;
+        self.run_fetch(journal, events, cancel).await
+    }
+
+    /// 继续一个未完成的下载任务
+    pub async fn resume_fetch(
+        &self,
+        journal: Journal,
+        events: &Events,
+        cancel: &CancelToken,
+    ) -> Result<usize> {
+        match journal.job {
+            JobKind::Fetch { .. } => {
+                store::migrate(&self.config)?;
+                let _lock = lock::comic(&self.config, journal.comic_id, true).await?;
+                self.run_fetch(journal, events, cancel).await
+            }
+            _ => Err(Error::UnknownJob(journal.id)),
+        }
+    }
+
+    async fn run_fetch(
+        &self,
+        journal: Journal,
+        events: &Events,
+        cancel: &CancelToken,
+    ) -> Result<usize> {
+        let (ep_list, locked, variant) = match &journal.job {
+            JobKind::Fetch {
+                pending,
+                locked,
+                variant,
+                ..
+            } => (
+                pending
+                    .iter()
+                    .map(|pending| pending.episode.clone())
+                    .collect::<Vec<_>>(),
+                locked.clone(),
+                *variant,
+            ),
+            _ => (Vec::new(), Vec::new(), Variant::default()),
+        };
+        let session = self.session()?;
+        let comic_root = Path::new(&self.config.cache_dir).join(journal.comic_id.to_string());
+        events.send(Event::FetchPlanned {
+            episodes: ep_list.clone(),
+            locked,
+        });
+        let tracker = Arc::new(Tracker::new(0));
+        let journal = Arc::new(Mutex::new(journal));
+        // 重试次数用完的章节最后一次失败的原因
+        let failed = Arc::new(Mutex::new(None));
+
+        let mut tasks = Vec::new();
+        for ep in ep_list.iter() {
+            let ep_root = comic_root.join(format!("{}", ep.id));
+            let events = events.clone();
+            let cancel = cancel.clone();
+            let tracker = tracker.clone();
+            let journal = journal.clone();
+            let failed = failed.clone();
+            let session = session.clone();
+            let ep = ep.clone();
+            let retries = self.retries;
+            tasks.push(tokio::task::spawn(async move {
+                // 只在第一次尝试时计入总量，重试时不会重复计算
+                let mut planned = false;
+                let mut failures = 0;
+                loop {
+                    let ep_cache = EpisodeCache::load(&ep_root);
+                    let task = Task {
+                        session: &session,
+                        variant,
+                        ep: &ep,
+                        ep_root: &ep_root,
+                        events: &events,
+                        tracker: &tracker,
+                        cancel: &cancel,
+                        journal: &journal,
+                    };
+                    match task.run(ep_cache, &mut planned).await {
+                        Ok(()) => {
+                            events.send(Event::EpisodeFinished { episode: ep.id });
+                            break;
+                        }
+                        Err(Error::Cancelled(_)) => break,
+                        Err(e) => {
+                            let reason = e.to_string();
+                            events.send(Event::EpisodeFailed {
+                                episode: ep.id,
+                                reason: reason.clone(),
+                            });
+                            failures += 1;
+                            if retries.is_some_and(|retries| failures > retries) {
+                                *failed.lock().unwrap() = Some(reason);
+                                break;
+                            }
+                        }
+                    }
+                    tokio::select! {
+                        _ = tokio::time::sleep(Duration::from_secs(3)) => {}
+                        _ = cancel.cancelled() => break,
+                    }
+                }
+            }))
+        }
+
+        futures::future::join_all(tasks).await;
+        let journal = journal.lock().unwrap();
+        match &journal.job {
+            // 没有取消并且没有限制重试次数时每一话都会一直重试到下载完成
+            JobKind::Fetch { pending, .. } if !pending.is_empty() => {
+                journal.save()?;
+                match failed.lock().unwrap().take() {
+                    Some(reason) => Err(Error::FetchFailed(journal.id, reason)),
+                    None => Err(Error::Cancelled(journal.id)),
+                }
+            }
+            _ => {
+                journal.remove()?;
+                Ok(ep_list.len())
+            }
+        }
+    }
+
+    /// 导出漫画，进度发送到 `events`，返回输出目录
+    ///
+    /// 被取消时返回 [`Error::Cancelled`]，之后可以通过 [`Library::resume_export`] 继续
+    pub fn export(
+        &self,
+        id: u32,
+        options: &ExportOptions,
+        events: &Events,
+        cancel: &CancelToken,
+    ) -> Result<PathBuf> {
+        store::migrate(&self.config)?;
+        let _lock = lock::comic_blocking(&self.config, id, false)?;
+        let journal = Journal::create(
+            &self.config,
+            id,
+            JobKind::Export {
+                options: options.clone(),
+                finished: Vec::new(),
+            },
+        )?;
+        self.run_export(journal, events, cancel)
+    }
+
+    /// 继续一个未完成的导出任务，已经导出的文件不会重新导出
+    pub fn resume_export(
+        &self,
+        journal: Journal,
+        events: &Events,
+        cancel: &CancelToken,
+    ) -> Result<PathBuf> {
+        match journal.job {
+            JobKind::Export { .. } => {
+                store::migrate(&self.config)?;
+                let _lock = lock::comic_blocking(&self.config, journal.comic_id, false)?;
+                self.run_export(journal, events, cancel)
+            }
+            _ => Err(Error::UnknownJob(journal.id)),
+        }
+    }
+
+    fn run_export(
+        &self,
+        mut journal: Journal,
+        events: &Events,
+        cancel: &CancelToken,
+    ) -> Result<PathBuf> {
+        let result = self.export_journal(&mut journal, events, cancel);
+        match &result {
+            Err(Error::Cancelled(_)) => {}
+            // 其他错误无法通过重试解决，不保留记录
+            _ => journal.remove()?,
+        }
+        result
+    }
+
+    fn export_journal(
+        &self,
+        journal: &mut Journal,
+        events: &Events,
+        cancel: &CancelToken,
+    ) -> Result<PathBuf> {
+        let id = journal.comic_id;
+        let options = match &journal.job {
+            JobKind::Export { options, .. } => options.clone(),
+            _ => return Err(Error::UnknownJob(journal.id)),
+        };
+        let config = options.apply(&self.config)?;
+        let cache = Cache::load(&config);
+        let comic_cache = cache.get_comic(id).ok_or(Error::NotCached(id))?;
+        let mut ep_list = comic_cache.episodes.values().collect::<Vec<_>>();
+
+        ep_list.sort_by(|a, b| a.ord.partial_cmp(&b.ord).unwrap());
+        ep_list = apply_range(ep_list, &options.range)?;
+        if ep_list.is_empty() {
+            return Err(Error::NothingToExport);
+        }
+        let ep_list = if options.split_episodes {
+            ep_list.iter().map(|ep| Item::Single(ep)).collect()
+        } else if options.grouping > 0 {
+            make_groups(ep_list, options.grouping)
+        } else {
+            vec![Item::Group(ep_list)]
+        };
+
+        let out_dir = match &options.export_dir {
+            Some(dir) => dir.to_path_buf(),
+            None => PathBuf::from(&config.default_download_dir),
+        };
+        let out_dir = out_dir.join(fix_episode_title(&comic_cache.title));
+        if !out_dir.exists() || !out_dir.is_dir() {
+            std::fs::create_dir_all(&out_dir)?;
+        }
+
+        let cover_path = Path::new(&config.cache_dir)
+            .join(format!("{}", id))
+            .join("cover.jpg");
+        let cover = || {
+            if cover_path.is_file() {
+                std::fs::read(&cover_path).ok()
+            } else {
+                None
+            }
+        };
+        let format = match options.format {
+            Format::Pdf => exports::PDF {}.into(),
+            Format::Epub => exports::Epub { cover: cover() }.into(),
+            Format::VolZip => exports::Vol {}.into(),
+            Format::Html => exports::Html { cover: cover() }.into(),
+            Format::Zip => exports::Zip {}.into(),
+        };
+        exports::export(
+            &comic_cache.title,
+            ep_list,
+            &config,
+            &out_dir,
+            &format,
+            events,
+            cancel,
+            journal,
+        )?;
+        Ok(out_dir)
+    }
+
+    /// 缓存中未完成的任务，按创建时间排列
+    pub fn journals(&self) -> Vec<Journal> {
+        Journal::list(&self.config)
+    }
+
+    /// 根据ID查找未完成的任务
+    pub fn journal(&self, id: u64) -> Result<Journal> {
+        self.journals()
+            .into_iter()
+            .find(|journal| journal.id == id)
+            .ok_or(Error::UnknownJob(id))
+    }
+
+    /// 放弃一个未完成的任务，已经下载或导出的文件会保留
+    pub fn discard(&self, id: u64) -> Result<()> {
+        self.journal(id)?.remove()
+    }
+
+    /// 启动本地漫画库服务器，`opds_only` 时只提供 OPDS 目录
+    ///
+    /// 监听成功后返回服务器的 future，需要 `await` 才会开始处理请求
+    pub fn serve(
+        &self,
+        addr: SocketAddr,
+        opds_only: bool,
+    ) -> Result<impl Future<Output = Result<()>>> {
+        store::migrate(&self.config)?;
+        server::serve(self.clone(), addr, opds_only)
+    }
+}
+
+/// 返回校验失败的图片，并将其从缓存中删除
+fn check_episode(episode: &EpisodeCache) -> Result<Vec<CorruptedPage>> {
+    let mut corrupted = Vec::new();
+    // 只有原图的文件名是 sha1，缩放或转换后的图片只在下载时校验 md5，旧版本按顺序导入的图片无法校验
+    if !episode.verifiable() {
+        return Ok(corrupted);
+    }
+    for (index, path) in episode.get_paths().iter().enumerate() {
+        if !path.is_file() {
+            continue;
+        }
+        let expected = path.file_stem().unwrap().to_str().unwrap().to_string();
+        let actual = format!("{:x}", create_hash(path, Sha1::default()).as_ref());
+        if expected != actual {
+            std::fs::remove_file(path)?;
+            corrupted.push(CorruptedPage {
+                index,
+                expected,
+                actual,
+            });
+        }
+    }
+    Ok(corrupted)
+}
+
+/// 下载某一话的任务
+struct Task<'a> {
+    session: &'a Session,
+    variant: Variant,
+    ep: &'a EpisodeInfo,
+    ep_root: &'a Path,
+    events: &'a Events,
+    tracker: &'a Tracker,
+    cancel: &'a CancelToken,
+    journal: &'a Mutex<Journal>,
+}
+
+impl Task<'_> {
+    /// 下载这一话中所有未下载的图片，`planned` 记录是否已经计入总进度
+    async fn run(&self, ep_cache: Option<EpisodeCache>, planned: &mut bool) -> Result<()> {
+        let Task {
+            session,
+            variant,
+            ep,
+            ep_root,
+            events,
+            tracker,
+            cancel,
+            journal,
+        } = self;
+        // 获取某个章节的图片索引
+        let mut replaced = Vec::new();
+        let mut ep_cache = if let Some(mut ep_cache) = ep_cache {
+            // 切换尺寸或格式时先不写入，下载完成后再记录，中途取消不影响已有的缓存
+            if ep_cache.variant != *variant {
+                replaced = ep_cache.blob_names();
+                ep_cache.imported.clear();
+            }
+            ep_cache.variant = *variant;
+            ep_cache
+        } else {
+            let indexes = network::get_episode_images(session, ep.id).await?;
+            let ep_cache = EpisodeCache {
+                id: ep.id,
+                short_title: ep.short_title.to_owned(),
+                title: ep.title.to_owned(),
+                files: vec![],
+                paths: indexes.paths,
+                host: indexes.host,
+                ord: ep.ord,
+                root_dir: ep_root.to_path_buf(),
+                variant: *variant,
+                exported: None,
+                store: store::dir_of(ep_root),
+                unverified: false,
+                imported: BTreeMap::new(),
+            };
+            ep_cache.sync(ep_root)?;
+            ep_cache
+        };
+
+        let not_downloaded = ep_cache.not_downloaded();
+        // 按顺序导入的图片缺失或损坏时下载原图
+        for page in &not_downloaded {
+            let name = ep_cache.upstream_name(page);
+            ep_cache.imported.remove(&name);
+        }
+        if !*planned {
+            tracker.add(not_downloaded.len());
+            *planned = true;
+        }
+        events.send(Event::EpisodeStarted {
+            episode: ep.id,
+            pages: not_downloaded.len(),
+        });
+        let mut failed = Vec::new();
+
+        let batch = session.config().token_batch();
+        'pages: for (index, chunk) in not_downloaded.chunks(batch).enumerate() {
+            if cancel.is_cancelled() {
+                failed.extend_from_slice(&not_downloaded[index * batch..]);
+                break;
+            }
+            // 临近下载时才获取这一批的链接，避免下载较慢时后面的链接过期
+            let urls = chunk.iter().map(|page| variant.apply(page)).collect();
+            let urls = network::get_image_tokens(session, urls).await?;
+            if urls.len() != chunk.len() {
+                return Err(Error::Api(format!(
+                    "请求了 {} 个图片链接，服务器只返回了 {} 个",
+                    chunk.len(),
+                    urls.len()
+                )));
+            }
+            for (i, (page, url)) in chunk.iter().zip(urls).enumerate() {
+                if cancel.is_cancelled() {
+                    // 正在下载的图片已经完成，记录剩下的图片
+                    failed.extend_from_slice(&not_downloaded[index * batch + i..]);
+                    break 'pages;
+                }
+                let path = ep_cache.blob_path(page);
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                let result = match network::download(session, url, &path).await {
+                    // 链接过期，重新获取一次
+                    Err(DownloadError::Forbidden) => {
+                        match network::get_image_tokens(session, vec![variant.apply(page)]).await?.pop() {
+                            Some(url) => network::download(session, url, &path).await,
+                            None => Err(DownloadError::Forbidden),
+                        }
+                    }
+                    result => result,
+                };
+                match result {
+                    Ok(bytes) => {
+                        events.send(Event::PageDownloaded {
+                            episode: ep.id,
+                            bytes,
+                        });
+                        events.send(tracker.advance());
+                    }
+                    Err(_) => failed.push(page.clone()),
+                }
+            }
+        }
+        // 取消时所有图片已经下载完成则视为完成
+        if cancel.is_cancelled() && !failed.is_empty() {
+            return Err(Error::Cancelled(journal.lock().unwrap().id));
+        }
+        if failed.is_empty() {
+            ep_cache.sync(ep_root)?;
+            // 其他章节没有用到的旧尺寸或格式的图片
+            if !replaced.is_empty() {
+                store::release(session.config(), &replaced)?;
+            }
+            journal.lock().unwrap().finish_episode(ep.id)
+        } else {
+            let count = failed.len();
+            Err(Error::Api(format!("{} 张图片下载失败", count)))
+        }
+    }
+}
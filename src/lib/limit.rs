@@ -0,0 +1,95 @@
+//! 下载限速
+//!
+//! 所有下载共用一个令牌桶，并发下载的总速度不会超过限速
+
+use crate::error::{Error, Result};
+use chrono::NaiveTime;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// 解析限速，支持 `K` `M` `G` 后缀 (1024进制)，0 表示不限速
+pub fn parse_rate(rate: &str) -> Result<u64> {
+    let rate = rate.trim();
+    let number = rate.to_uppercase();
+    parse_bytes(number.trim_end_matches("/S")).ok_or_else(|| Error::InvalidRate(rate.to_string()))
+}
+
+/// 解析文件大小，如 `10G` `500M`
+pub fn parse_size(size: &str) -> Result<u64> {
+    parse_bytes(size.trim()).ok_or_else(|| Error::InvalidSize(size.trim().to_string()))
+}
+
+/// 带有 `K` `M` `G` 后缀 (1024进制) 的字节数
+fn parse_bytes(bytes: &str) -> Option<u64> {
+    let upper = bytes.to_uppercase();
+    let number = upper.trim_end_matches('B');
+    let (number, unit) = match number.chars().last() {
+        Some('K') => (&number[..number.len() - 1], 1024.),
+        Some('M') => (&number[..number.len() - 1], 1024. * 1024.),
+        Some('G') => (&number[..number.len() - 1], 1024. * 1024. * 1024.),
+        _ => (number, 1.),
+    };
+    let number = number.trim().parse::<f64>().ok()?;
+    if number < 0. {
+        return None;
+    }
+    Some((number * unit) as u64)
+}
+
+/// 解析 `HH:MM` 格式的时间
+pub(crate) fn parse_time(time: &str) -> Result<NaiveTime> {
+    NaiveTime::parse_from_str(time.trim(), "%H:%M")
+        .map_err(|_| Error::Config(format!("无效的时间：{}，应为 HH:MM 格式", time)))
+}
+
+/// `from` 和 `to` 之间的时间段是否包含 `now`，`from` 晚于 `to` 时跨过午夜
+pub(crate) fn contains(from: NaiveTime, to: NaiveTime, now: NaiveTime) -> bool {
+    if from <= to {
+        from <= now && now < to
+    } else {
+        now >= from || now < to
+    }
+}
+
+/// 令牌桶，克隆出的副本共享同一个桶
+#[derive(Clone, Default)]
+pub(crate) struct RateLimiter {
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+#[derive(Default)]
+struct Bucket {
+    /// 可以立即下载的字节数，为负时需要等待
+    available: f64,
+    last: Option<Instant>,
+}
+
+impl RateLimiter {
+    /// 下载了 `bytes` 字节后调用，超出限速时等待相应的时间
+    pub async fn acquire(&self, rate: Option<u64>, bytes: usize) {
+        let rate = match rate {
+            Some(rate) if rate > 0 => rate as f64,
+            _ => return,
+        };
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let elapsed = bucket
+                .last
+                .map(|last| now.duration_since(last).as_secs_f64())
+                .unwrap_or(0.);
+            // 最多积累一秒的额度，避免空闲后瞬间超速
+            bucket.available = (bucket.available + elapsed * rate).min(rate);
+            bucket.available -= bytes as f64;
+            bucket.last = Some(now);
+            if bucket.available < 0. {
+                Duration::from_secs_f64(-bucket.available / rate)
+            } else {
+                Duration::ZERO
+            }
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
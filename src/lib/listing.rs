@@ -0,0 +1,227 @@
+//! 列出缓存中的漫画
+//!
+//! 每一话的状态来自本地缓存和保存的漫画信息，可以按状态过滤、按标题、ID、更新时间或大小排序
+
+use crate::cache::{ComicCache, EpisodeCache};
+use crate::config::Config;
+use crate::get_dir_size;
+use crate::remote::RemoteInfo;
+use crate::store;
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use std::str::FromStr;
+
+/// 章节的状态
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EpisodeState {
+    /// 所有图片都已下载
+    Downloaded,
+    /// 已经缓存，但还缺少图片
+    Partial,
+    /// 已解锁但还没有缓存
+    Missing,
+    /// 未解锁且没有缓存
+    Locked,
+}
+
+impl FromStr for EpisodeState {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "downloaded" => Ok(EpisodeState::Downloaded),
+            "partial" => Ok(EpisodeState::Partial),
+            "missing" => Ok(EpisodeState::Missing),
+            "locked" => Ok(EpisodeState::Locked),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Display for EpisodeState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            EpisodeState::Downloaded => "已下载",
+            EpisodeState::Partial => "部分下载",
+            EpisodeState::Missing => "未下载",
+            EpisodeState::Locked => "锁定",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// 排序方式
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ListSort {
+    /// 按漫画ID从小到大
+    #[default]
+    Id,
+    /// 按标题
+    Title,
+    /// 最近下载或导出的排在前面
+    Updated,
+    /// 占用空间大的排在前面
+    Size,
+}
+
+impl FromStr for ListSort {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "id" => Ok(ListSort::Id),
+            "title" => Ok(ListSort::Title),
+            "updated" => Ok(ListSort::Updated),
+            "size" => Ok(ListSort::Size),
+            _ => Err(()),
+        }
+    }
+}
+
+/// 列出缓存的参数
+#[derive(Clone, Debug, Default)]
+pub struct ListOptions {
+    /// 只列出这部漫画
+    pub id: Option<u32>,
+    /// 只列出这些状态的章节，为空时列出全部
+    pub states: Vec<EpisodeState>,
+    pub sort: ListSort,
+    /// 获取最新的漫画信息，与缓存比较
+    pub remote: bool,
+    /// 获取漫画信息时不访问网络，只使用保存的信息
+    pub offline: bool,
+}
+
+/// 缓存中的一部漫画
+#[derive(Clone, Debug)]
+pub struct ComicListing {
+    pub id: u32,
+    pub title: String,
+    /// 过滤后的章节，按顺序排列
+    pub episodes: Vec<EpisodeListing>,
+    /// 占用的空间 (字节)，这部漫画中多话共用的图片只计一次
+    pub size: u64,
+    /// 已下载的图片数量
+    pub pages: usize,
+    /// 已缓存章节的图片总数
+    pub total_pages: usize,
+    /// 最近下载或导出的时间 (unix 时间戳)
+    pub updated: i64,
+    /// 漫画信息，没有获取过时为空
+    pub remote: Option<RemoteInfo>,
+    /// 比缓存中最新的一话更新的章节数量，没有漫画信息时为空
+    pub new_episodes: Option<usize>,
+}
+
+/// 漫画中的一话
+#[derive(Clone, Debug)]
+pub struct EpisodeListing {
+    pub id: u32,
+    pub ord: f64,
+    pub short_title: String,
+    pub title: String,
+    pub state: EpisodeState,
+    /// 已下载的图片数量，没有缓存时为0
+    pub pages: usize,
+    /// 图片总数，没有缓存时为0
+    pub total_pages: usize,
+}
+
+/// 汇总一部漫画的缓存，`remote` 中有而缓存中没有的章节记为未下载或锁定
+pub(crate) fn comic_listing(config: &Config, comic: &ComicCache, remote: Option<RemoteInfo>) -> ComicListing {
+    let mut episodes = comic.episodes.values().map(episode_listing).collect::<Vec<_>>();
+    if let Some(remote) = &remote {
+        for ep in remote.comic.ep_list.iter().filter(|ep| comic.get_episode(ep.id).is_none()) {
+            episodes.push(EpisodeListing {
+                id: ep.id,
+                ord: ep.ord,
+                short_title: ep.short_title.clone(),
+                title: ep.title.clone(),
+                state: if ep.is_locked {
+                    EpisodeState::Locked
+                } else {
+                    EpisodeState::Missing
+                },
+                pages: 0,
+                total_pages: 0,
+            });
+        }
+    }
+    episodes.sort_by(|a, b| a.ord.partial_cmp(&b.ord).unwrap());
+    let latest = comic
+        .episodes
+        .values()
+        .map(|episode| episode.ord)
+        .fold(f64::MIN, f64::max);
+    let new_episodes = remote
+        .as_ref()
+        .map(|remote| remote.comic.ep_list.iter().filter(|ep| ep.ord > latest).count());
+
+    let blobs = comic
+        .episodes
+        .values()
+        .flat_map(|episode| {
+            episode
+                .blob_names()
+                .into_iter()
+                .map(move |name| (episode.store.clone(), name))
+        })
+        .collect::<HashSet<_>>();
+    let size = get_dir_size(Path::new(&config.cache_dir).join(comic.id.to_string()))
+        + blobs
+            .iter()
+            .map(|(store, name)| store::blob_size(store, name))
+            .sum::<u64>();
+    ComicListing {
+        id: comic.id,
+        title: comic.title.clone(),
+        pages: episodes.iter().map(|episode| episode.pages).sum(),
+        total_pages: episodes.iter().map(|episode| episode.total_pages).sum(),
+        episodes,
+        size,
+        updated: comic
+            .episodes
+            .values()
+            .map(EpisodeCache::last_used)
+            .max()
+            .unwrap_or(0),
+        remote,
+        new_episodes,
+    }
+}
+
+fn episode_listing(episode: &EpisodeCache) -> EpisodeListing {
+    let total_pages = episode.paths.len();
+    let pages = total_pages - episode.not_downloaded_uncheck().len();
+    EpisodeListing {
+        id: episode.id,
+        ord: episode.ord,
+        short_title: episode.short_title.clone(),
+        title: episode.title.clone(),
+        state: if pages == total_pages {
+            EpisodeState::Downloaded
+        } else {
+            EpisodeState::Partial
+        },
+        pages,
+        total_pages,
+    }
+}
+
+/// 按状态过滤章节并排序，过滤后没有章节的漫画不会列出
+pub(crate) fn filter_and_sort(mut comics: Vec<ComicListing>, options: &ListOptions) -> Vec<ComicListing> {
+    if !options.states.is_empty() {
+        for comic in comics.iter_mut() {
+            comic.episodes.retain(|episode| options.states.contains(&episode.state));
+        }
+        comics.retain(|comic| !comic.episodes.is_empty());
+    }
+    match options.sort {
+        ListSort::Id => comics.sort_by_key(|comic| comic.id),
+        ListSort::Title => comics.sort_by(|a, b| a.title.cmp(&b.title)),
+        ListSort::Updated => comics.sort_by_key(|comic| std::cmp::Reverse(comic.updated)),
+        ListSort::Size => comics.sort_by_key(|comic| std::cmp::Reverse(comic.size)),
+    }
+    comics
+}
@@ -0,0 +1,166 @@
+//! 缓存目录的进程间锁
+//!
+//! 锁文件保存在缓存目录下的 `.locks` 中：`cache.lock` 保护整个缓存目录，
+//! `<漫画ID>.lock` 保护一部漫画。读取缓存时获取共享锁，下载或校验一部漫画时获取这部漫画的独占锁，
+//! 清空或清理缓存时获取整个缓存目录的独占锁。锁是建议性的，只对 bcdown 自己有效
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// 锁文件目录的名称，以 . 开头，加载缓存时会跳过
+pub(crate) const LOCK_DIR: &str = ".locks";
+
+/// 等待锁时的重试间隔
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// 持有的锁，离开作用域时释放
+#[derive(Debug)]
+pub(crate) struct CacheLock {
+    _files: Vec<File>,
+}
+
+/// 要获取的一个锁
+struct Request {
+    path: PathBuf,
+    exclusive: bool,
+    /// 被占用时提示的内容
+    what: String,
+}
+
+/// 整个缓存目录的共享锁，用于只读取缓存的操作
+pub(crate) fn shared(config: &Config) -> Result<CacheLock> {
+    wait(config, &[global(config, false)])
+}
+
+/// 整个缓存目录的独占锁，用于会删除多部漫画的操作
+pub(crate) fn exclusive(config: &Config) -> Result<CacheLock> {
+    wait(config, &[global(config, true)])
+}
+
+/// 一部漫画的锁，同时持有整个缓存目录的共享锁
+pub(crate) async fn comic(config: &Config, id: u32, exclusive: bool) -> Result<CacheLock> {
+    let requests = comic_requests(config, id, exclusive);
+    let deadline = Instant::now() + config.lock_timeout();
+    loop {
+        if let Some(lock) = try_acquire(&requests, Instant::now() >= deadline)? {
+            return Ok(lock);
+        }
+        tokio::time::sleep(RETRY_INTERVAL).await;
+    }
+}
+
+/// 一部漫画的锁，用于不在异步任务中的操作
+pub(crate) fn comic_blocking(config: &Config, id: u32, exclusive: bool) -> Result<CacheLock> {
+    wait(config, &comic_requests(config, id, exclusive))
+}
+
+fn global(config: &Config, exclusive: bool) -> Request {
+    Request {
+        path: Path::new(&config.cache_dir).join(LOCK_DIR).join("cache.lock"),
+        exclusive,
+        what: "缓存目录".to_string(),
+    }
+}
+
+fn comic_requests(config: &Config, id: u32, exclusive: bool) -> [Request; 2] {
+    [
+        global(config, false),
+        Request {
+            path: Path::new(&config.cache_dir)
+                .join(LOCK_DIR)
+                .join(format!("{}.lock", id)),
+            exclusive,
+            what: format!("漫画 {} 的缓存", id),
+        },
+    ]
+}
+
+fn wait(config: &Config, requests: &[Request]) -> Result<CacheLock> {
+    let deadline = Instant::now() + config.lock_timeout();
+    loop {
+        if let Some(lock) = try_acquire(requests, Instant::now() >= deadline)? {
+            return Ok(lock);
+        }
+        std::thread::sleep(RETRY_INTERVAL);
+    }
+}
+
+/// 依次尝试获取所有的锁，有一个被占用时释放已经获取的锁，
+/// 避免在等待时挡住其他进程。`last` 时被占用直接返回错误
+fn try_acquire(requests: &[Request], last: bool) -> Result<Option<CacheLock>> {
+    let mut files = Vec::new();
+    for request in requests {
+        if let Some(parent) = request.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&request.path)?;
+        match sys::try_lock(&file, request.exclusive) {
+            Ok(()) => files.push(file),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                if last {
+                    return Err(Error::Locked(request.what.clone()));
+                }
+                return Ok(None);
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(Some(CacheLock { _files: files }))
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::fs::File;
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+
+    pub fn try_lock(file: &File, exclusive: bool) -> io::Result<()> {
+        let operation = if exclusive { libc::LOCK_EX } else { libc::LOCK_SH };
+        // 文件关闭时锁会自动释放
+        if unsafe { libc::flock(file.as_raw_fd(), operation | libc::LOCK_NB) } == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+}
+
+#[cfg(windows)]
+mod sys {
+    use std::fs::File;
+    use std::io;
+    use std::os::windows::io::AsRawHandle;
+    use winapi::um::fileapi::LockFileEx;
+    use winapi::um::minwinbase::{LOCKFILE_EXCLUSIVE_LOCK, LOCKFILE_FAIL_IMMEDIATELY, OVERLAPPED};
+    use winapi::shared::winerror::ERROR_LOCK_VIOLATION;
+
+    pub fn try_lock(file: &File, exclusive: bool) -> io::Result<()> {
+        let mut flags = LOCKFILE_FAIL_IMMEDIATELY;
+        if exclusive {
+            flags |= LOCKFILE_EXCLUSIVE_LOCK;
+        }
+        let mut overlapped: OVERLAPPED = unsafe { std::mem::zeroed() };
+        // 文件关闭时锁会自动释放
+        let ok = unsafe {
+            LockFileEx(file.as_raw_handle() as _, flags, 0, !0, !0, &mut overlapped)
+        };
+        if ok != 0 {
+            return Ok(());
+        }
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() == Some(ERROR_LOCK_VIOLATION as i32) {
+            Err(io::ErrorKind::WouldBlock.into())
+        } else {
+            Err(e)
+        }
+    }
+}
@@ -0,0 +1,166 @@
+use crate::event::Event;
+use crate::network::EpisodeInfo;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// 计算速度时使用的时间窗口
+const WINDOW: Duration = Duration::from_secs(5);
+
+/// 根据下载事件统计速度、剩余时间和重试次数
+pub struct Metrics {
+    started: Instant,
+    /// 时间窗口内下载完成的图片和字节数
+    window: VecDeque<(Instant, usize)>,
+    /// 每一话还没有下载的图片数量
+    remaining: HashMap<u32, usize>,
+    /// 每一话失败的次数
+    retries: HashMap<u32, usize>,
+    episodes: Vec<EpisodeInfo>,
+    locked: Vec<EpisodeInfo>,
+    bytes: u64,
+    pages: usize,
+    finished: usize,
+}
+
+/// 某一时刻的下载速度
+#[derive(Clone, Copy, Debug)]
+pub struct Rate {
+    pub bytes_per_second: f64,
+    pub pages_per_second: f64,
+    /// 剩余的图片数量，只包含已经开始下载的章节
+    pub remaining_pages: usize,
+    /// 按当前速度估算的剩余时间，还没有速度时为空
+    pub eta: Option<Duration>,
+}
+
+/// 下载结束后的统计
+#[derive(Clone, Debug)]
+pub struct Summary {
+    pub bytes: u64,
+    pub pages: usize,
+    pub duration: Duration,
+    /// 下载完成的章节数量
+    pub finished: usize,
+    /// 计划下载的章节数量
+    pub planned: usize,
+    /// 失败过的章节及其失败次数
+    pub retries: Vec<(EpisodeInfo, usize)>,
+    /// 范围内因为未解锁而跳过的章节
+    pub locked: Vec<EpisodeInfo>,
+}
+
+impl Summary {
+    /// 全部失败次数
+    pub fn failures(&self) -> usize {
+        self.retries.iter().map(|(_, count)| count).sum()
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            started: Instant::now(),
+            window: VecDeque::new(),
+            remaining: HashMap::new(),
+            retries: HashMap::new(),
+            episodes: Vec::new(),
+            locked: Vec::new(),
+            bytes: 0,
+            pages: 0,
+            finished: 0,
+        }
+    }
+
+    /// 记录一个事件
+    pub fn record(&mut self, event: &Event) {
+        match event {
+            Event::FetchPlanned { episodes, locked } => {
+                self.episodes = episodes.clone();
+                self.locked = locked.clone();
+            }
+            // 重试时图片数量会重新计算
+            Event::EpisodeStarted { episode, pages } => {
+                self.remaining.insert(*episode, *pages);
+            }
+            Event::PageDownloaded { episode, bytes } => {
+                let now = Instant::now();
+                self.window.push_back((now, *bytes));
+                self.trim(now);
+                self.bytes += *bytes as u64;
+                self.pages += 1;
+                if let Some(remaining) = self.remaining.get_mut(episode) {
+                    *remaining = remaining.saturating_sub(1);
+                }
+            }
+            Event::EpisodeFinished { episode } => {
+                self.remaining.remove(episode);
+                self.finished += 1;
+            }
+            Event::EpisodeFailed { episode, .. } => {
+                *self.retries.entry(*episode).or_default() += 1;
+            }
+            _ => {}
+        }
+    }
+
+    /// 丢弃时间窗口以外的记录
+    fn trim(&mut self, now: Instant) {
+        while let Some((time, _)) = self.window.front() {
+            if now.duration_since(*time) > WINDOW {
+                self.window.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// 最近一段时间内的速度
+    pub fn rate(&mut self) -> Rate {
+        let now = Instant::now();
+        self.trim(now);
+        // 刚开始下载时窗口还没有填满，按实际经过的时间计算
+        let span = now.duration_since(self.started).min(WINDOW).as_secs_f64();
+        let (bytes_per_second, pages_per_second) = if span > 0. {
+            let bytes = self.window.iter().map(|(_, bytes)| bytes).sum::<usize>();
+            (bytes as f64 / span, self.window.len() as f64 / span)
+        } else {
+            (0., 0.)
+        };
+        let remaining_pages = self.remaining.values().sum();
+        let eta = if pages_per_second > 0. {
+            Some(Duration::from_secs_f64(remaining_pages as f64 / pages_per_second))
+        } else {
+            None
+        };
+        Rate {
+            bytes_per_second,
+            pages_per_second,
+            remaining_pages,
+            eta,
+        }
+    }
+
+    pub fn summary(&self) -> Summary {
+        let mut retries = self
+            .episodes
+            .iter()
+            .filter_map(|ep| Some((ep.clone(), *self.retries.get(&ep.id)?)))
+            .collect::<Vec<_>>();
+        retries.sort_by(|a, b| a.0.ord.partial_cmp(&b.0.ord).unwrap());
+        Summary {
+            bytes: self.bytes,
+            pages: self.pages,
+            duration: self.started.elapsed(),
+            finished: self.finished,
+            planned: self.episodes.len(),
+            retries,
+            locked: self.locked.clone(),
+        }
+    }
+}
@@ -1,116 +1,118 @@
 use super::config::Config;
+use super::error::{Error, Result};
 use printpdf::image_crate::EncodableLayout;
-use serde::Deserialize;
+use reqwest::{Response, StatusCode};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::path::Path;
-use std::process::exit;
+use std::time::Duration;
 use tokio::fs::File;
 use tokio::io::AsyncWriteExt;
 
+/// 网络会话，克隆出的副本共用同一个客户端
+///
+/// 所有请求共用连接池，同一主机的请求会复用已经建立的连接，服务器支持时使用 HTTP/2
+#[derive(Clone)]
+pub struct Session {
+    client: reqwest::Client,
+    config: Config,
+    read_timeout: Duration,
+}
+
+impl Session {
+    /// 根据配置创建客户端，代理和证书设置有误时返回错误
+    pub fn new(config: &Config) -> Result<Session> {
+        Ok(Session {
+            client: config.get_client()?,
+            config: config.clone(),
+            read_timeout: config.read_timeout(),
+        })
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+}
+
 pub struct UserInfo {
     pub name: String,
     // uname
     pub coin: i64, // remain_gold
 }
 
-pub async fn get_user_info(config: &Config) -> Option<UserInfo> {
-    let mut log = paris::Logger::new();
+/// 从响应中取出 `code` 字段
+fn get_code(value: &serde_json::Value) -> Result<i64> {
+    value
+        .get("code")
+        .and_then(|code| code.as_i64())
+        .ok_or_else(|| Error::Api(format!("服务器返回了无法解析的数据：{:?}", value)))
+}
+
+/// 获取登录用户的信息，未登录或登录已过期时返回 `None`
+pub async fn get_user_info(session: &Session) -> Result<Option<UserInfo>> {
     let url = "https://api.bilibili.com/x/web-interface/nav";
     let wallet_url =
         "https://manga.bilibili.com/twirp/user.v1.User/GetWallet?device=pc&platform=web";
-    let client = config.get_client();
-    if let Ok(resp) = client.get(url).send().await {
-        let value: serde_json::Value = resp.json().await.unwrap();
-        let code = if let Some(code) = value.get("code") {
-            if let serde_json::Value::Number(code) = code {
-                code.as_i64().unwrap()
-            } else {
-                log.error("服务端返回的 \"code\" 字段不是数字");
-                println!("调试信息：{:?}", value);
-                exit(1);
-            }
-        } else {
-            log.error("服务器返回了无法解析的数据");
-            println!("调试信息：{:?}", value);
-            exit(1);
-        };
-        if code == -101 {
-            log.warn("未登录或登录已过期");
-            None
-        } else if code == 0 {
+    let client = &session.client;
+    let value: serde_json::Value = client
+        .get(url)
+        .timeout(session.read_timeout)
+        .send()
+        .await?
+        .json()
+        .await?;
+    match get_code(&value)? {
+        -101 => Ok(None),
+        0 => {
             let username = value
                 .get("data")
-                .unwrap()
-                .get("uname")
-                .unwrap()
-                .as_str()
-                .unwrap();
+                .and_then(|data| data.get("uname"))
+                .and_then(|name| name.as_str())
+                .ok_or_else(|| Error::Api(format!("服务器返回了无法解析的数据：{:?}", value)))?;
             // 继续查询wallet
-            let resp = client.post(wallet_url).send().await.unwrap();
-            let value: serde_json::Value = resp.json().await.unwrap();
-            if let Some(data) = value.get("data") {
-                if let Some(coin) = data.get("remain_gold") {
-                    return Some(UserInfo {
-                        coin: coin.as_i64().unwrap(),
-                        name: username.to_string(),
-                    });
-                }
+            let value: serde_json::Value = client
+                .post(wallet_url)
+                .timeout(session.read_timeout)
+                .send()
+                .await?
+                .json()
+                .await?;
+            match value
+                .get("data")
+                .and_then(|data| data.get("remain_gold"))
+                .and_then(|coin| coin.as_i64())
+            {
+                Some(coin) => Ok(Some(UserInfo {
+                    coin,
+                    name: username.to_string(),
+                })),
+                None => Err(Error::Api(format!("服务器返回了无法解析的数据：{:?}", value))),
             }
-            log.error("服务器返回了无法解析的数据");
-            println!("调试信息：{:?}", value);
-            exit(1);
-        } else {
-            log.error("服务器返回了未知错误");
-            println!("调试信息：{:?}", value);
-            exit(1);
         }
-    } else {
-        log.error("无法获取用户信息");
-        exit(1);
+        _ => Err(Error::Api(format!("服务器返回了未知错误：{:?}", value))),
     }
 }
 
-pub async fn get_qr_data(config: &Config) -> (String, String) {
+/// 获取登录二维码的链接和 oauthKey
+pub async fn get_qr_data(session: &Session) -> Result<(String, String)> {
     let url = "https://passport.bilibili.com/qrcode/getLoginUrl";
-    let mut log = paris::Logger::new();
-    log.loading("加载二维码");
-    let client = config.get_client();
-    if let Ok(resp) = client.get(url).send().await {
-        let value: serde_json::Value = resp.json().await.unwrap();
-        if let Some(code) = value.get("code") {
-            let code = code.as_i64().unwrap();
-            if code != 0 {
-                log.done();
-                log.error("获取二维码失败");
-                println!("调试信息：{:?}", value);
-                exit(1);
-            }
-        } else {
-            log.done();
-            log.error("无法解析二维码数据");
-            println!("调试信息：{:?}", value);
-            exit(1);
-        }
-        log.done();
-        let url = value
-            .get("data")
-            .unwrap()
-            .get("url")
-            .unwrap()
-            .as_str()
-            .unwrap();
-        let oauth_key = value
-            .get("data")
-            .unwrap()
-            .get("oauthKey")
-            .unwrap()
-            .as_str()
-            .unwrap();
-        (url.to_string(), oauth_key.to_string())
-    } else {
-        log.done();
-        log.error("无法获取二维码数据 请检查网络");
-        exit(1);
+    let client = &session.client;
+    let value: serde_json::Value = client
+        .get(url)
+        .timeout(session.read_timeout)
+        .send()
+        .await?
+        .json()
+        .await?;
+    if get_code(&value)? != 0 {
+        return Err(Error::Api(format!("获取二维码失败：{:?}", value)));
+    }
+    let data = value.get("data");
+    let url = data.and_then(|data| data.get("url")?.as_str());
+    let oauth_key = data.and_then(|data| data.get("oauthKey")?.as_str());
+    match (url, oauth_key) {
+        (Some(url), Some(oauth_key)) => Ok((url.to_string(), oauth_key.to_string())),
+        _ => Err(Error::Api(format!("无法解析二维码数据：{:?}", value))),
     }
 }
 
@@ -124,46 +126,41 @@ pub enum QRStatus {
     Invalid, // 无效
 }
 
-pub async fn check_qr_status(config: &Config, oauth: String) -> QRStatus {
+pub async fn check_qr_status(session: &Session, oauth: String) -> Result<QRStatus> {
     let url = "https://passport.bilibili.com/qrcode/getLoginInfo";
-    let mut log = paris::Logger::new();
-    let client = config.get_client();
+    let client = &session.client;
     let mut params = HashMap::new();
     params.insert("oauthKey", oauth.clone());
-    if let Ok(resp) = client.post(url).form(&params).send().await {
-        let value: serde_json::Value = resp.json().await.unwrap();
-        let data = value.get("data").unwrap();
-        if data.is_number() {
-            let code = data.as_i64().unwrap();
-            if code == -4 {
-                return QRStatus::NotScan;
-            }
-            if code == -5 {
-                return QRStatus::Scanning;
-            }
-            if code == -2 {
-                return QRStatus::Invalid;
-            }
-        } else {
-            let data = value.get("data").unwrap();
-            // dbg!(resp.cookies());
-            let url = data.get("url").unwrap().as_str().unwrap();
-            let sessdata = url.split("&SESSDATA=").collect::<Vec<&str>>()[1]
-                .split('&')
-                .collect::<Vec<&str>>()[0]
-                .to_string();
-            return QRStatus::Complete(sessdata);
+    let value: serde_json::Value = client
+        .post(url)
+        .form(&params)
+        .timeout(session.read_timeout)
+        .send()
+        .await?
+        .json()
+        .await?;
+    let invalid = || Error::Api(format!("无法获取二维码状态：{:?}", value));
+    let data = value.get("data").ok_or_else(invalid)?;
+    if let Some(code) = data.as_i64() {
+        match code {
+            -4 => Ok(QRStatus::NotScan),
+            -5 => Ok(QRStatus::Scanning),
+            -2 => Ok(QRStatus::Invalid),
+            _ => Err(invalid()),
         }
-        log.error("无法获取二维码状态");
-        println!("调试信息：{:?}", value);
-        exit(1);
     } else {
-        log.error("无法获取二维码状态 请检查网络");
-        exit(1);
+        // dbg!(resp.cookies());
+        let url = data.get("url").and_then(|url| url.as_str()).ok_or_else(invalid)?;
+        let sessdata = url
+            .split("&SESSDATA=")
+            .nth(1)
+            .and_then(|rest| rest.split('&').next())
+            .ok_or_else(invalid)?;
+        Ok(QRStatus::Complete(sessdata.to_string()))
     }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct EpisodeInfo {
     pub short_title: String,
     pub title: String,
@@ -173,13 +170,29 @@ pub struct EpisodeInfo {
     pub ord: f64,
 }
 
-impl crate::lib::HasOrd for EpisodeInfo {
+impl crate::range::Selectable for EpisodeInfo {
     fn ord(&self) -> f64 {
         self.ord
     }
+
+    fn id(&self) -> u32 {
+        self.id
+    }
+
+    fn titles(&self) -> [&str; 2] {
+        [&self.short_title, &self.title]
+    }
+
+    fn is_locked(&self) -> bool {
+        self.is_locked
+    }
+
+    fn is_free(&self) -> Option<bool> {
+        Some(self.is_in_free)
+    }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct ComicInfo {
     pub id: u32,
     pub title: String,
@@ -209,47 +222,42 @@ pub fn fix_episode_title(title: &str) -> String {
     result
 }
 
-pub async fn get_comic_info(config: &Config, comic_id: u32) -> ComicInfo {
-    let mut log = paris::Logger::new();
-    log.loading("获取漫画信息...");
+pub async fn get_comic_info(session: &Session, comic_id: u32) -> Result<ComicInfo> {
     let mut payload = HashMap::new();
     payload.insert("comic_id", comic_id);
-    let client = config.get_client();
+    let client = &session.client;
     let url = "https://manga.bilibili.com/twirp/comic.v1.Comic/ComicDetail?device=pc&platform=web";
 
-    if let Ok(resp) = client.post(url).json(&payload).send().await {
-        log.done();
-
-        let value: serde_json::Value = resp.json().await.unwrap();
-        let data = value.get("data").unwrap();
-        match serde_json::from_value::<ComicInfo>(data.to_owned()) {
-            Ok(mut value) => {
-                // 如果有标题为空的episode，则使用ord作为标题
-                for ep in value.ep_list.iter_mut() {
-                    // 去除空字符
-                    ep.short_title = ep.short_title.trim().to_string();
-                    ep.title = ep.title.trim().to_string();
-                    if ep.title.is_empty() {
-                        ep.title = format!("第{}话", ep.ord);
-                    }
-                    ep.title = fix_episode_title(&ep.title);
-                    // 如果 is_in_free 为 true 则设置 is_locked 为 false
-                    if ep.is_in_free {
-                        ep.is_locked = false;
-                    }
+    let value: serde_json::Value = client
+        .post(url)
+        .json(&payload)
+        .timeout(session.read_timeout)
+        .send()
+        .await?
+        .json()
+        .await?;
+    let data = value
+        .get("data")
+        .ok_or_else(|| Error::Api(format!("无法获取漫画信息：{:?}", value)))?;
+    match serde_json::from_value::<ComicInfo>(data.to_owned()) {
+        Ok(mut value) => {
+            // 如果有标题为空的episode，则使用ord作为标题
+            for ep in value.ep_list.iter_mut() {
+                // 去除空字符
+                ep.short_title = ep.short_title.trim().to_string();
+                ep.title = ep.title.trim().to_string();
+                if ep.title.is_empty() {
+                    ep.title = format!("第{}话", ep.ord);
+                }
+                ep.title = fix_episode_title(&ep.title);
+                // 如果 is_in_free 为 true 则设置 is_locked 为 false
+                if ep.is_in_free {
+                    ep.is_locked = false;
                 }
-                value
-            }
-            Err(e) => {
-                log.error("无法解析服务器响应漫画信息");
-                println!("调试信息：{:?}", e);
-                exit(1);
             }
+            Ok(value)
         }
-    } else {
-        log.done();
-        log.error("无法获取漫画信息 请检查网络");
-        exit(1);
+        Err(e) => Err(Error::Api(format!("无法解析服务器响应漫画信息：{}", e))),
     }
 }
 
@@ -269,53 +277,108 @@ struct ImageIndex {
     host: String,
 }
 
-pub async fn get_episode_images(config: &Config, ep_id: u32) -> Option<EpisodeImages> {
-    let client = config.get_client();
+pub async fn get_episode_images(session: &Session, ep_id: u32) -> Result<EpisodeImages> {
+    let client = &session.client;
     let mut payload = HashMap::new();
     payload.insert("ep_id", ep_id);
     let url =
         "https://manga.bilibili.com/twirp/comic.v1.Comic/GetImageIndex?device=pc&platform=web";
 
-    let resp = client.post(url).json(&payload).send().await.ok()?;
-    let value: serde_json::Value = resp.json().await.unwrap();
-    let data = value.get("data").unwrap();
-    let index: ImageIndex = serde_json::from_value(data.to_owned()).ok()?;
-    Some(EpisodeImages {
+    let value: serde_json::Value = client
+        .post(url)
+        .json(&payload)
+        .timeout(session.read_timeout)
+        .send()
+        .await?
+        .json()
+        .await?;
+    let index: ImageIndex = value
+        .get("data")
+        .and_then(|data| serde_json::from_value(data.to_owned()).ok())
+        .ok_or_else(|| Error::Api(format!("无法获取图片索引：{:?}", value)))?;
+    Ok(EpisodeImages {
         host: index.host,
         paths: index.images.iter().map(|x| x.path.clone()).collect(),
     })
 }
 
-pub async fn get_image_tokens(config: &Config, paths: Vec<String>) -> Option<Vec<String>> {
-    let client = config.get_client();
+pub async fn get_image_tokens(session: &Session, paths: Vec<String>) -> Result<Vec<String>> {
+    let client = &session.client;
     let mut payload = HashMap::new();
     let paths: Vec<String> = paths.iter().map(|x| format!("\"{}\"", x)).collect();
     payload.insert("urls", format!("[{}]", paths.join(",")));
     let url = "https://manga.bilibili.com/twirp/comic.v1.Comic/ImageToken?device=pc&platform=web";
-    let resp = client.post(url).json(&payload).send().await.ok()?;
-    let value: serde_json::Value = resp.json().await.unwrap();
+    let value: serde_json::Value = client
+        .post(url)
+        .json(&payload)
+        .timeout(session.read_timeout)
+        .send()
+        .await?
+        .json()
+        .await?;
+    let invalid = || Error::Api(format!("无法获取图片链接：{:?}", value));
     let mut urls = Vec::new();
-    let data = value.get("data").unwrap().as_array()?;
+    let data = value
+        .get("data")
+        .and_then(|data| data.as_array())
+        .ok_or_else(invalid)?;
     for obj in data {
-        let token = obj.get("token").unwrap().as_str()?;
-        let url = obj.get("url")?.as_str()?;
+        let token = obj.get("token").and_then(|t| t.as_str()).ok_or_else(invalid)?;
+        let url = obj.get("url").and_then(|u| u.as_str()).ok_or_else(invalid)?;
         urls.push(format!("{}?token={}", url, token));
     }
-    Some(urls)
+    Ok(urls)
 }
 
-pub async fn down_to<T: AsRef<Path>>(config: &Config, url: String, path: T) -> Option<usize> {
+/// 下载失败的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DownloadError {
+    /// 服务器返回 403，通常是图片链接中的 token 已经过期
+    Forbidden,
+    /// 网络错误、校验失败或无法写入文件
+    Failed,
+}
+
+pub async fn down_to<T: AsRef<Path>>(session: &Session, url: String, path: T) -> Option<usize> {
+    download(session, url, path).await.ok()
+}
+
+/// 下载到 `path`，返回文件大小，token 过期时返回 [`DownloadError::Forbidden`]
+pub async fn download<T: AsRef<Path>>(
+    session: &Session,
+    url: String,
+    path: T,
+) -> std::result::Result<usize, DownloadError> {
     // if path.as_ref().is_file() {
     //     panic!("重复下载文件: {}", path.as_ref().display());
     // }
-    let client = config.get_client();
-    let resp = client.get(url).send().await.ok()?; // 这里出错是在计划内的，不会强制退出
+    let client = &session.client;
+    // 这里出错是在计划内的，不会强制退出
+    let resp = tokio::time::timeout(session.read_timeout, client.get(url).send())
+        .await
+        .ok()
+        .and_then(|resp| resp.ok())
+        .ok_or(DownloadError::Failed)?;
+    match resp.status() {
+        StatusCode::FORBIDDEN => Err(DownloadError::Forbidden),
+        status if status.is_success() => save(session, resp, path).await.ok_or(DownloadError::Failed),
+        _ => Err(DownloadError::Failed),
+    }
+}
 
+/// 读取响应并校验 md5，通过后写入文件
+async fn save<T: AsRef<Path>>(session: &Session, mut resp: Response, path: T) -> Option<usize> {
+    let timeout = session.read_timeout;
     let header_md5 = resp.headers().get("content-md5").cloned();
-    let bytes = resp.bytes().await.ok()?; // 出现问题也很罕见，有时候会EOF
+    // 分块读取，每一块都受限速控制，超过读取超时没有收到数据则放弃
+    let mut bytes = Vec::new();
+    while let Some(chunk) = tokio::time::timeout(timeout, resp.chunk()).await.ok()?.ok()? { // 出现问题也很罕见，有时候会EOF
+        session.config.throttle(chunk.len()).await;
+        bytes.extend_from_slice(&chunk);
+    }
 
     if let Some(md5) = header_md5 {
-        let md5 = md5.to_str().unwrap().parse::<String>().unwrap();
+        let md5 = md5.to_str().ok()?.to_string();
 
         // 这里的md5是base64编码的 编码的是md5的二进制数组
         let hash = md5::compute(&bytes);
@@ -327,6 +390,6 @@ pub async fn down_to<T: AsRef<Path>>(config: &Config, url: String, path: T) -> O
         }
     }
     let mut file = File::create(&path).await.ok()?;
-    file.write_all(&bytes).await.unwrap();
+    file.write_all(&bytes).await.ok()?;
     Some(bytes.len())
 }
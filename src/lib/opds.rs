@@ -0,0 +1,592 @@
+//! OPDS 目录
+//!
+//! 同时提供 OPDS 1.2 (Atom) 和 OPDS 2.0 (JSON) 两种格式，供 KOReader、Panels 等阅读器浏览本地缓存。
+//! 下载链接在请求时才调用对应的导出格式生成文件，生成的文件会经过当前的设备配置处理
+
+use crate::cache::Cache;
+use crate::cache::{ComicCache, EpisodeCache};
+use crate::config::Config;
+use crate::exports::{
+    encode_href, escape_html, export_item, Epub, ExportFormatEnum, Item, Zip,
+};
+use crate::error::Error;
+use crate::lock;
+use crate::server::{
+    blocking, error_response, file_response, json_response, load_comic, not_found, sorted_episodes,
+};
+use chrono::{DateTime, SecondsFormat, Utc};
+use hyper::{Body, Response, StatusCode};
+use serde_json::json;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use tokio::io::AsyncReadExt;
+
+const NAVIGATION: &str = "application/atom+xml;profile=opds-catalog;kind=navigation";
+const ACQUISITION: &str = "application/atom+xml;profile=opds-catalog;kind=acquisition";
+const OPDS_JSON: &str = "application/opds+json";
+const ACQUISITION_REL: &str = "http://opds-spec.org/acquisition";
+/// 未指定 `size` 参数时每个合集包含的话数
+const DEFAULT_VOLUME_SIZE: usize = 10;
+
+/// 临时文件的序号，避免同时下载时文件名冲突
+static NEXT_TEMP: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Clone, Copy)]
+enum Download {
+    Cbz,
+    Epub,
+}
+
+impl Download {
+    const ALL: [Download; 2] = [Download::Cbz, Download::Epub];
+
+    fn extension(&self) -> &'static str {
+        match self {
+            Download::Cbz => "cbz",
+            Download::Epub => "epub",
+        }
+    }
+
+    fn mime(&self) -> &'static str {
+        match self {
+            Download::Cbz => "application/vnd.comicbook+zip",
+            Download::Epub => "application/epub+zip",
+        }
+    }
+
+    /// 从 `{id}.{ext}` 形式的文件名中解析
+    fn parse(file_name: &str) -> Option<(usize, Download)> {
+        let (stem, extension) = file_name.rsplit_once('.')?;
+        let download = match extension {
+            "cbz" => Download::Cbz,
+            "epub" => Download::Epub,
+            _ => return None,
+        };
+        Some((stem.parse().ok()?, download))
+    }
+}
+
+pub(crate) async fn handle(
+    config: &Config,
+    segments: &[&str],
+    query: Option<&str>,
+) -> Response<Body> {
+    let size = volume_size(query);
+    if let ["comics", id, kind @ ("episodes" | "volumes"), file_name] = segments {
+        return match (id.parse::<u32>(), Download::parse(file_name)) {
+            (Ok(id), Some((key, download))) => {
+                download_response(config, id, *kind == "episodes", key, size, download).await
+            }
+            _ => not_found(),
+        };
+    }
+    // 目录需要读取缓存，在阻塞线程中生成
+    let config = config.clone();
+    let segments = segments.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+    blocking(move || {
+        let segments = segments.iter().map(String::as_str).collect::<Vec<_>>();
+        feed(&config, &segments, size)
+    })
+    .await
+}
+
+fn feed(config: &Config, segments: &[&str], size: usize) -> Response<Body> {
+    match segments {
+        [] => root_feed(config),
+        ["comics", id] => match load_comic(config, id) {
+            Some(comic) => comic_feed(config, &comic, size),
+            None => not_found(),
+        },
+        ["comics", id, "cover"] => match id.parse::<u32>() {
+            Ok(id) => file_response(&cover_path(config, id)),
+            Err(_) => not_found(),
+        },
+        ["comics", id, kind @ ("episodes" | "volumes")] => match load_comic(config, id) {
+            Some(comic) => acquisition_feed(config, &comic, kind, size),
+            None => not_found(),
+        },
+        ["v2"] => root_feed_v2(config),
+        ["v2", "comics", id] => match load_comic(config, id) {
+            Some(comic) => comic_feed_v2(config, &comic, size),
+            None => not_found(),
+        },
+        _ => not_found(),
+    }
+}
+
+fn volume_size(query: Option<&str>) -> usize {
+    query
+        .unwrap_or("")
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("size=")?.parse().ok())
+        .filter(|size| *size > 0)
+        .unwrap_or(DEFAULT_VOLUME_SIZE)
+}
+
+fn cover_path(config: &Config, id: u32) -> PathBuf {
+    Path::new(&config.cache_dir)
+        .join(id.to_string())
+        .join("cover.jpg")
+}
+
+/// 文件的修改时间，用作条目的更新时间
+fn updated(path: &Path) -> String {
+    let time = std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .map(DateTime::<Utc>::from)
+        .unwrap_or_else(|_| Utc::now());
+    time.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+fn comic_updated(config: &Config, comic: &ComicCache) -> String {
+    updated(
+        &Path::new(&config.cache_dir)
+            .join(comic.id.to_string())
+            .join("meta.toml"),
+    )
+}
+
+/// 只有下载完整的章节才会出现在目录中
+fn downloaded_episodes(comic: &ComicCache) -> Vec<&EpisodeCache> {
+    sorted_episodes(comic)
+        .into_iter()
+        .filter(|ep| ep.not_downloaded_uncheck().is_empty())
+        .collect()
+}
+
+/// 按照 `size` 将章节分为合集
+fn volumes(episodes: Vec<&EpisodeCache>, size: usize) -> Vec<Vec<&EpisodeCache>> {
+    episodes.chunks(size).map(|chunk| chunk.to_vec()).collect()
+}
+
+fn volume_title(volume: &[&EpisodeCache]) -> String {
+    let first = volume.first().unwrap();
+    let last = volume.last().unwrap();
+    if volume.len() == 1 {
+        format!("{} {}", first.short_title, first.title)
+    } else {
+        format!("{} - {}", first.short_title, last.short_title)
+    }
+}
+
+fn atom_response(kind: &str, body: String) -> Response<Body> {
+    Response::builder()
+        .header("Content-Type", format!("{};charset=utf-8", kind))
+        .body(Body::from(body))
+        .unwrap()
+}
+
+fn atom_feed(
+    id: &str,
+    title: &str,
+    updated: &str,
+    self_href: &str,
+    kind: &str,
+    entries: Vec<String>,
+) -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom" xmlns:opds="http://opds-spec.org/2010/catalog">
+  <id>{}</id>
+  <title>{}</title>
+  <updated>{}</updated>
+  <author><name>bcdown</name></author>
+  <link rel="self" href="{}" type="{}"/>
+  <link rel="start" href="/opds" type="{}"/>
+{}</feed>
+"#,
+        id,
+        escape_html(title),
+        updated,
+        escape_html(self_href),
+        kind,
+        NAVIGATION,
+        entries.concat()
+    )
+}
+
+fn atom_entry(id: &str, title: &str, updated: &str, content: &str, links: Vec<String>) -> String {
+    format!(
+        "  <entry>\n    <title>{}</title>\n    <id>{}</id>\n    <updated>{}</updated>\n    <content type=\"text\">{}</content>\n{}  </entry>\n",
+        escape_html(title),
+        id,
+        updated,
+        escape_html(content),
+        links.concat()
+    )
+}
+
+fn atom_link(rel: &str, href: &str, kind: &str) -> String {
+    format!(
+        "    <link rel=\"{}\" href=\"{}\" type=\"{}\"/>\n",
+        rel,
+        escape_html(href),
+        kind
+    )
+}
+
+fn cover_links(comic_id: u32) -> Vec<String> {
+    let href = format!("/opds/comics/{}/cover", comic_id);
+    vec![
+        atom_link("http://opds-spec.org/image", &href, "image/jpeg"),
+        atom_link("http://opds-spec.org/image/thumbnail", &href, "image/jpeg"),
+    ]
+}
+
+fn root_feed(config: &Config) -> Response<Body> {
+    let cache = Cache::load(config);
+    let mut comics = cache.comics.values().collect::<Vec<_>>();
+    comics.sort_by(|a, b| a.title.cmp(&b.title));
+    let entries = comics
+        .iter()
+        .map(|comic| {
+            let mut links = vec![atom_link(
+                "subsection",
+                &format!("/opds/comics/{}", comic.id),
+                NAVIGATION,
+            )];
+            links.extend(cover_links(comic.id));
+            atom_entry(
+                &format!("urn:bcdown:comic:{}", comic.id),
+                &comic.title,
+                &comic_updated(config, comic),
+                &format!("共 {} 话", comic.episodes.len()),
+                links,
+            )
+        })
+        .collect();
+    atom_response(
+        NAVIGATION,
+        atom_feed(
+            "urn:bcdown:root",
+            "bcdown 漫画库",
+            &updated(Path::new(&config.cache_dir)),
+            "/opds",
+            NAVIGATION,
+            entries,
+        ),
+    )
+}
+
+fn comic_feed(config: &Config, comic: &ComicCache, size: usize) -> Response<Body> {
+    let updated = comic_updated(config, comic);
+    let episodes = downloaded_episodes(comic);
+    let entries = vec![
+        atom_entry(
+            &format!("urn:bcdown:comic:{}:episodes", comic.id),
+            "按话下载",
+            &updated,
+            &format!("{} 话", episodes.len()),
+            vec![atom_link(
+                "subsection",
+                &format!("/opds/comics/{}/episodes", comic.id),
+                ACQUISITION,
+            )],
+        ),
+        atom_entry(
+            &format!("urn:bcdown:comic:{}:volumes:{}", comic.id, size),
+            "合集下载",
+            &updated,
+            &format!("每 {} 话一个合集", size),
+            vec![atom_link(
+                "subsection",
+                &format!("/opds/comics/{}/volumes?size={}", comic.id, size),
+                ACQUISITION,
+            )],
+        ),
+    ];
+    atom_response(
+        NAVIGATION,
+        atom_feed(
+            &format!("urn:bcdown:comic:{}", comic.id),
+            &comic.title,
+            &updated,
+            &format!("/opds/comics/{}", comic.id),
+            NAVIGATION,
+            entries,
+        ),
+    )
+}
+
+fn acquisition_feed(
+    config: &Config,
+    comic: &ComicCache,
+    kind: &str,
+    size: usize,
+) -> Response<Body> {
+    let episodes = downloaded_episodes(comic);
+    let entries = if kind == "episodes" {
+        episodes
+            .iter()
+            .map(|ep| {
+                acquisition_entry(
+                    comic,
+                    &format!("urn:bcdown:episode:{}", ep.id),
+                    &format!("{} {}", ep.short_title, ep.title),
+                    &updated(&ep.root_dir.join("meta.toml")),
+                    &format!("/opds/comics/{}/episodes/{}", comic.id, ep.id),
+                    "",
+                )
+            })
+            .collect()
+    } else {
+        volumes(episodes, size)
+            .iter()
+            .enumerate()
+            .map(|(i, volume)| {
+                acquisition_entry(
+                    comic,
+                    &format!("urn:bcdown:volume:{}:{}:{}", comic.id, size, i),
+                    &volume_title(volume),
+                    &volume
+                        .iter()
+                        .map(|ep| updated(&ep.root_dir.join("meta.toml")))
+                        .max()
+                        .unwrap(),
+                    &format!("/opds/comics/{}/volumes/{}", comic.id, i),
+                    &format!("?size={}", size),
+                )
+            })
+            .collect()
+    };
+    let mut self_href = format!("/opds/comics/{}/{}", comic.id, kind);
+    if kind == "volumes" {
+        self_href.push_str(&format!("?size={}", size));
+    }
+    atom_response(
+        ACQUISITION,
+        atom_feed(
+            &format!("urn:bcdown:comic:{}:{}", comic.id, kind),
+            &comic.title,
+            &comic_updated(config, comic),
+            &self_href,
+            ACQUISITION,
+            entries,
+        ),
+    )
+}
+
+fn acquisition_entry(
+    comic: &ComicCache,
+    id: &str,
+    title: &str,
+    updated: &str,
+    href: &str,
+    query: &str,
+) -> String {
+    let mut links = Download::ALL
+        .iter()
+        .map(|download| {
+            atom_link(
+                ACQUISITION_REL,
+                &format!("{}.{}{}", href, download.extension(), query),
+                download.mime(),
+            )
+        })
+        .collect::<Vec<_>>();
+    links.extend(cover_links(comic.id));
+    atom_entry(id, title, updated, &comic.title, links)
+}
+
+fn root_feed_v2(config: &Config) -> Response<Body> {
+    let cache = Cache::load(config);
+    let mut comics = cache.comics.values().collect::<Vec<_>>();
+    comics.sort_by(|a, b| a.title.cmp(&b.title));
+    let navigation = comics
+        .iter()
+        .map(|comic| {
+            json!({
+                "href": format!("/opds/v2/comics/{}", comic.id),
+                "title": comic.title,
+                "type": OPDS_JSON,
+                "rel": "subsection",
+            })
+        })
+        .collect::<Vec<_>>();
+    json_v2(json!({
+        "metadata": { "title": "bcdown 漫画库" },
+        "links": [
+            { "rel": "self", "href": "/opds/v2", "type": OPDS_JSON },
+            { "rel": "alternate", "href": "/opds", "type": NAVIGATION },
+        ],
+        "navigation": navigation,
+    }))
+}
+
+fn comic_feed_v2(config: &Config, comic: &ComicCache, size: usize) -> Response<Body> {
+    let episodes = downloaded_episodes(comic);
+    let publication = |identifier: String,
+                       title: String,
+                       modified: String,
+                       href: String,
+                       query: String| {
+        json!({
+            "metadata": {
+                "@type": "http://schema.org/ComicStory",
+                "identifier": identifier,
+                "title": title,
+                "modified": modified,
+                "belongsTo": { "series": comic.title },
+            },
+            "links": Download::ALL.iter().map(|download| json!({
+                "rel": ACQUISITION_REL,
+                "href": format!("{}.{}{}", href, download.extension(), query),
+                "type": download.mime(),
+            })).collect::<Vec<_>>(),
+            "images": [{ "href": format!("/opds/comics/{}/cover", comic.id), "type": "image/jpeg" }],
+        })
+    };
+    let singles = episodes
+        .iter()
+        .map(|ep| {
+            publication(
+                format!("urn:bcdown:episode:{}", ep.id),
+                format!("{} {}", ep.short_title, ep.title),
+                updated(&ep.root_dir.join("meta.toml")),
+                format!("/opds/comics/{}/episodes/{}", comic.id, ep.id),
+                String::new(),
+            )
+        })
+        .collect::<Vec<_>>();
+    let groups = volumes(episodes, size)
+        .iter()
+        .enumerate()
+        .map(|(i, volume)| {
+            publication(
+                format!("urn:bcdown:volume:{}:{}:{}", comic.id, size, i),
+                volume_title(volume),
+                volume
+                    .iter()
+                    .map(|ep| updated(&ep.root_dir.join("meta.toml")))
+                    .max()
+                    .unwrap(),
+                format!("/opds/comics/{}/volumes/{}", comic.id, i),
+                format!("?size={}", size),
+            )
+        })
+        .collect::<Vec<_>>();
+    json_v2(json!({
+        "metadata": { "title": comic.title, "modified": comic_updated(config, comic) },
+        "links": [
+            { "rel": "self", "href": format!("/opds/v2/comics/{}", comic.id), "type": OPDS_JSON },
+            { "rel": "start", "href": "/opds/v2", "type": OPDS_JSON },
+        ],
+        "groups": [
+            { "metadata": { "title": "按话下载" }, "publications": singles },
+            { "metadata": { "title": "合集下载" }, "publications": groups },
+        ],
+    }))
+}
+
+fn json_v2(value: serde_json::Value) -> Response<Body> {
+    let mut response = json_response(StatusCode::OK, value);
+    response
+        .headers_mut()
+        .insert("Content-Type", OPDS_JSON.parse().unwrap());
+    response
+}
+
+/// 导出到临时文件后分块发送，发送完成后删除临时文件
+///
+/// 导出和发送时持有这部漫画的共享锁，避免其间被 prune 或 clear 删除
+async fn download_response(
+    config: &Config,
+    id: u32,
+    by_episode: bool,
+    key: usize,
+    size: usize,
+    download: Download,
+) -> Response<Body> {
+    let temp = std::env::temp_dir().join(format!(
+        "bcdown-{}-{}.{}",
+        std::process::id(),
+        NEXT_TEMP.fetch_add(1, Ordering::Relaxed),
+        download.extension()
+    ));
+    let path = temp.clone();
+    let config = config.clone();
+    let exported = tokio::task::spawn_blocking(move || {
+        let lock = lock::comic_blocking(&config, id, false)?;
+        let comic = load_comic(&config, &id.to_string()).ok_or(Error::NotCached(id))?;
+        let format: ExportFormatEnum = match download {
+            Download::Cbz => Zip.into(),
+            Download::Epub => Epub {
+                cover: std::fs::read(cover_path(&config, comic.id)).ok(),
+            }
+            .into(),
+        };
+        let mut episodes = downloaded_episodes(&comic);
+        let item = if by_episode {
+            let index = episodes
+                .iter()
+                .position(|ep| ep.id as usize == key)
+                .ok_or(Error::NotCached(comic.id))?;
+            Item::Single(episodes.swap_remove(index))
+        } else {
+            let mut volumes = volumes(episodes, size);
+            if key >= volumes.len() {
+                return Err(Error::NotCached(comic.id));
+            }
+            Item::Group(volumes.swap_remove(key))
+        };
+        let file_name = format!(
+            "{} {}.{}",
+            comic.title,
+            item.make_file_name(),
+            download.extension()
+        );
+        export_item(&comic.title, item, &config, &path, &format, &|_| Ok(()))?;
+        Ok::<_, Error>((file_name, lock))
+    })
+    .await;
+    let (file_name, lock) = match exported {
+        Ok(Ok(exported)) => exported,
+        Ok(Err(e)) => {
+            std::fs::remove_file(&temp).ok();
+            return error_response(&e);
+        }
+        Err(e) => {
+            std::fs::remove_file(&temp).ok();
+            return json_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                json!({ "error": format!("导出失败：{}", e) }),
+            );
+        }
+    };
+
+    let mut file = match tokio::fs::File::open(&temp).await {
+        Ok(file) => file,
+        Err(e) => return error_response(&e.into()),
+    };
+    let length = file.metadata().await.map(|meta| meta.len()).unwrap_or(0);
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            match file.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    // 客户端断开连接时停止发送
+                    if sender.send_data(buf[..n].to_vec().into()).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+        drop(file);
+        drop(lock);
+        tokio::fs::remove_file(&temp).await.ok();
+    });
+    Response::builder()
+        .header("Content-Type", download.mime())
+        .header("Content-Length", length)
+        .header(
+            "Content-Disposition",
+            format!(
+                "attachment; filename*=UTF-8''{}",
+                encode_href(&file_name).replace('/', "%2F")
+            ),
+        )
+        .body(body)
+        .unwrap()
+}
@@ -0,0 +1,344 @@
+//! 导出前的图片处理：缩放、灰度、重新压缩
+//!
+//! 所有的导出格式都通过 [`load_pages`] 读取图片，未选择设备配置时图片会原样导出
+
+use crate::cache::EpisodeCache;
+use crate::config::{Config, DeviceProfile, Direction, SpreadMode};
+use crate::error::{Error, Result};
+use crate::webtoon;
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, GrayImage, ImageFormat, ImageOutputFormat};
+use rayon::prelude::*;
+use std::io::Cursor;
+
+/// 未指定质量但需要重新编码时使用的 JPEG 质量
+const DEFAULT_QUALITY: u8 = 90;
+/// 未指定时判定为跨页的宽高比
+const DEFAULT_SPREAD_RATIO: f64 = 1.2;
+
+/// 导出时使用的一页图片
+pub struct Page {
+    /// 文件名，包含扩展名
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+impl Page {
+    pub fn extension(&self) -> &str {
+        self.name.rsplit('.').next().unwrap_or("jpg")
+    }
+
+    pub fn stem(&self) -> &str {
+        self.name.split('.').next().unwrap_or(&self.name)
+    }
+}
+
+/// 导出前检查设备配置是否可用
+pub fn check(config: &Config) -> Result<()> {
+    if let Some(profile) = config.get_profile()? {
+        if profile.webp && !cfg!(feature = "webp") {
+            return Err(Error::MissingFeature("webp"));
+        }
+    }
+    Ok(())
+}
+
+/// 当前配置下导出的图片是否与缓存中的完全一致
+pub fn is_passthrough(config: &Config) -> bool {
+    profile(config).map(|p| p.is_passthrough()).unwrap_or(true)
+        && config.webtoon.is_none()
+        && config.spread == SpreadMode::Keep
+}
+
+/// 设备配置已经由 [`check`] 检查过
+fn profile(config: &Config) -> Option<DeviceProfile> {
+    config.get_profile().ok().flatten()
+}
+
+/// 读取某一话的全部图片，并按照当前的设备配置进行处理
+pub fn load_pages(episode: &EpisodeCache, config: &Config) -> Result<Vec<Page>> {
+    let profile = profile(config).filter(|p| !p.is_passthrough());
+    let pages = episode
+        .get_paths()
+        .par_iter()
+        .map(|path| {
+            Ok(Page {
+                name: path.file_name().unwrap_or_default().to_string_lossy().to_string(),
+                data: std::fs::read(path)?,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut stages = if let Some(ratio) = config.webtoon {
+        restrip(episode, pages, ratio)?
+    } else {
+        pages.into_iter().map(Stage::Raw).collect()
+    };
+    if config.spread != SpreadMode::Keep {
+        let ratio = config.spread_ratio.unwrap_or(DEFAULT_SPREAD_RATIO);
+        stages = stages
+            .into_par_iter()
+            .flat_map_iter(|stage| handle_spread(stage, config.spread, config.direction, ratio))
+            .collect();
+    }
+
+    stages
+        .into_par_iter()
+        .map(|stage| match stage {
+            Stage::Raw(page) => match &profile {
+                Some(profile) => transcode(page, profile),
+                None => Ok(page),
+            },
+            Stage::Decoded { stem, source, img } => render(&stem, source, img, profile.as_ref()),
+        })
+        .collect()
+}
+
+/// 处理过程中的图片，未经修改的图片不会被重新编码
+enum Stage {
+    Raw(Page),
+    Decoded {
+        stem: String,
+        source: Option<ImageFormat>,
+        img: DynamicImage,
+    },
+}
+
+impl Stage {
+    fn dimensions(&self) -> Option<(u32, u32)> {
+        match self {
+            Stage::Raw(page) => image::io::Reader::new(Cursor::new(&page.data))
+                .with_guessed_format()
+                .ok()?
+                .into_dimensions()
+                .ok(),
+            Stage::Decoded { img, .. } => Some((img.width(), img.height())),
+        }
+    }
+}
+
+/// 拆分或旋转跨页图片，非跨页图片原样返回
+fn handle_spread(stage: Stage, mode: SpreadMode, direction: Direction, ratio: f64) -> Vec<Stage> {
+    match stage.dimensions() {
+        Some((w, h)) if w as f64 / h as f64 > ratio => {}
+        _ => return vec![stage],
+    }
+    let (stem, source, img) = match stage {
+        Stage::Raw(page) => match image::load_from_memory(&page.data) {
+            Ok(img) => (
+                page.stem().to_string(),
+                image::guess_format(&page.data).ok(),
+                img,
+            ),
+            Err(_) => return vec![Stage::Raw(page)],
+        },
+        Stage::Decoded { stem, source, img } => (stem, source, img),
+    };
+    match mode {
+        SpreadMode::Keep => vec![Stage::Decoded { stem, source, img }],
+        SpreadMode::Split => {
+            let half = img.width() / 2;
+            let left = img.crop_imm(0, 0, half, img.height());
+            let right = img.crop_imm(half, 0, img.width() - half, img.height());
+            let (first, second) = match direction {
+                Direction::Ltr => (left, right),
+                Direction::Rtl => (right, left),
+            };
+            vec![
+                Stage::Decoded {
+                    stem: format!("{}_1", stem),
+                    source,
+                    img: first,
+                },
+                Stage::Decoded {
+                    stem: format!("{}_2", stem),
+                    source,
+                    img: second,
+                },
+            ]
+        }
+        SpreadMode::Rotate => {
+            // 先读的一侧朝上
+            let img = match direction {
+                Direction::Ltr => img.rotate90(),
+                Direction::Rtl => img.rotate270(),
+            };
+            vec![Stage::Decoded { stem, source, img }]
+        }
+    }
+}
+
+/// 重新切分需要解码全部图片，有一张无法解码时整话无法切分
+fn restrip(episode: &EpisodeCache, pages: Vec<Page>, ratio: f64) -> Result<Vec<Stage>> {
+    let source = pages
+        .first()
+        .and_then(|page| image::guess_format(&page.data).ok());
+    let images = pages
+        .par_iter()
+        .map(|page| {
+            image::load_from_memory(&page.data)
+                .map_err(|e| Error::Export(format!("无法解码图片 {}：{}", page.name, e)))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(webtoon::restrip(images, ratio)?
+        .into_iter()
+        .enumerate()
+        .map(|(i, img)| Stage::Decoded {
+            stem: format!("{}_{:03}", episode.id, i),
+            source,
+            img,
+        })
+        .collect())
+}
+
+#[derive(PartialEq)]
+enum Target {
+    Jpeg,
+    Png,
+    WebP,
+}
+
+impl Target {
+    fn extension(&self) -> &'static str {
+        match self {
+            Target::Jpeg => "jpg",
+            Target::Png => "png",
+            Target::WebP => "webp",
+        }
+    }
+
+    fn choose(source: Option<ImageFormat>, profile: Option<&DeviceProfile>) -> Target {
+        let png_to_jpeg = profile.map(|p| p.png_to_jpeg).unwrap_or(false);
+        if profile.map(|p| p.webp).unwrap_or(false) {
+            Target::WebP
+        } else if source == Some(ImageFormat::Png) && !png_to_jpeg {
+            Target::Png
+        } else {
+            Target::Jpeg
+        }
+    }
+
+    fn matches(&self, source: Option<ImageFormat>) -> bool {
+        match self {
+            Target::Jpeg => source == Some(ImageFormat::Jpeg),
+            Target::Png => source == Some(ImageFormat::Png),
+            Target::WebP => source == Some(ImageFormat::WebP),
+        }
+    }
+}
+
+fn transcode(page: Page, profile: &DeviceProfile) -> Result<Page> {
+    let source = image::guess_format(&page.data).ok();
+    let img = match image::load_from_memory(&page.data) {
+        Ok(img) => img,
+        // 无法解码的图片原样导出
+        Err(_) => return Ok(page),
+    };
+    let (img, changed) = process(img, profile);
+    let target = Target::choose(source, Some(profile));
+    if !changed && target.matches(source) && profile.quality.is_none() {
+        return Ok(page);
+    }
+    let quality = profile.quality.unwrap_or(DEFAULT_QUALITY).clamp(1, 100);
+    Ok(Page {
+        name: format!("{}.{}", page.stem(), target.extension()),
+        data: encode(&img, &target, quality)?,
+    })
+}
+
+/// 编码已经解码过的图片
+fn render(
+    stem: &str,
+    source: Option<ImageFormat>,
+    img: DynamicImage,
+    profile: Option<&DeviceProfile>,
+) -> Result<Page> {
+    let img = match profile {
+        Some(profile) => process(img, profile).0,
+        None => img,
+    };
+    let target = Target::choose(source, profile);
+    let quality = profile
+        .and_then(|p| p.quality)
+        .unwrap_or(DEFAULT_QUALITY)
+        .clamp(1, 100);
+    Ok(Page {
+        name: format!("{}.{}", stem, target.extension()),
+        data: encode(&img, &target, quality)?,
+    })
+}
+
+/// 缩放和灰度处理，返回处理后的图片以及是否发生了修改
+fn process(mut img: DynamicImage, profile: &DeviceProfile) -> (DynamicImage, bool) {
+    let mut changed = false;
+
+    let max_width = profile.max_width.unwrap_or(u32::MAX);
+    let max_height = profile.max_height.unwrap_or(u32::MAX);
+    if img.width() > max_width || img.height() > max_height {
+        img = img.resize(
+            max_width.min(img.width()),
+            max_height.min(img.height()),
+            FilterType::Lanczos3,
+        );
+        changed = true;
+    }
+
+    if profile.grayscale {
+        let mut gray = img.to_luma8();
+        if let Some(gamma) = profile.gamma {
+            apply_gamma(&mut gray, gamma);
+        }
+        img = DynamicImage::ImageLuma8(gray);
+        changed = true;
+    }
+    (img, changed)
+}
+
+fn apply_gamma(img: &mut GrayImage, gamma: f32) {
+    let mut table = [0u8; 256];
+    for (i, v) in table.iter_mut().enumerate() {
+        *v = (255. * (i as f32 / 255.).powf(1. / gamma)).round() as u8;
+    }
+    for pixel in img.pixels_mut() {
+        pixel.0[0] = table[pixel.0[0] as usize];
+    }
+}
+
+fn encode(img: &DynamicImage, target: &Target, quality: u8) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    match target {
+        Target::Jpeg => {
+            // JPEG 不支持透明通道
+            let img = if img.color().has_alpha() {
+                DynamicImage::ImageRgb8(img.to_rgb8())
+            } else {
+                img.clone()
+            };
+            JpegEncoder::new_with_quality(&mut data, quality)
+                .encode(img.as_bytes(), img.width(), img.height(), img.color())
+                .map_err(|e| Error::Export(format!("无法编码图片：{}", e)))?;
+        }
+        Target::Png => {
+            img.write_to(&mut Cursor::new(&mut data), ImageOutputFormat::Png)
+                .map_err(|e| Error::Export(format!("无法编码图片：{}", e)))?;
+        }
+        Target::WebP => {
+            data = encode_webp(img, quality);
+        }
+    }
+    Ok(data)
+}
+
+#[cfg(feature = "webp")]
+fn encode_webp(img: &DynamicImage, quality: u8) -> Vec<u8> {
+    let rgb = img.to_rgb8();
+    webp::Encoder::from_rgb(&rgb, rgb.width(), rgb.height())
+        .encode(quality as f32)
+        .to_vec()
+}
+
+#[cfg(not(feature = "webp"))]
+fn encode_webp(_img: &DynamicImage, _quality: u8) -> Vec<u8> {
+    unreachable!("导出前会通过 check 检查 webp 特性")
+}
@@ -1,11 +1,22 @@
-use printpdf::{Image, Mm, PdfDocument};
-use std::path::PathBuf;
+use super::pages::Page;
+use crate::error::{Error, Result};
+use printpdf::image_crate::DynamicImage;
+use printpdf::{Image, Mm, PdfDocument, PdfDocumentReference, PdfLayerReference};
 
-const H: f64 = 297.;
+pub const H: f64 = 297.;
 // 297mm
-const W: f64 = 210.;
+pub const W: f64 = 210.;
 
-fn calc_transformation(img_w: u32, img_h: u32, dpi: f64) -> printpdf::ImageTransform {
+/// 横向的跨页图片使用横向页面
+fn page_size(img_w: u32, img_h: u32) -> (f64, f64) {
+    if img_w > img_h {
+        (H, W)
+    } else {
+        (W, H)
+    }
+}
+
+fn calc_transformation(img_w: u32, img_h: u32, w: f64, h: f64, dpi: f64) -> printpdf::ImageTransform {
     // 变换方法: 中心点对齐 不允许出界 保持比例
     let img_w = img_w as f64;
     let img_h = img_h as f64;
@@ -14,13 +25,7 @@ fn calc_transformation(img_w: u32, img_h: u32, dpi: f64) -> printpdf::ImageTrans
     let img_w_mm = img_w / dpi * 25.4;
     let img_h_mm = img_h / dpi * 25.4;
 
-    let w = W;
-    let h = H;
-    let scale = if img_w > img_h {
-        w / img_w_mm
-    } else {
-        h / img_h_mm
-    };
+    let scale = (w / img_w_mm).min(h / img_h_mm);
     let x = (w - img_w_mm * scale) / 2.;
     let y = (h - img_h_mm * scale) / 2.;
     printpdf::ImageTransform {
@@ -33,72 +38,73 @@ fn calc_transformation(img_w: u32, img_h: u32, dpi: f64) -> printpdf::ImageTrans
     }
 }
 
-fn calc_best_dpi(img_w: u32, img_h: u32) -> f64 {
+fn calc_best_dpi(img_w: u32, img_h: u32, w: f64, h: f64) -> f64 {
+    // 恰好铺满页面时的dpi
     let img_w = img_w as f64;
     let img_h = img_h as f64;
-    let w = W;
-    let h = H;
+    (img_w / w * 25.4).max(img_h / h * 25.4)
+}
 
-    if img_w > img_h {
-        w / img_w
-    } else {
-        h / img_h
-    }
+fn decode(page: &Page) -> Result<DynamicImage> {
+    printpdf::image_crate::load_from_memory(&page.data)
+        .map_err(|e| Error::Export(format!("无法解码图片 {}：{}", page.name, e)))
+}
+
+fn draw(layer: PdfLayerReference, d_image: &DynamicImage, w: f64, h: f64, dpi: Option<f64>) {
+    let image = Image::from_dynamic_image(d_image);
+    let dpi = dpi.unwrap_or_else(|| calc_best_dpi(d_image.width(), d_image.height(), w, h));
+    image.add_to_layer(
+        layer,
+        calc_transformation(d_image.width(), d_image.height(), w, h, dpi),
+    );
 }
 
 pub fn from_images(
-    images: Vec<PathBuf>,
+    images: Vec<Page>,
     title: &str,
     bookmark: &str,
     dpi: Option<f64>,
-) -> printpdf::PdfDocumentReference {
-    let (doc, page, layer) = PdfDocument::new(title, Mm(W), Mm(H), "image_layer");
+) -> Result<PdfDocumentReference> {
+    let mut images = images.iter();
+    let first = images.next().map(decode).transpose()?;
+    let (w, h) = first
+        .as_ref()
+        .map(|img| page_size(img.width(), img.height()))
+        .unwrap_or((W, H));
+    let (doc, page, layer) = PdfDocument::new(title, Mm(w), Mm(h), "image_layer");
     doc.add_bookmark(bookmark, page);
-    let mut current_layer = doc.get_page(page).get_layer(layer);
-    for (i, path) in images.iter().enumerate() {
-        let d_image = printpdf::image_crate::open(path).unwrap();
-        let image = Image::from_dynamic_image(&d_image);
-        let dpi = dpi.unwrap_or_else(|| calc_best_dpi(d_image.width(), d_image.height()));
-        image.add_to_layer(
-            current_layer,
-            calc_transformation(d_image.width(), d_image.height(), dpi),
-        );
-
-        if i < images.len() - 1 {
-            let (page, layer) = doc.add_page(Mm(W), Mm(H), "image_layer");
-            current_layer = doc.get_page(page).get_layer(layer);
-        } else {
-            break;
-        }
+    if let Some(d_image) = first {
+        draw(doc.get_page(page).get_layer(layer), &d_image, w, h, dpi);
     }
-    doc
+    append_pages(&doc, images, None, dpi)?;
+    Ok(doc)
 }
 
 pub fn append(
-    doc: printpdf::PdfDocumentReference,
-    images: Vec<PathBuf>,
+    doc: PdfDocumentReference,
+    images: Vec<Page>,
     bookmark: &str,
     dpi: Option<f64>,
-) -> printpdf::PdfDocumentReference {
-    let (page, layer) = doc.add_page(Mm(W), Mm(H), "image_layer");
-    doc.add_bookmark(bookmark, page);
-
-    let mut current_layer = doc.get_page(page).get_layer(layer);
-    for (i, path) in images.iter().enumerate() {
-        let d_image = printpdf::image_crate::open(path).unwrap();
-        let image = Image::from_dynamic_image(&d_image);
-        let dpi = dpi.unwrap_or_else(|| calc_best_dpi(d_image.width(), d_image.height()));
-        image.add_to_layer(
-            current_layer,
-            calc_transformation(d_image.width(), d_image.height(), dpi),
-        );
+) -> Result<PdfDocumentReference> {
+    append_pages(&doc, images.iter(), Some(bookmark), dpi)?;
+    Ok(doc)
+}
 
-        if i < images.len() - 1 {
-            let (page, layer) = doc.add_page(Mm(W), Mm(H), "image_layer");
-            current_layer = doc.get_page(page).get_layer(layer);
-        } else {
-            break;
+fn append_pages<'a>(
+    doc: &PdfDocumentReference,
+    images: impl Iterator<Item = &'a Page>,
+    mut bookmark: Option<&str>,
+    dpi: Option<f64>,
+) -> Result<()> {
+    for page in images {
+        let d_image = decode(page)?;
+        let (w, h) = page_size(d_image.width(), d_image.height());
+        let (page, layer) = doc.add_page(Mm(w), Mm(h), "image_layer");
+        // 书签只加在第一页
+        if let Some(bookmark) = bookmark.take() {
+            doc.add_bookmark(bookmark, page);
         }
+        draw(doc.get_page(page).get_layer(layer), &d_image, w, h, dpi);
     }
-    doc
+    Ok(())
 }
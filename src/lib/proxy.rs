@@ -0,0 +1,159 @@
+//! 代理、证书和超时设置
+//!
+//! 配置文件中的 `[network]` 可以被 `BCDOWN_` 开头的环境变量覆盖
+
+use crate::error::{Error, Result};
+use reqwest::{Certificate, ClientBuilder, Proxy, Url};
+use std::time::Duration;
+
+/// 默认的连接超时 (秒)
+const CONNECT_TIMEOUT: u64 = 10;
+/// 默认的读取超时 (秒)
+const READ_TIMEOUT: u64 = 30;
+
+/// 网络设置
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, Default)]
+pub struct NetworkConfig {
+    /// 所有请求使用的代理，如 "http://127.0.0.1:7890" "socks5://127.0.0.1:1080"
+    pub proxy: Option<String>,
+    /// 只用于 http 请求的代理，优先于 `proxy`
+    pub http_proxy: Option<String>,
+    /// 只用于 https 请求的代理，优先于 `proxy`
+    pub https_proxy: Option<String>,
+    /// 连接超时 (秒)，默认为 10
+    pub connect_timeout: Option<u64>,
+    /// 读取超时 (秒)，超过该时间没有收到数据则视为失败，默认为 30
+    pub read_timeout: Option<u64>,
+    /// 不使用代理的域名，如 "localhost" ".example.com"，"*" 表示全部不使用
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub no_proxy: Vec<String>,
+    /// 额外信任的根证书文件，PEM 或 DER 格式
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ca_certs: Vec<String>,
+}
+
+impl NetworkConfig {
+    /// 应用环境变量后的设置
+    ///
+    /// `BCDOWN_PROXY` `BCDOWN_HTTP_PROXY` `BCDOWN_HTTPS_PROXY` `BCDOWN_NO_PROXY` (逗号分隔)
+    /// `BCDOWN_CA_CERTS` (按系统的路径分隔符分隔) `BCDOWN_CONNECT_TIMEOUT` `BCDOWN_READ_TIMEOUT`
+    pub fn with_env(&self) -> Result<NetworkConfig> {
+        let mut network = self.clone();
+        if let Some(proxy) = env("BCDOWN_PROXY") {
+            network.proxy = Some(proxy);
+        }
+        if let Some(proxy) = env("BCDOWN_HTTP_PROXY") {
+            network.http_proxy = Some(proxy);
+        }
+        if let Some(proxy) = env("BCDOWN_HTTPS_PROXY") {
+            network.https_proxy = Some(proxy);
+        }
+        if let Some(no_proxy) = env("BCDOWN_NO_PROXY") {
+            network.no_proxy = no_proxy
+                .split(',')
+                .map(|host| host.trim().to_string())
+                .filter(|host| !host.is_empty())
+                .collect();
+        }
+        if let Some(certs) = std::env::var_os("BCDOWN_CA_CERTS").filter(|certs| !certs.is_empty()) {
+            network.ca_certs = std::env::split_paths(&certs)
+                .map(|path| path.to_string_lossy().to_string())
+                .collect();
+        }
+        if let Some(timeout) = env("BCDOWN_CONNECT_TIMEOUT") {
+            network.connect_timeout = Some(parse_secs("BCDOWN_CONNECT_TIMEOUT", &timeout)?);
+        }
+        if let Some(timeout) = env("BCDOWN_READ_TIMEOUT") {
+            network.read_timeout = Some(parse_secs("BCDOWN_READ_TIMEOUT", &timeout)?);
+        }
+        Ok(network)
+    }
+
+    pub fn connect_timeout(&self) -> Duration {
+        Duration::from_secs(self.connect_timeout.unwrap_or(CONNECT_TIMEOUT))
+    }
+
+    pub fn read_timeout(&self) -> Duration {
+        Duration::from_secs(self.read_timeout.unwrap_or(READ_TIMEOUT))
+    }
+
+    /// 为客户端设置代理、证书和连接超时
+    ///
+    /// 没有设置代理时沿用 reqwest 的默认行为，读取系统的 `HTTP_PROXY` 等环境变量
+    pub(crate) fn apply(&self, mut builder: ClientBuilder) -> Result<ClientBuilder> {
+        builder = builder.connect_timeout(self.connect_timeout());
+        for path in &self.ca_certs {
+            builder = builder.add_root_certificate(load_cert(path)?);
+        }
+        let http = self.http_proxy.as_ref().or(self.proxy.as_ref());
+        let https = self.https_proxy.as_ref().or(self.proxy.as_ref());
+        if http.is_none() && https.is_none() {
+            return Ok(builder);
+        }
+        let http = http.map(|proxy| parse_proxy(proxy)).transpose()?;
+        let https = https.map(|proxy| parse_proxy(proxy)).transpose()?;
+        let no_proxy = self.no_proxy.clone();
+        let proxy = Proxy::custom(move |url| {
+            if url.host_str().is_some_and(|host| bypass(&no_proxy, host)) {
+                return None;
+            }
+            match url.scheme() {
+                "http" => http.clone(),
+                "https" => https.clone(),
+                _ => None,
+            }
+        });
+        Ok(builder.proxy(proxy))
+    }
+}
+
+/// 非空的环境变量
+fn env(key: &str) -> Option<String> {
+    std::env::var(key).ok().filter(|value| !value.trim().is_empty())
+}
+
+fn parse_secs(key: &str, value: &str) -> Result<u64> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| Error::Client(format!("{} 应为秒数，而不是 {}", key, value)))
+}
+
+/// 检查代理地址，支持 http https socks5 socks5h
+fn parse_proxy(proxy: &str) -> Result<Url> {
+    let invalid = || Error::Client(format!("无效的代理地址：{}", proxy));
+    let url = Url::parse(proxy.trim()).map_err(|_| invalid())?;
+    match url.scheme() {
+        "http" | "https" | "socks5" | "socks5h" if url.host_str().is_some() => {}
+        _ => return Err(invalid()),
+    }
+    // 交给 reqwest 再检查一遍，如地址中的用户名和密码
+    Proxy::all(url.clone()).map_err(|_| invalid())?;
+    Ok(url)
+}
+
+/// `host` 是否在不使用代理的列表中，`.example.com` 和 `example.com` 都匹配子域名
+fn bypass(no_proxy: &[String], host: &str) -> bool {
+    let host = host.trim_start_matches('[').trim_end_matches(']').to_lowercase();
+    no_proxy.iter().any(|pattern| {
+        let pattern = pattern.trim().to_lowercase();
+        if pattern == "*" {
+            return true;
+        }
+        let domain = pattern.trim_start_matches('.');
+        !domain.is_empty()
+            && (host == domain
+                || host
+                    .strip_suffix(domain)
+                    .is_some_and(|rest| rest.ends_with('.')))
+    })
+}
+
+/// 读取证书文件，先按 PEM 解析，失败后按 DER 解析
+fn load_cert(path: &str) -> Result<Certificate> {
+    let bytes = std::fs::read(path)
+        .map_err(|e| Error::Client(format!("无法读取证书 {}：{}", path, e)))?;
+    Certificate::from_pem(&bytes)
+        .or_else(|_| Certificate::from_der(&bytes))
+        .map_err(|e| Error::Client(format!("无法解析证书 {}：{}", path, e)))
+}
@@ -0,0 +1,238 @@
+//! 按策略清理缓存
+//!
+//! 先按关注列表、保留话数和导出时间挑出要删除的章节，
+//! 剩下的缓存仍然超过大小上限时，再按最近使用的时间从旧到新删除。
+//! 图片可能被多话共用，只有最后一个引用它的章节被删除时才计入释放的空间
+
+use crate::cache::{Cache, ComicCache, EpisodeCache};
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::{get_dir_size, is_temp_file, store};
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+
+/// 清理缓存的策略，未设置的条件不生效
+#[derive(Clone, Debug, Default)]
+pub struct PrunePolicy {
+    /// 每部漫画只保留最新的几话
+    pub keep_latest: Option<usize>,
+    /// 删除导出时间超过这些天的章节
+    pub exported_days: Option<u64>,
+    /// 删除不在关注列表中的漫画
+    pub unwatched: bool,
+    /// 缓存总大小的上限 (字节)
+    pub max_size: Option<u64>,
+}
+
+impl PrunePolicy {
+    /// 是否没有设置任何条件
+    pub fn is_empty(&self) -> bool {
+        self.keep_latest.is_none()
+            && self.exported_days.is_none()
+            && !self.unwatched
+            && self.max_size.is_none()
+    }
+}
+
+/// 删除的原因
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PruneReason {
+    /// 漫画不在关注列表中
+    Unwatched,
+    /// 超出保留的话数
+    Outdated,
+    /// 导出已经超过一段时间
+    Exported,
+    /// 缓存超过大小上限，且这一话最久没有使用
+    OverSize,
+}
+
+impl Display for PruneReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let reason = match self {
+            PruneReason::Unwatched => "不在关注列表中",
+            PruneReason::Outdated => "超出保留的话数",
+            PruneReason::Exported => "已经导出",
+            PruneReason::OverSize => "超出缓存大小上限",
+        };
+        write!(f, "{}", reason)
+    }
+}
+
+/// 一项被删除的缓存
+#[derive(Clone, Debug)]
+pub struct PruneItem {
+    pub comic_id: u32,
+    pub comic_title: String,
+    /// 为空时删除整部漫画
+    pub episode: Option<PrunedEpisode>,
+    pub path: PathBuf,
+    /// 删除后释放的空间 (字节)
+    pub bytes: u64,
+    pub reason: PruneReason,
+}
+
+/// 被删除的章节
+#[derive(Clone, Debug)]
+pub struct PrunedEpisode {
+    pub id: u32,
+    pub ord: f64,
+    pub short_title: String,
+    pub title: String,
+}
+
+/// 清理的结果
+#[derive(Clone, Debug)]
+pub struct PruneReport {
+    pub items: Vec<PruneItem>,
+    /// 清理前缓存目录的大小 (字节)
+    pub before: u64,
+    /// 为真时只计算，没有删除任何文件
+    pub dry_run: bool,
+}
+
+impl PruneReport {
+    /// 释放的空间 (字节)
+    pub fn freed(&self) -> u64 {
+        self.items.iter().map(|item| item.bytes).sum()
+    }
+}
+
+pub(crate) fn prune(config: &Config, policy: &PrunePolicy, dry_run: bool) -> Result<PruneReport> {
+    if policy.unwatched && config.watchlist.is_empty() {
+        return Err(Error::EmptyWatchlist);
+    }
+    let root = Path::new(&config.cache_dir);
+    // 加载缓存时不会删除文件，下载中断留下的无效文件夹和临时文件在这里清理
+    if !dry_run {
+        for path in Cache::invalid_dirs(config) {
+            std::fs::remove_dir_all(path)?;
+        }
+        remove_temp_files(root, 2)?;
+    }
+    let cache = Cache::load(config);
+    let before = get_dir_size(root);
+    let store_dir = root.join(store::STORE_DIR);
+    let mut references = store::references(&cache);
+    let now = chrono::Local::now().timestamp();
+    let mut comics = cache.comics.values().collect::<Vec<_>>();
+    comics.sort_by_key(|comic| comic.id);
+
+    let mut items = Vec::new();
+    // 没有被前面的条件删除的章节，超过大小上限时从中挑选
+    let mut kept = Vec::new();
+    for comic in comics {
+        if policy.unwatched && !config.watchlist.contains(&comic.id) {
+            let path = root.join(comic.id.to_string());
+            let released = comic
+                .episodes
+                .values()
+                .map(|episode| release(&mut references, &store_dir, episode))
+                .sum::<u64>();
+            items.push(PruneItem {
+                comic_id: comic.id,
+                comic_title: comic.title.clone(),
+                episode: None,
+                bytes: get_dir_size(&path) + released,
+                path,
+                reason: PruneReason::Unwatched,
+            });
+            continue;
+        }
+        let mut episodes = comic.episodes.values().collect::<Vec<_>>();
+        // 从新到旧
+        episodes.sort_by(|a, b| b.ord.partial_cmp(&a.ord).unwrap());
+        for (index, episode) in episodes.into_iter().enumerate() {
+            let outdated = policy.keep_latest.is_some_and(|keep| index >= keep);
+            let exported = policy.exported_days.is_some_and(|days| {
+                episode
+                    .exported
+                    .is_some_and(|exported| now - exported >= days as i64 * 24 * 3600)
+            });
+            if outdated {
+                let bytes = release(&mut references, &store_dir, episode);
+                items.push(episode_item(comic, episode, bytes, PruneReason::Outdated));
+            } else if exported {
+                let bytes = release(&mut references, &store_dir, episode);
+                items.push(episode_item(comic, episode, bytes, PruneReason::Exported));
+            } else {
+                kept.push((comic, episode));
+            }
+        }
+    }
+
+    if let Some(max_size) = policy.max_size {
+        let freed = items.iter().map(|item| item.bytes).sum::<u64>();
+        let mut size = before.saturating_sub(freed);
+        kept.sort_by_key(|(_, episode)| episode.last_used());
+        for (comic, episode) in kept {
+            if size <= max_size {
+                break;
+            }
+            let bytes = release(&mut references, &store_dir, episode);
+            let item = episode_item(comic, episode, bytes, PruneReason::OverSize);
+            size = size.saturating_sub(item.bytes);
+            items.push(item);
+        }
+    }
+
+    if !dry_run {
+        for item in &items {
+            if item.path.is_dir() {
+                std::fs::remove_dir_all(&item.path)?;
+            }
+        }
+        store::sweep(config, &[])?;
+    }
+    Ok(PruneReport {
+        items,
+        before,
+        dry_run,
+    })
+}
+
+/// 删除 `dir` 及其 `depth` 层子目录中的临时文件，章节目录在第二层
+fn remove_temp_files(dir: &Path, depth: usize) -> Result<()> {
+    for path in std::fs::read_dir(dir)?.flatten().map(|entry| entry.path()) {
+        if path.is_dir() {
+            if depth > 0 {
+                remove_temp_files(&path, depth - 1)?;
+            }
+        } else if is_temp_file(&path) {
+            std::fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+/// 删除一话后减少它用到的图片的引用次数，返回不再被引用的图片的大小 (字节)
+fn release(references: &mut HashMap<String, usize>, store_dir: &Path, episode: &EpisodeCache) -> u64 {
+    let mut bytes = 0;
+    for name in episode.blob_names() {
+        if let Some(count) = references.get_mut(&name) {
+            *count = count.saturating_sub(1);
+            if *count == 0 {
+                bytes += store::blob_size(store_dir, &name);
+            }
+        }
+    }
+    bytes
+}
+
+/// `bytes` 为 [`release`] 释放的图片大小，再加上章节目录本身
+fn episode_item(comic: &ComicCache, episode: &EpisodeCache, bytes: u64, reason: PruneReason) -> PruneItem {
+    PruneItem {
+        comic_id: comic.id,
+        comic_title: comic.title.clone(),
+        episode: Some(PrunedEpisode {
+            id: episode.id,
+            ord: episode.ord,
+            short_title: episode.short_title.clone(),
+            title: episode.title.clone(),
+        }),
+        path: episode.root_dir.clone(),
+        bytes: get_dir_size(&episode.root_dir) + bytes,
+        reason,
+    }
+}
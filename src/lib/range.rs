@@ -0,0 +1,390 @@
+//! 章节范围
+//!
+//! 范围由逗号分隔的多项组成，每一项可以是：
+//!
+//! ```text
+//! 5            序号为 5 的一话
+//! 1-20 1- -20  序号在范围内的章节，省略时从第一话开始或到最后一话结束
+//! 1-20/2       序号在范围内、从开始每隔 2 话选择一话
+//! latest:3     最新的 3 话
+//! first:3      最早的 3 话
+//! id:12345     章节ID
+//! title:番外   短标题或标题中包含的文字
+//! unlocked     已解锁的章节
+//! free         限时免费的章节
+//! !10-12       排除，! 后面可以是以上任意一项
+//! ```
+//!
+//! 只有排除项时从全部章节中排除
+
+use crate::error::{Error, Result};
+use std::str::FromStr;
+
+/// 可以按范围选择的章节
+pub(crate) trait Selectable {
+    fn ord(&self) -> f64;
+    fn id(&self) -> u32;
+    /// 短标题和标题
+    fn titles(&self) -> [&str; 2];
+    /// 是否未解锁，缓存中的章节都已经解锁
+    fn is_locked(&self) -> bool {
+        false
+    }
+    /// 是否限时免费，没有这项信息时为空
+    fn is_free(&self) -> Option<bool> {
+        None
+    }
+}
+
+/// 解析后的范围
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct EpisodeRange {
+    includes: Vec<Selector>,
+    excludes: Vec<Selector>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Selector {
+    /// 序号在 `from` 到 `to` 之间，`step` 不为空时只选择 `from`、`from + step` ...
+    Ord {
+        from: Option<f64>,
+        to: Option<f64>,
+        step: Option<f64>,
+    },
+    Latest(usize),
+    First(usize),
+    Id(u32),
+    Title(String),
+    Unlocked,
+    Free,
+}
+
+impl FromStr for EpisodeRange {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut range = EpisodeRange::default();
+        for term in s.split(',').map(str::trim) {
+            if term.is_empty() {
+                continue;
+            }
+            match term.strip_prefix('!') {
+                Some(excluded) => range.excludes.push(parse_selector(excluded.trim(), term)?),
+                None => range.includes.push(parse_selector(term, term)?),
+            }
+        }
+        Ok(range)
+    }
+}
+
+impl EpisodeRange {
+    /// 是否没有任何条件，此时选择全部章节
+    pub(crate) fn is_empty(&self) -> bool {
+        self.includes.is_empty() && self.excludes.is_empty()
+    }
+
+    /// 选择 `list` 中在范围内的章节，保持原来的顺序
+    ///
+    /// `list` 需要按序号排好，`latest:N` `first:N` 和省略的开始或结束都按列表的顺序计算
+    pub(crate) fn apply<T: Selectable>(&self, list: Vec<T>) -> Result<Vec<T>> {
+        if self.is_empty() || list.is_empty() {
+            return Ok(list);
+        }
+        let mut selected = vec![self.includes.is_empty(); list.len()];
+        for selector in &self.includes {
+            for index in selector.matches(&list)? {
+                selected[index] = true;
+            }
+        }
+        for selector in &self.excludes {
+            for index in selector.matches(&list)? {
+                selected[index] = false;
+            }
+        }
+        Ok(list
+            .into_iter()
+            .zip(selected)
+            .filter_map(|(item, selected)| selected.then_some(item))
+            .collect())
+    }
+}
+
+/// 解析并应用范围，`range` 为空时返回全部章节
+pub(crate) fn apply_range<T: Selectable>(list: Vec<T>, range: &str) -> Result<Vec<T>> {
+    range.parse::<EpisodeRange>()?.apply(list)
+}
+
+impl Selector {
+    /// 匹配的章节在列表中的位置
+    fn matches<T: Selectable>(&self, list: &[T]) -> Result<Vec<usize>> {
+        let indexes = 0..list.len();
+        let matched = match self {
+            Selector::Ord { from, to, step } => {
+                let from = from.unwrap_or_else(|| list[0].ord());
+                let to = to.unwrap_or_else(|| list[list.len() - 1].ord());
+                indexes
+                    .filter(|&i| {
+                        let ord = list[i].ord();
+                        ord >= from
+                            && ord <= to
+                            && step.is_none_or(|step| is_multiple(ord - from, step))
+                    })
+                    .collect()
+            }
+            Selector::Latest(count) => indexes.skip(list.len().saturating_sub(*count)).collect(),
+            Selector::First(count) => indexes.take(*count).collect(),
+            Selector::Id(id) => indexes.filter(|&i| list[i].id() == *id).collect(),
+            Selector::Title(text) => {
+                let text = text.to_lowercase();
+                indexes
+                    .filter(|&i| {
+                        list[i]
+                            .titles()
+                            .iter()
+                            .any(|title| title.to_lowercase().contains(&text))
+                    })
+                    .collect()
+            }
+            Selector::Unlocked => indexes.filter(|&i| !list[i].is_locked()).collect(),
+            Selector::Free => {
+                let mut matched = Vec::new();
+                for i in indexes {
+                    match list[i].is_free() {
+                        Some(true) => matched.push(i),
+                        Some(false) => {}
+                        None => {
+                            return Err(Error::InvalidRange(
+                                "free：缓存中没有章节是否免费的信息，只能用于 fetch check import".to_string(),
+                            ))
+                        }
+                    }
+                }
+                matched
+            }
+        };
+        Ok(matched)
+    }
+}
+
+/// `value` 是否为 `step` 的整数倍，序号可能是 1.5 这样的小数
+fn is_multiple(value: f64, step: f64) -> bool {
+    let times = value / step;
+    (times - times.round()).abs() < 1e-6
+}
+
+/// 解析一项，`term` 为完整的一项，用于错误信息
+fn parse_selector(s: &str, term: &str) -> Result<Selector> {
+    let invalid = |reason: &str| Error::InvalidRange(format!("{}：{}", term, reason));
+    if let Some((key, value)) = s.split_once(':') {
+        let value = value.trim();
+        return match key.trim() {
+            "latest" | "first" => {
+                let count = value
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|count| *count > 0)
+                    .ok_or_else(|| invalid("话数必须是正整数"))?;
+                Ok(if key.trim() == "latest" {
+                    Selector::Latest(count)
+                } else {
+                    Selector::First(count)
+                })
+            }
+            "id" => value
+                .parse()
+                .map(Selector::Id)
+                .map_err(|_| invalid("章节ID必须是整数")),
+            "title" if !value.is_empty() => Ok(Selector::Title(value.to_string())),
+            "title" => Err(invalid("标题不能为空")),
+            _ => Err(invalid("只支持 latest: first: id: title:")),
+        };
+    }
+    match s {
+        "unlocked" => return Ok(Selector::Unlocked),
+        "free" => return Ok(Selector::Free),
+        "" => return Err(invalid("缺少要排除的章节")),
+        _ => {}
+    }
+
+    let (span, step) = match s.split_once('/') {
+        Some((span, step)) => {
+            let step = parse_ord(step)
+                .filter(|step| *step > 0.0)
+                .ok_or_else(|| invalid("间隔必须是正数"))?;
+            (span, Some(step))
+        }
+        None => (s, None),
+    };
+    let (from, to) = match span.split_once('-') {
+        Some((from, to)) => (parse_bound(from, &invalid)?, parse_bound(to, &invalid)?),
+        None => {
+            let ord = parse_ord(span).ok_or_else(|| invalid("不是有效的序号"))?;
+            (Some(ord), Some(ord))
+        }
+    };
+    if let (Some(from), Some(to)) = (from, to) {
+        if from > to {
+            return Err(invalid("开始大于结束"));
+        }
+    }
+    if step.is_some() && !span.contains('-') {
+        return Err(invalid("间隔只能用于范围，如 1-20/2"));
+    }
+    Ok(Selector::Ord { from, to, step })
+}
+
+/// 范围的开始或结束，省略时为空
+fn parse_bound(s: &str, invalid: &dyn Fn(&str) -> Error) -> Result<Option<f64>> {
+    let s = s.trim();
+    if s.is_empty() {
+        return Ok(None);
+    }
+    parse_ord(s)
+        .map(Some)
+        .ok_or_else(|| invalid("不是有效的序号"))
+}
+
+fn parse_ord(s: &str) -> Option<f64> {
+    s.trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|ord| ord.is_finite() && *ord >= 0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::EpisodeCache;
+    use crate::network::EpisodeInfo;
+
+    fn episode(id: u32, ord: f64) -> EpisodeInfo {
+        EpisodeInfo {
+            short_title: ord.to_string(),
+            title: format!("第{}话", ord),
+            id,
+            is_locked: false,
+            is_in_free: false,
+            ord,
+        }
+    }
+
+    fn episodes(ords: &[f64]) -> Vec<EpisodeInfo> {
+        ords.iter()
+            .enumerate()
+            .map(|(i, ord)| episode(100 + i as u32, *ord))
+            .collect()
+    }
+
+    fn select(ords: &[f64], range: &str) -> Vec<f64> {
+        apply_range(episodes(ords), range)
+            .unwrap()
+            .iter()
+            .map(|episode| episode.ord)
+            .collect()
+    }
+
+    fn is_invalid(range: &str) -> bool {
+        matches!(range.parse::<EpisodeRange>(), Err(Error::InvalidRange(_)))
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(is_invalid("5-3"));
+        assert!(is_invalid("5/2"));
+        assert!(is_invalid("latest:0"));
+        assert!(is_invalid("first:-1"));
+        assert!(is_invalid("!"));
+        assert!(is_invalid("1-20/0"));
+        assert!(is_invalid("id:abc"));
+        assert!(is_invalid("title:"));
+        assert!(is_invalid("last:3"));
+        assert!(is_invalid("abc"));
+    }
+
+    #[test]
+    fn empty_range_selects_all() {
+        assert_eq!(select(&[1., 2., 3.], ""), vec![1., 2., 3.]);
+        assert_eq!(select(&[1., 2., 3.], " , "), vec![1., 2., 3.]);
+    }
+
+    #[test]
+    fn open_bounds() {
+        let ords = [1., 2., 3., 4., 5.];
+        assert_eq!(select(&ords, "-2"), vec![1., 2.]);
+        assert_eq!(select(&ords, "4-"), vec![4., 5.]);
+        assert_eq!(select(&ords, "-"), ords.to_vec());
+        assert_eq!(select(&ords, "2-/2"), vec![2., 4.]);
+    }
+
+    #[test]
+    fn step_with_fractional_ords() {
+        let ords = [1., 1.5, 2., 2.5, 3., 4., 5.];
+        assert_eq!(select(&ords, "1-5/1"), vec![1., 2., 3., 4., 5.]);
+        assert_eq!(select(&ords, "1-3/0.5"), vec![1., 1.5, 2., 2.5, 3.]);
+        assert_eq!(select(&ords, "1.5-5/2"), vec![1.5]);
+        // 0.3 - 0.1 不是 0.2 的精确值
+        assert_eq!(select(&[0.1, 0.2, 0.3], "0.1-0.3/0.1"), vec![0.1, 0.2, 0.3]);
+    }
+
+    #[test]
+    fn exclude_only() {
+        let ords = [1., 2., 3., 4., 5.];
+        assert_eq!(select(&ords, "!2,!4-5"), vec![1., 3.]);
+        assert_eq!(select(&ords, "1-4,!2"), vec![1., 3., 4.]);
+        assert_eq!(select(&ords, "!latest:2"), vec![1., 2., 3.]);
+    }
+
+    #[test]
+    fn other_selectors() {
+        let ords = [1., 2., 3., 4., 5.];
+        assert_eq!(select(&ords, "latest:2"), vec![4., 5.]);
+        assert_eq!(select(&ords, "first:2,5"), vec![1., 2., 5.]);
+        assert_eq!(select(&ords, "latest:10"), ords.to_vec());
+        assert_eq!(select(&ords, "id:102"), vec![3.]);
+        assert_eq!(select(&ords, "title:第4话"), vec![4.]);
+    }
+
+    #[test]
+    fn locked_and_free() {
+        let mut list = episodes(&[1., 2., 3.]);
+        list[1].is_locked = true;
+        list[2].is_in_free = true;
+        let ids = |range| {
+            apply_range(list.clone(), range)
+                .unwrap()
+                .iter()
+                .map(|episode| episode.id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ids("unlocked"), vec![100, 102]);
+        assert_eq!(ids("free"), vec![102]);
+    }
+
+    #[test]
+    fn free_on_cache_items() {
+        let cached = EpisodeCache {
+            id: 100,
+            short_title: "1".to_string(),
+            title: "第1话".to_string(),
+            files: Vec::new(),
+            paths: Vec::new(),
+            host: String::new(),
+            ord: 1.,
+            root_dir: Default::default(),
+            variant: Default::default(),
+            exported: None,
+            store: Default::default(),
+            unverified: false,
+            imported: Default::default(),
+        };
+        assert!(matches!(
+            apply_range(vec![&cached], "free"),
+            Err(Error::InvalidRange(_))
+        ));
+        assert!(matches!(
+            apply_range(vec![&cached], "!free"),
+            Err(Error::InvalidRange(_))
+        ));
+        assert_eq!(apply_range(vec![&cached], "1").unwrap().len(), 1);
+    }
+}
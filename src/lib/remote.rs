@@ -0,0 +1,75 @@
+//! 保存在本地的漫画信息
+//!
+//! 每次从网络获取漫画信息后写入缓存目录的 `.remote` 文件夹，
+//! 离线或网络请求失败时用于查看章节和解锁状态
+
+use crate::config::Config;
+use crate::error::Result;
+use crate::network::ComicInfo;
+use crate::write_atomic;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// 保存目录的名称，以 . 开头，加载缓存时会跳过
+const REMOTE_DIR: &str = ".remote";
+
+/// 超过这个时间的信息可能已经过时
+const STALE_AFTER: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// 漫画信息及其获取时间
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RemoteInfo {
+    /// 获取时间 (unix 时间戳)
+    pub fetched: i64,
+    pub comic: ComicInfo,
+    /// 为 true 时信息来自本地保存的副本，而不是刚刚从网络获取
+    #[serde(skip)]
+    pub cached: bool,
+}
+
+impl RemoteInfo {
+    /// 刚刚从网络获取的信息
+    pub(crate) fn fresh(comic: ComicInfo) -> RemoteInfo {
+        RemoteInfo {
+            fetched: chrono::Local::now().timestamp(),
+            comic,
+            cached: false,
+        }
+    }
+
+    /// 距离获取时过了多久
+    pub fn age(&self) -> Duration {
+        let seconds = chrono::Local::now().timestamp() - self.fetched;
+        Duration::from_secs(seconds.max(0) as u64)
+    }
+
+    /// 信息已经超过 7 天，章节和解锁状态可能已经变化
+    pub fn is_stale(&self) -> bool {
+        self.age() > STALE_AFTER
+    }
+}
+
+/// 保存刚刚获取的漫画信息
+pub(crate) fn save(config: &Config, info: &RemoteInfo) -> Result<()> {
+    let path = path_of(config, info.comic.id);
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    write_atomic(path, serde_json::to_string_pretty(info).unwrap().as_bytes())?;
+    Ok(())
+}
+
+/// 读取保存的漫画信息，没有保存或无法解析时返回 `None`
+pub(crate) fn load(config: &Config, id: u32) -> Option<RemoteInfo> {
+    let content = std::fs::read_to_string(path_of(config, id)).ok()?;
+    let mut info: RemoteInfo = serde_json::from_str(&content).ok()?;
+    info.cached = true;
+    Some(info)
+}
+
+fn path_of(config: &Config, id: u32) -> PathBuf {
+    Path::new(&config.cache_dir)
+        .join(REMOTE_DIR)
+        .join(format!("{}.json", id))
+}
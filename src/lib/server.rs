@@ -0,0 +1,622 @@
+//! 本地漫画库服务器
+//!
+//! 提供缓存中漫画的 JSON 接口、图片、一个简单的网页阅读器，
+//! 以及在后台执行 fetch 和 export 任务的接口
+
+use crate::cache::{Cache, ComicCache, EpisodeCache};
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::{opds, CancelToken, Event, Events, ExportOptions, Format, Library};
+use hyper::header::{CONTENT_TYPE, HOST, ORIGIN};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast::error::RecvError;
+
+/// 最多保留的已结束任务数量，超出后移除最早的任务
+const MAX_FINISHED_JOBS: usize = 100;
+/// 通过接口下载时每一话失败后的重试次数，没有人能按 Ctrl+C 停止一直失败的任务
+const FETCH_RETRIES: u32 = 5;
+
+#[derive(Clone)]
+enum JobStatus {
+    Running,
+    Done,
+    /// 已取消，参数为任务记录的ID，可以通过 `bcdown resume` 继续
+    Cancelled(u64),
+    Failed(String),
+}
+
+#[derive(Default)]
+struct JobProgress {
+    /// 任务进度，fetch 以话为单位，export 以文件为单位
+    position: AtomicU64,
+    length: AtomicU64,
+    /// 已下载的字节数
+    bytes: AtomicU64,
+    /// 预计剩余的秒数
+    eta: AtomicU64,
+}
+
+impl JobProgress {
+    fn update(&self, event: Event) {
+        match event {
+            Event::FetchPlanned { episodes, .. } => {
+                self.length.store(episodes.len() as u64, Ordering::Relaxed);
+            }
+            Event::ExportPlanned { files, .. } => self.length.store(files as u64, Ordering::Relaxed),
+            Event::PageDownloaded { bytes, .. } => {
+                self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+            }
+            Event::EpisodeFinished { .. } | Event::ExportFinished { .. } => {
+                self.position.fetch_add(1, Ordering::Relaxed);
+            }
+            Event::Progress { eta, .. } => self.eta.store(eta.as_secs(), Ordering::Relaxed),
+            _ => {}
+        }
+    }
+
+    /// 订阅任务的事件，直到任务结束
+    fn listen(self: Arc<Self>, events: &Events) {
+        let mut receiver = events.subscribe();
+        tokio::spawn(async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(event) => self.update(event),
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+}
+
+struct Job {
+    kind: &'static str,
+    comic_id: u32,
+    status: JobStatus,
+    progress: Arc<JobProgress>,
+    cancel: CancelToken,
+    output: Option<String>,
+}
+
+impl Job {
+    fn to_json(&self, id: u32) -> serde_json::Value {
+        let (status, error, journal) = match &self.status {
+            JobStatus::Running => ("running", None, None),
+            JobStatus::Done => ("done", None, None),
+            JobStatus::Cancelled(journal) => ("cancelled", None, Some(*journal)),
+            JobStatus::Failed(e) => ("failed", Some(e.clone()), None),
+        };
+        json!({
+            "id": id,
+            "kind": self.kind,
+            "comic_id": self.comic_id,
+            "status": status,
+            "error": error,
+            "journal": journal,
+            "position": self.progress.position.load(Ordering::Relaxed),
+            "length": self.progress.length.load(Ordering::Relaxed),
+            "bytes": self.progress.bytes.load(Ordering::Relaxed),
+            "eta": self.progress.eta.load(Ordering::Relaxed),
+            "output": self.output,
+        })
+    }
+}
+
+struct State {
+    library: Library,
+    /// 只提供 OPDS 目录
+    opds_only: bool,
+    jobs: Mutex<BTreeMap<u32, Job>>,
+    next_job: AtomicU32,
+}
+
+impl State {
+    /// 创建任务，返回任务ID、任务的事件通道和取消信号
+    fn add_job(&self, kind: &'static str, comic_id: u32) -> (u32, Events, CancelToken) {
+        let id = self.next_job.fetch_add(1, Ordering::Relaxed);
+        let progress = Arc::new(JobProgress::default());
+        let events = Events::new();
+        let cancel = CancelToken::new();
+        progress.clone().listen(&events);
+        self.jobs.lock().unwrap().insert(
+            id,
+            Job {
+                kind,
+                comic_id,
+                status: JobStatus::Running,
+                progress: progress.clone(),
+                cancel: cancel.clone(),
+                output: None,
+            },
+        );
+        (id, events, cancel)
+    }
+
+    fn finish_job(&self, id: u32, result: Result<Option<String>>) {
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(job) = jobs.get_mut(&id) {
+            match result {
+                Ok(output) => {
+                    job.status = JobStatus::Done;
+                    job.output = output;
+                }
+                Err(Error::Cancelled(journal)) => job.status = JobStatus::Cancelled(journal),
+                Err(e) => job.status = JobStatus::Failed(e.to_string()),
+            }
+        }
+        // 任务ID递增，先移除最早结束的任务
+        let finished = jobs
+            .iter()
+            .filter(|(_, job)| !matches!(job.status, JobStatus::Running))
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in finished.iter().take(finished.len().saturating_sub(MAX_FINISHED_JOBS)) {
+            jobs.remove(id);
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct FetchRequest {
+    id: u32,
+    #[serde(default)]
+    range: String,
+}
+
+#[derive(Deserialize)]
+struct ExportRequest {
+    id: u32,
+    format: String,
+    #[serde(default)]
+    range: String,
+    #[serde(default)]
+    group: usize,
+    #[serde(default)]
+    split: bool,
+    output: Option<String>,
+    profile: Option<String>,
+}
+
+/// 监听 `addr`，返回服务器的 future
+pub(crate) fn serve(
+    library: Library,
+    addr: SocketAddr,
+    opds_only: bool,
+) -> Result<impl Future<Output = Result<()>>> {
+    let state = Arc::new(State {
+        library: library.with_retries(FETCH_RETRIES),
+        opds_only,
+        jobs: Mutex::new(BTreeMap::new()),
+        next_job: AtomicU32::new(1),
+    });
+    let make_svc = make_service_fn(move |_| {
+        let state = state.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let state = state.clone();
+                async move { Ok::<_, Infallible>(handle(state, req).await) }
+            }))
+        }
+    });
+    let server = Server::try_bind(&addr)?.serve(make_svc);
+    Ok(async move { server.await.map_err(Error::from) })
+}
+
+async fn handle(state: Arc<State>, req: Request<Body>) -> Response<Body> {
+    let path = req.uri().path().to_string();
+    let segments = path
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<_>>();
+    if segments.first() == Some(&"opds") {
+        if req.method() != Method::GET {
+            return not_found();
+        }
+        return opds::handle(state.library.config(), &segments[1..], req.uri().query()).await;
+    }
+    if state.opds_only {
+        return not_found();
+    }
+    if req.method() == Method::POST {
+        if let Some(response) = check_post(&req) {
+            return response;
+        }
+    }
+    match (req.method(), segments.as_slice()) {
+        (&Method::GET, []) => html(READER),
+        (&Method::GET, ["api", "comics"]) => {
+            let config = state.library.config().clone();
+            blocking(move || {
+                let cache = Cache::load(&config);
+                let mut comics = cache.comics.values().collect::<Vec<_>>();
+                comics.sort_by(|a, b| a.title.cmp(&b.title));
+                json_response(
+                    StatusCode::OK,
+                    json!(comics.iter().map(|c| comic_summary(c)).collect::<Vec<_>>()),
+                )
+            })
+            .await
+        }
+        (&Method::GET, ["api", "comics", id]) => {
+            let config = state.library.config().clone();
+            let id = id.to_string();
+            blocking(move || match load_comic(&config, &id) {
+                Some(comic) => {
+                    let mut summary = comic_summary(&comic);
+                    summary["episodes"] = json!(sorted_episodes(&comic)
+                        .iter()
+                        .map(|ep| episode_summary(ep))
+                        .collect::<Vec<_>>());
+                    json_response(StatusCode::OK, summary)
+                }
+                None => not_found(),
+            })
+            .await
+        }
+        (&Method::GET, ["api", "comics", id, "cover"]) => match id.parse::<u32>() {
+            Ok(id) => {
+                let path = Path::new(&state.library.config().cache_dir)
+                    .join(id.to_string())
+                    .join("cover.jpg");
+                blocking(move || file_response(&path)).await
+            }
+            Err(_) => not_found(),
+        },
+        (&Method::GET, ["api", "comics", id, "episodes", ep_id]) => {
+            let config = state.library.config().clone();
+            let (id, ep_id) = (id.to_string(), ep_id.to_string());
+            blocking(move || episode_response(&config, &id, &ep_id)).await
+        }
+        (&Method::GET, ["images", id, ep_id, file_name]) => {
+            let config = state.library.config().clone();
+            let (id, ep_id, file_name) = (id.to_string(), ep_id.to_string(), file_name.to_string());
+            blocking(move || {
+                let comic = load_comic(&config, &id);
+                let episode = comic
+                    .as_ref()
+                    .and_then(|c| c.get_episode(ep_id.parse().ok()?));
+                // 只允许访问章节中记录的文件
+                match episode.and_then(|ep| ep.get_paths().into_iter().find(|p| p.ends_with(&file_name)))
+                {
+                    Some(path) => file_response(&path),
+                    None => not_found(),
+                }
+            })
+            .await
+        }
+        (&Method::GET, ["api", "jobs"]) => {
+            let jobs = state.jobs.lock().unwrap();
+            json_response(
+                StatusCode::OK,
+                json!(jobs
+                    .iter()
+                    .map(|(id, job)| job.to_json(*id))
+                    .collect::<Vec<_>>()),
+            )
+        }
+        (&Method::GET, ["api", "jobs", id]) => {
+            let jobs = state.jobs.lock().unwrap();
+            match id.parse().ok().and_then(|id| Some((id, jobs.get(&id)?))) {
+                Some((id, job)) => json_response(StatusCode::OK, job.to_json(id)),
+                None => not_found(),
+            }
+        }
+        (&Method::POST, ["api", "jobs", id, "cancel"]) => {
+            let jobs = state.jobs.lock().unwrap();
+            match id.parse().ok().and_then(|id| Some((id, jobs.get(&id)?))) {
+                Some((id, job)) => {
+                    job.cancel.cancel();
+                    json_response(StatusCode::ACCEPTED, json!({ "id": id }))
+                }
+                None => not_found(),
+            }
+        }
+        (&Method::POST, ["api", "jobs", "fetch"]) => match read_json::<FetchRequest>(req).await {
+            Ok(request) => start_fetch(state, request),
+            Err(response) => response,
+        },
+        (&Method::POST, ["api", "jobs", "export"]) => match read_json::<ExportRequest>(req).await {
+            Ok(request) => start_export(state, request),
+            Err(response) => response,
+        },
+        _ => not_found(),
+    }
+}
+
+fn start_fetch(state: Arc<State>, request: FetchRequest) -> Response<Body> {
+    let (job_id, events, cancel) = state.add_job("fetch", request.id);
+    tokio::spawn(async move {
+        let result = state
+            .library
+            .fetch(request.id, &request.range, &events, &cancel)
+            .await;
+        state.finish_job(job_id, result.map(|_| None));
+    });
+    json_response(StatusCode::ACCEPTED, json!({ "id": job_id }))
+}
+
+fn start_export(state: Arc<State>, request: ExportRequest) -> Response<Body> {
+    let format = match request.format.parse::<Format>() {
+        Ok(format) => format,
+        Err(e) => {
+            return json_response(
+                StatusCode::BAD_REQUEST,
+                json!({ "error": e.to_string() }),
+            )
+        }
+    };
+    let export_dir = match request.output.as_deref().map(|output| output_dir(state.library.config(), output)) {
+        Some(Err(e)) => return error_response(&e),
+        Some(Ok(dir)) => Some(dir),
+        None => None,
+    };
+    let (job_id, events, cancel) = state.add_job("export", request.id);
+    tokio::task::spawn_blocking(move || {
+        let options = ExportOptions {
+            range: request.range,
+            grouping: request.group,
+            split_episodes: request.split,
+            export_dir,
+            format,
+            profile: request.profile,
+            ..Default::default()
+        };
+        let result = state
+            .library
+            .export(request.id, &options, &events, &cancel);
+        state.finish_job(
+            job_id,
+            result.map(|out_dir| Some(out_dir.display().to_string())),
+        );
+    });
+    json_response(StatusCode::ACCEPTED, json!({ "id": job_id }))
+}
+
+/// 一话的信息和图片链接，以及前后两话的ID
+fn episode_response(config: &Config, id: &str, ep_id: &str) -> Response<Body> {
+    let comic = load_comic(config, id);
+    let episode = comic
+        .as_ref()
+        .and_then(|c| c.get_episode(ep_id.parse().ok()?));
+    match (comic.as_ref(), episode) {
+        (Some(comic), Some(ep)) => {
+            let episodes = sorted_episodes(comic);
+            let index = episodes.iter().position(|e| e.id == ep.id).unwrap();
+            let mut summary = episode_summary(ep);
+            summary["prev"] = json!(index.checked_sub(1).map(|i| episodes[i].id));
+            summary["next"] = json!(episodes.get(index + 1).map(|e| e.id));
+            summary["pages"] = json!(ep
+                .get_paths()
+                .iter()
+                .filter(|p| p.is_file())
+                .map(|p| format!(
+                    "/images/{}/{}/{}",
+                    comic.id,
+                    ep.id,
+                    p.file_name().unwrap_or_default().to_string_lossy()
+                ))
+                .collect::<Vec<_>>());
+            json_response(StatusCode::OK, summary)
+        }
+        _ => not_found(),
+    }
+}
+
+/// 在阻塞线程中读取缓存，避免文件读写挡住其他请求
+pub(crate) async fn blocking<F>(f: F) -> Response<Body>
+where
+    F: FnOnce() -> Response<Body> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f).await.unwrap_or_else(|e| {
+        json_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            json!({ "error": e.to_string() }),
+        )
+    })
+}
+
+/// 客户端指定的输出目录只能是默认下载目录下的相对路径，不能包含 `..`
+fn output_dir(config: &Config, output: &str) -> Result<PathBuf> {
+    let path = Path::new(output);
+    let relative = path.components().next().is_some()
+        && path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+    if !relative {
+        return Err(Error::InvalidOutput(output.to_string()));
+    }
+    Ok(Path::new(&config.default_download_dir).join(path))
+}
+
+pub(crate) fn load_comic(config: &Config, id: &str) -> Option<ComicCache> {
+    let path = Path::new(&config.cache_dir).join(id.parse::<u32>().ok()?.to_string());
+    ComicCache::load(path)
+}
+
+pub(crate) fn sorted_episodes(comic: &ComicCache) -> Vec<&EpisodeCache> {
+    let mut episodes = comic.episodes.values().collect::<Vec<_>>();
+    episodes.sort_by(|a, b| a.ord.partial_cmp(&b.ord).unwrap());
+    episodes
+}
+
+fn comic_summary(comic: &ComicCache) -> serde_json::Value {
+    json!({
+        "id": comic.id,
+        "title": comic.title,
+        "cover": format!("/api/comics/{}/cover", comic.id),
+        "episode_count": comic.episodes.len(),
+    })
+}
+
+fn episode_summary(ep: &EpisodeCache) -> serde_json::Value {
+    json!({
+        "id": ep.id,
+        "ord": ep.ord,
+        "short_title": ep.short_title,
+        "title": ep.title,
+        "page_count": ep.paths.len(),
+        "downloaded": ep.not_downloaded_uncheck().is_empty(),
+    })
+}
+
+/// 拒绝其他网页发起的跨域请求，返回拒绝时的响应
+///
+/// 只接受 `application/json`，浏览器会先发送预检请求，而服务器不会允许；
+/// 带有 `Origin` 时必须和 `Host` 一致
+fn check_post(req: &Request<Body>) -> Option<Response<Body>> {
+    let header = |name| {
+        req.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    };
+    let is_json = header(CONTENT_TYPE).is_some_and(|content_type| {
+        content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .eq_ignore_ascii_case("application/json")
+    });
+    if !is_json {
+        return Some(json_response(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            json!({ "error": "Content-Type 必须是 application/json" }),
+        ));
+    }
+    let origin = header(ORIGIN)?;
+    let host = header(HOST).unwrap_or_default();
+    if origin.strip_prefix("http://") != Some(host) {
+        return Some(json_response(
+            StatusCode::FORBIDDEN,
+            json!({ "error": "不接受跨域请求" }),
+        ));
+    }
+    None
+}
+
+async fn read_json<T: serde::de::DeserializeOwned>(
+    req: Request<Body>,
+) -> std::result::Result<T, Response<Body>> {
+    let body = hyper::body::to_bytes(req.into_body())
+        .await
+        .map_err(|e| json_response(StatusCode::BAD_REQUEST, json!({ "error": e.to_string() })))?;
+    serde_json::from_slice(&body)
+        .map_err(|e| json_response(StatusCode::BAD_REQUEST, json!({ "error": e.to_string() })))
+}
+
+pub(crate) fn json_response(status: StatusCode, value: serde_json::Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json; charset=utf-8")
+        .body(Body::from(value.to_string()))
+        .unwrap()
+}
+
+fn html(content: &'static str) -> Response<Body> {
+    Response::builder()
+        .header("Content-Type", "text/html; charset=utf-8")
+        .body(Body::from(content))
+        .unwrap()
+}
+
+pub(crate) fn not_found() -> Response<Body> {
+    json_response(StatusCode::NOT_FOUND, json!({ "error": "not found" }))
+}
+
+/// 把操作失败的原因返回给客户端，缓存被其他进程占用时可以稍后重试
+pub(crate) fn error_response(e: &Error) -> Response<Body> {
+    let status = match e {
+        Error::NotCached(_) => StatusCode::NOT_FOUND,
+        Error::InvalidOutput(_) => StatusCode::BAD_REQUEST,
+        Error::Locked(_) => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    json_response(status, json!({ "error": e.to_string() }))
+}
+
+pub(crate) fn file_response(path: &Path) -> Response<Body> {
+    let mime = match path.extension().and_then(|e| e.to_str()) {
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("png") => "image/png",
+        Some("webp") => "image/webp",
+        Some("avif") => "image/avif",
+        _ => "application/octet-stream",
+    };
+    match std::fs::read(path) {
+        Ok(data) => Response::builder()
+            .header("Content-Type", mime)
+            // 缓存中的图片以哈希命名，内容不会改变
+            .header("Cache-Control", "max-age=31536000, immutable")
+            .body(Body::from(data))
+            .unwrap(),
+        Err(_) => not_found(),
+    }
+}
+
+const READER: &str = r##"<!DOCTYPE html>
+<html lang="zh-CN">
+<head>
+<meta charset="utf-8" />
+<meta name="viewport" content="width=device-width, initial-scale=1" />
+<title>bcdown</title>
+<style>
+body { margin: 0; background: #222; color: #ddd; font-family: sans-serif; }
+a { color: #8cf; text-decoration: none; }
+header { position: sticky; top: 0; display: flex; gap: 12px; padding: 8px 12px; background: #111; }
+header .title { flex: 1; overflow: hidden; white-space: nowrap; text-overflow: ellipsis; }
+.grid { display: grid; grid-template-columns: repeat(auto-fill, minmax(150px, 1fr)); gap: 16px; padding: 16px; }
+.grid img { width: 100%; aspect-ratio: 3 / 4; object-fit: cover; background: #333; }
+.list { max-width: 640px; margin: 0 auto; padding: 16px; }
+.list li { margin: 6px 0; }
+.missing { color: #f88; }
+.pages img { display: block; margin: 0 auto; max-width: 100%; }
+</style>
+</head>
+<body>
+<header><a href="#/">漫画库</a><span class="title" id="title"></span><span id="nav"></span></header>
+<main id="main"></main>
+<script>
+const main = document.getElementById("main");
+const title = document.getElementById("title");
+const nav = document.getElementById("nav");
+const escape = (s) => String(s).replace(/[&<>"]/g, (c) => ({ "&": "&amp;", "<": "&lt;", ">": "&gt;", '"': "&quot;" })[c]);
+async function get(url) { return (await fetch(url)).json(); }
+async function route() {
+  const parts = location.hash.replace(/^#\/?/, "").split("/").filter((p) => p);
+  nav.innerHTML = "";
+  window.scrollTo(0, 0);
+  if (parts.length === 0) {
+    const comics = await get("/api/comics");
+    title.textContent = "";
+    main.innerHTML = '<div class="grid">' + comics.map((c) =>
+      `<a href="#/${c.id}"><img loading="lazy" src="${c.cover}" alt="" /><div>${escape(c.title)}</div></a>`).join("") + "</div>";
+  } else if (parts.length === 1) {
+    const comic = await get(`/api/comics/${parts[0]}`);
+    title.textContent = comic.title;
+    main.innerHTML = '<ol class="list">' + comic.episodes.map((e) =>
+      `<li><a class="${e.downloaded ? "" : "missing"}" href="#/${comic.id}/${e.id}">${escape(e.short_title)} ${escape(e.title)}</a></li>`).join("") + "</ol>";
+  } else {
+    const ep = await get(`/api/comics/${parts[0]}/episodes/${parts[1]}`);
+    title.textContent = `${ep.short_title} ${ep.title}`;
+    nav.innerHTML = (ep.prev ? `<a href="#/${parts[0]}/${ep.prev}">上一话</a> ` : "") +
+      `<a href="#/${parts[0]}">目录</a>` + (ep.next ? ` <a href="#/${parts[0]}/${ep.next}">下一话</a>` : "");
+    main.innerHTML = '<div class="pages">' + ep.pages.map((p, i) =>
+      `<img src="${p}" loading="${i < 2 ? "eager" : "lazy"}" alt="${i + 1}" />`).join("") + "</div>";
+  }
+}
+window.addEventListener("hashchange", route);
+route();
+</script>
+</body>
+</html>
+"##;
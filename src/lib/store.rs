@@ -0,0 +1,190 @@
+//! 按内容寻址的图片存储
+//!
+//! 图片保存在缓存目录下的 `.store` 中，按文件名的前两个字符分目录。
+//! 原图的文件名就是它的 sha1，缩放或转换后的文件名由原图和尺寸决定，
+//! 所以不同章节中相同的图片只保存一份，章节目录中只保留记录图片列表的 meta.toml
+
+use crate::cache::Cache;
+use crate::config::Config;
+use crate::error::Result;
+use crate::{lock, write_atomic};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// 存储目录的名称，以 . 开头，加载缓存时会跳过
+pub(crate) const STORE_DIR: &str = ".store";
+
+/// 章节目录对应的存储目录，章节目录为 `<cache_dir>/<漫画ID>/<章节ID>`
+pub(crate) fn dir_of(episode_dir: &Path) -> PathBuf {
+    episode_dir
+        .parent()
+        .and_then(Path::parent)
+        .unwrap_or(episode_dir)
+        .join(STORE_DIR)
+}
+
+/// 图片在存储中的路径
+pub(crate) fn blob_path(store: &Path, name: &str) -> PathBuf {
+    let prefix = name.get(..2).unwrap_or(name);
+    store.join(prefix).join(name)
+}
+
+/// 存储目录中记录布局版本的文件，存在时说明旧版本的缓存已经迁移
+const VERSION_FILE: &str = "version";
+
+/// 当前的存储布局版本
+const VERSION: &str = "1";
+
+/// 把旧版本缓存中直接放在章节目录里的图片移动到存储中，只在第一次使用新版本时执行
+///
+/// 迁移会移动多部漫画的文件，需要整个缓存目录的独占锁，调用时不能持有其他锁。
+/// 有图片移动失败时不会记录版本，下次运行时会重新迁移
+pub(crate) fn migrate(config: &Config) -> Result<()> {
+    let store = Path::new(&config.cache_dir).join(STORE_DIR);
+    if is_migrated(&store) {
+        return Ok(());
+    }
+    let _lock = lock::exclusive(config)?;
+    // 等待锁时其他进程可能已经完成了迁移
+    if is_migrated(&store) {
+        return Ok(());
+    }
+    let mut failed = None;
+    for comic_dir in dirs(Path::new(&config.cache_dir))? {
+        for episode_dir in dirs(&comic_dir)? {
+            // 继续迁移其他章节，只返回第一个错误
+            if let Err(e) = migrate_episode(&episode_dir, &store) {
+                failed.get_or_insert(io::Error::new(
+                    e.kind(),
+                    format!("迁移 {} 中的图片失败：{}", episode_dir.display(), e),
+                ));
+            }
+        }
+    }
+    if let Some(e) = failed {
+        return Err(e.into());
+    }
+    fs::create_dir_all(&store)?;
+    write_atomic(store.join(VERSION_FILE), VERSION.as_bytes())?;
+    Ok(())
+}
+
+fn is_migrated(store: &Path) -> bool {
+    store.join(VERSION_FILE).is_file()
+}
+
+/// 名称为数字的子目录，即漫画或章节的目录，`path` 不存在时为空
+fn dirs(path: &Path) -> io::Result<Vec<PathBuf>> {
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut dirs = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        let numeric = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.parse::<u32>().is_ok());
+        if numeric && path.is_dir() {
+            dirs.push(path);
+        }
+    }
+    Ok(dirs)
+}
+
+/// 移动一话的图片，存储中已有的图片直接删除
+fn migrate_episode(episode_dir: &Path, store: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(episode_dir)? {
+        let path = entry?.path();
+        if !path.is_file() || !crate::cache::is_image(&path) {
+            continue;
+        }
+        let name = path.file_name().unwrap().to_string_lossy().to_string();
+        let target = blob_path(store, &name);
+        if target.is_file() {
+            fs::remove_file(&path)?;
+            continue;
+        }
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // 缓存目录在其他磁盘上时无法直接移动，复制完成后再删除原来的图片
+        if fs::rename(&path, &target).is_err() {
+            write_atomic(&target, &fs::read(&path)?)?;
+            fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+/// 每张图片被多少话引用，同一话中重复的图片只计一次
+pub(crate) fn references(cache: &Cache) -> HashMap<String, usize> {
+    let mut references = HashMap::new();
+    for episode in cache.comics.values().flat_map(|comic| comic.episodes.values()) {
+        for name in episode.blob_names() {
+            *references.entry(name).or_insert(0) += 1;
+        }
+    }
+    references
+}
+
+/// 图片的大小 (字节)，不存在时为0
+pub(crate) fn blob_size(store: &Path, name: &str) -> u64 {
+    fs::metadata(blob_path(store, name))
+        .map(|meta| meta.len())
+        .unwrap_or(0)
+}
+
+/// 删除 `names` 中没有被任何一话引用的图片，返回释放的空间 (字节)
+///
+/// 只检查给定的图片，其他进程正在下载、还没有记录到 meta.toml 中的图片不会被删除
+pub(crate) fn release(config: &Config, names: &[String]) -> Result<u64> {
+    let store = Path::new(&config.cache_dir).join(STORE_DIR);
+    let referenced = references(&Cache::load(config));
+    let mut freed = 0;
+    for name in names.iter().filter(|name| !referenced.contains_key(*name)) {
+        let path = blob_path(&store, name);
+        if path.is_file() {
+            freed += blob_size(&store, name);
+            fs::remove_file(&path)?;
+        }
+    }
+    Ok(freed)
+}
+
+/// 删除没有被任何一话引用的图片，返回释放的空间 (字节)
+///
+/// `excluded` 中的章节目录不计入引用，用于只删除图片而保留 meta.toml 的章节
+pub(crate) fn sweep(config: &Config, excluded: &[PathBuf]) -> Result<u64> {
+    let store = Path::new(&config.cache_dir).join(STORE_DIR);
+    if !store.is_dir() {
+        return Ok(0);
+    }
+    let referenced = Cache::load(config)
+        .comics
+        .values()
+        .flat_map(|comic| comic.episodes.values())
+        .filter(|episode| !excluded.contains(&episode.root_dir))
+        .flat_map(|episode| episode.blob_names())
+        .collect::<HashSet<_>>();
+    let mut freed = 0;
+    for prefix in fs::read_dir(&store)?.flatten().map(|entry| entry.path()) {
+        if !prefix.is_dir() {
+            continue;
+        }
+        for path in fs::read_dir(&prefix)?.flatten().map(|entry| entry.path()) {
+            let name = path.file_name().unwrap().to_string_lossy().to_string();
+            if path.is_file() && !referenced.contains(&name) {
+                freed += fs::metadata(&path).map(|meta| meta.len()).unwrap_or(0);
+                fs::remove_file(&path)?;
+            }
+        }
+        // 空目录一并删除，失败说明目录中还有图片
+        fs::remove_dir(&prefix).ok();
+    }
+    Ok(freed)
+}
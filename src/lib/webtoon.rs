@@ -0,0 +1,233 @@
+//! 条漫的拼接与重新切分
+//!
+//! 将一话的全部图片按顺序拼接成一整条，再按照目标宽高比切分成页面。
+//! 切分点优先选在分镜之间的留白处，避免把一个分镜切成两半
+
+use crate::error::{Error, Result};
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImage, RgbImage};
+
+/// 同一行像素的亮度差小于该值时视为留白
+const GUTTER_TOLERANCE: u8 = 12;
+/// 切分点最多向前回退的比例，超出后直接在目标高度处切分
+const SEARCH_WINDOW: f64 = 0.4;
+/// 最后一页的高度小于该比例时并入上一页
+const MIN_LAST_PAGE: f64 = 0.2;
+/// 切分后页面的最小高度
+const MIN_PAGE_HEIGHT: f64 = 2.;
+
+/// 拼接并重新切分，`ratio` 为页面的高宽比
+pub fn restrip(images: Vec<DynamicImage>, ratio: f64) -> Result<Vec<DynamicImage>> {
+    if images.is_empty() {
+        return Ok(images);
+    }
+    let strip = stitch(images);
+    let width = strip.width();
+    let target = (width as f64 * ratio).round();
+    if target.is_nan() || target < MIN_PAGE_HEIGHT {
+        return Err(Error::Export(format!(
+            "条漫的高宽比 {} 太小，宽 {} 像素的图片切分后不足 {} 像素高",
+            ratio, width, MIN_PAGE_HEIGHT
+        )));
+    }
+    let cuts = cuts(&find_gutters(&strip), target as u32);
+
+    let strip = DynamicImage::ImageRgb8(strip);
+    Ok(cuts
+        .into_iter()
+        .map(|(top, bottom)| strip.crop_imm(0, top, width, bottom - top))
+        .collect())
+}
+
+/// 计算每一页的起止行
+fn cuts(gutters: &[bool], target: u32) -> Vec<(u32, u32)> {
+    let height = gutters.len() as u32;
+    let mut cuts = Vec::new();
+    let mut top = 0;
+    while height - top > target {
+        let ideal = top + target;
+        let lowest = top + ((1. - SEARCH_WINDOW) * target as f64) as u32;
+        // 每次至少前进一行，否则目标高度很小时可能原地不动
+        let cut = best_cut(gutters, lowest, ideal).unwrap_or(ideal).max(top + 1);
+        cuts.push((top, cut));
+        top = cut;
+    }
+    if top < height {
+        let rest = height - top;
+        match cuts.last_mut() {
+            Some(last) if (rest as f64) < target as f64 * MIN_LAST_PAGE => last.1 = height,
+            _ => cuts.push((top, height)),
+        }
+    }
+    cuts
+}
+
+/// 统一宽度后纵向拼接
+fn stitch(images: Vec<DynamicImage>) -> RgbImage {
+    // 以出现次数最多的宽度为准，个别宽度不同的图片会被缩放
+    let mut widths = images.iter().map(|img| img.width()).collect::<Vec<_>>();
+    widths.sort_unstable();
+    let width = widths
+        .iter()
+        .max_by_key(|w| widths.iter().filter(|x| x == w).count())
+        .copied()
+        .unwrap();
+
+    let images = images
+        .into_iter()
+        .map(|img| {
+            if img.width() == width {
+                img.to_rgb8()
+            } else {
+                let height = (img.height() as f64 * width as f64 / img.width() as f64).round();
+                img.resize_exact(width, (height as u32).max(1), FilterType::Lanczos3)
+                    .to_rgb8()
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let height = images.iter().map(|img| img.height()).sum();
+    let mut strip = RgbImage::new(width, height);
+    let mut y = 0;
+    for img in images {
+        strip.copy_from(&img, 0, y).unwrap();
+        y += img.height();
+    }
+    strip
+}
+
+/// 标记每一行是否为留白
+fn find_gutters(strip: &RgbImage) -> Vec<bool> {
+    (0..strip.height())
+        .map(|y| {
+            let mut min = u8::MAX;
+            let mut max = u8::MIN;
+            for x in 0..strip.width() {
+                let [r, g, b] = strip.get_pixel(x, y).0;
+                let luma = ((r as u32 * 299 + g as u32 * 587 + b as u32 * 114) / 1000) as u8;
+                min = min.min(luma);
+                max = max.max(luma);
+                if max - min > GUTTER_TOLERANCE {
+                    return false;
+                }
+            }
+            true
+        })
+        .collect()
+}
+
+/// 在 `[lowest, ideal]` 内寻找离 `ideal` 最近的留白，返回留白的中线
+fn best_cut(gutters: &[bool], lowest: u32, ideal: u32) -> Option<u32> {
+    let mut y = ideal as usize;
+    // 目标位置正好落在留白中时，向下延伸找到整段留白
+    while y + 1 < gutters.len() && gutters[y] && gutters[y + 1] {
+        y += 1;
+    }
+    let mut bottom = None;
+    loop {
+        if gutters[y] {
+            if bottom.is_none() {
+                bottom = Some(y);
+            }
+        } else if let Some(bottom) = bottom {
+            // 留白的中线不能超过目标高度
+            return Some((((y + 1 + bottom) / 2) as u32).min(ideal));
+        }
+        if y as u32 <= lowest {
+            return bottom.map(|bottom| (((y + bottom) / 2) as u32).min(ideal));
+        }
+        y -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgb;
+
+    /// `true` 为留白，`false` 为内容
+    fn rows(spec: &str) -> Vec<bool> {
+        spec.chars().map(|c| c == '_').collect()
+    }
+
+    #[test]
+    fn best_cut_without_gutters() {
+        assert_eq!(best_cut(&rows("##########"), 4, 8), None);
+    }
+
+    #[test]
+    fn best_cut_gutter_at_ideal() {
+        // 目标位置在留白中时取整段留白的中线
+        assert_eq!(best_cut(&rows("######____"), 4, 8), Some(7));
+        // 中线超过目标高度时取目标高度
+        assert_eq!(best_cut(&rows("#######___"), 4, 7), Some(7));
+        // 目标位置不是留白时向上寻找
+        assert_eq!(best_cut(&rows("######__##"), 4, 8), Some(6));
+    }
+
+    #[test]
+    fn best_cut_gutter_at_lowest() {
+        assert_eq!(best_cut(&rows("####_#####"), 4, 8), Some(4));
+        assert_eq!(best_cut(&rows("###_######"), 4, 8), None);
+    }
+
+    #[test]
+    fn best_cut_target_one() {
+        // lowest == ideal 且两行都是留白时中线就是起点
+        assert_eq!(best_cut(&rows("__________"), 3, 3), Some(3));
+    }
+
+    #[test]
+    fn cuts_always_advance() {
+        let gutters = rows("__________");
+        let cuts = cuts(&gutters, 1);
+        assert_eq!(cuts.len(), 10);
+        assert!(cuts.iter().all(|(top, bottom)| bottom > top));
+        assert_eq!(cuts.last().unwrap().1, 10);
+    }
+
+    #[test]
+    fn cuts_merge_short_last_page() {
+        assert_eq!(cuts(&rows("#".repeat(21).as_str()), 10), vec![(0, 10), (10, 21)]);
+        assert_eq!(cuts(&rows("#".repeat(25).as_str()), 10), vec![(0, 10), (10, 20), (20, 25)]);
+    }
+
+    /// 宽 10 像素，`spec` 中的每个字符为一行
+    fn strip(spec: &str) -> DynamicImage {
+        let mut img = RgbImage::from_pixel(10, spec.len() as u32, Rgb([255, 255, 255]));
+        for (y, c) in spec.chars().enumerate() {
+            if c == '#' {
+                img.put_pixel(0, y as u32, Rgb([0, 0, 0]));
+            }
+        }
+        DynamicImage::ImageRgb8(img)
+    }
+
+    #[test]
+    fn restrip_cuts_at_gutters() {
+        let images = vec![strip("########__"), strip("######__##"), strip("##########")];
+        let heights = restrip(images, 1.)
+            .unwrap()
+            .iter()
+            .map(|img| img.height())
+            .collect::<Vec<_>>();
+        assert_eq!(heights, vec![8, 8, 10, 4]);
+    }
+
+    #[test]
+    fn restrip_without_gutters() {
+        let heights = restrip(vec![strip(&"#".repeat(30))], 1.)
+            .unwrap()
+            .iter()
+            .map(|img| img.height())
+            .collect::<Vec<_>>();
+        assert_eq!(heights, vec![10, 10, 10]);
+    }
+
+    #[test]
+    fn restrip_rejects_tiny_ratio() {
+        assert!(restrip(vec![strip("####")], 0.1).is_err());
+        assert!(restrip(vec![strip("####")], 0.001).is_err());
+        assert_eq!(restrip(vec![strip("####")], 0.2).unwrap().len(), 2);
+    }
+}
@@ -1,6 +1,6 @@
 use clap::{Arg, Command};
 
-mod lib;
+mod cli;
 
 #[tokio::main]
 async fn main() {
@@ -29,13 +29,138 @@ async fn main() {
                 ),
         )
         .subcommand(Command::new("info").about("获取工具信息，包括配置信息和缓存信息"))
-        .subcommand(Command::new("clear").about("清除缓存"))
+        .subcommand(
+            Command::new("clear")
+                .about("清除缓存，不指定漫画时清空整个缓存目录")
+                .arg(
+                    Arg::new("id_or_link")
+                        .help("只清除这部漫画的缓存，漫画ID或者链接")
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("range")
+                        .value_name("RANGE")
+                        .allow_hyphen_values(true)
+                        .long("range")
+                        .short('r')
+                        .requires("id_or_link")
+                        .help("只清除这些章节，如1-3,5,7-"),
+                )
+                .arg(
+                    Arg::new("keep_meta")
+                        .long("keep-meta")
+                        .requires("id_or_link")
+                        .help("只删除图片，保留 meta.toml，重新下载时不需要再获取图片列表"),
+                ),
+        )
+        .subcommand(
+            Command::new("cache")
+                .about("管理缓存")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("prune")
+                        .about("按条件清理缓存，至少需要指定一个条件")
+                        .arg(
+                            Arg::new("keep")
+                                .long("keep")
+                                .value_name("N")
+                                .help("每部漫画只保留最新的 N 话"),
+                        )
+                        .arg(
+                            Arg::new("exported_days")
+                                .long("exported-days")
+                                .value_name("DAYS")
+                                .help("删除导出超过 DAYS 天的章节"),
+                        )
+                        .arg(
+                            Arg::new("unwatched")
+                                .long("unwatched")
+                                .help("删除不在关注列表 (配置文件中的 watchlist) 中的漫画"),
+                        )
+                        .arg(
+                            Arg::new("max_size")
+                                .long("max-size")
+                                .value_name("SIZE")
+                                .help("缓存大小上限，如 10G，超出时先删除最久没有下载或导出的章节"),
+                        )
+                        .arg(
+                            Arg::new("dry_run")
+                                .short('n')
+                                .long("dry-run")
+                                .help("只列出会删除的内容和释放的空间，不删除文件"),
+                        ),
+                )
+                .subcommand(
+                    Command::new("export")
+                        .about("把缓存中的漫画打包，用于复制到其他电脑")
+                        .arg(
+                            Arg::new("id_or_link")
+                                .value_name("ID_OR_LINK")
+                                .help("漫画的ID或者链接")
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::new("range")
+                                .value_name("RANGE")
+                                .allow_hyphen_values(true)
+                                .long("range")
+                                .short('r')
+                                .help("只打包这些章节，如1-3,5,7-"),
+                        )
+                        .arg(
+                            Arg::new("output")
+                                .value_name("FILE")
+                                .long("output")
+                                .short('o')
+                                .help("缓存包的路径，默认保存到下载目录中的 <漫画标题>.bcdown"),
+                        ),
+                )
+                .subcommand(
+                    Command::new("import")
+                        .about("把缓存包合并到缓存中，已有的图片会被跳过")
+                        .arg(
+                            Arg::new("file")
+                                .value_name("FILE")
+                                .help("缓存包的路径")
+                                .required(true),
+                        ),
+                ),
+        )
         .subcommand(Command::new("list").about("获取本地缓存的漫画列表")
             .arg(
             Arg::new("id_or_link")
                 .value_name("ID_OR_LINK")
                 .help("可选：漫画的ID或者链接，未指定则输出所有列表")
                 .required(false),
+            )
+            .arg(
+                Arg::new("filter")
+                    .long("filter")
+                    .short('f')
+                    .value_name("STATE")
+                    .possible_values(["downloaded", "partial", "missing", "locked"])
+                    .use_value_delimiter(true)
+                    .multiple_occurrences(true)
+                    .help("只列出这些状态的章节，多个状态用逗号隔开。missing 和 locked 需要保存过漫画信息"),
+            )
+            .arg(
+                Arg::new("sort")
+                    .long("sort")
+                    .short('s')
+                    .value_name("KEY")
+                    .possible_values(["id", "title", "updated", "size"])
+                    .help("排序方式，updated 和 size 从大到小排列，默认按ID"),
+            )
+            .arg(
+                Arg::new("remote")
+                    .long("remote")
+                    .help("获取最新的漫画信息，显示比缓存更新的章节数量"),
+            )
+            .arg(
+                Arg::new("offline")
+                    .long("offline")
+                    .requires("remote")
+                    .help("不访问网络，与上次获取时保存的漫画信息比较"),
             ),
         )
         .subcommand(
@@ -45,6 +170,40 @@ async fn main() {
                     Arg::new("id_or_link")
                         .value_name("ID_OR_LINK")
                         .help("漫画的ID或者链接"),
+                )
+                .arg(
+                    Arg::new("offline")
+                        .long("offline")
+                        .help("不访问网络，使用上次获取时保存的漫画信息"),
+                ),
+        )
+        .subcommand(
+            Command::new("import")
+                .about("把其他工具下载的文件夹或压缩包导入为某个漫画的缓存")
+                .arg(
+                    Arg::new("id_or_link")
+                        .value_name("ID_OR_LINK")
+                        .help("漫画的ID或者链接")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("path")
+                        .value_name("PATH")
+                        .help("文件夹或 zip / cbz 压缩包，每个子文件夹或压缩包是一话")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("by_order")
+                        .long("by-order")
+                        .help("按名称排序后依次对应章节，默认按名称中的数字对应章节的序号"),
+                )
+                .arg(
+                    Arg::new("range")
+                        .value_name("RANGE")
+                        .allow_hyphen_values(true)
+                        .long("range")
+                        .short('r')
+                        .help("只导入到这些章节，如1-3,5,7-"),
                 ),
         )
         .subcommand(
@@ -58,9 +217,31 @@ async fn main() {
                 .arg(
                     Arg::new("range")
                         .value_name("RANGE")
+                        .allow_hyphen_values(true)
                         .long("range")
                         .short('r')
-                        .help("指定下载范围，如1-3,5,7-"),
+                        .help("指定下载范围，如 1-3,5,7- latest:3 !10-12 unlocked，完整格式见 README"),
+                )
+                .arg(
+                    Arg::new("limit")
+                        .long("limit")
+                        .value_name("LIMIT")
+                        .help("下载限速，如 2M 512K，0 为不限速，会覆盖配置文件中的限速"),
+                )
+                .arg(
+                    Arg::new("quality")
+                        .long("quality")
+                        .short('q')
+                        .value_name("QUALITY")
+                        .possible_values(["original", "high", "medium", "low"])
+                        .help("图片尺寸，high medium low 分别限制宽度为 1600 1100 660 像素，与缓存不同时会重新下载"),
+                )
+                .arg(
+                    Arg::new("image_format")
+                        .long("image-format")
+                        .value_name("IMAGE_FORMAT")
+                        .possible_values(["original", "jpg", "webp"])
+                        .help("图片格式，与缓存不同时会重新下载"),
                 ),
         )
         .subcommand(
@@ -71,6 +252,19 @@ async fn main() {
                         .value_name("ID_OR_LINK")
                         .help("漫画的ID或者链接"),
                 )
+                .arg(
+                    Arg::new("range")
+                        .value_name("RANGE")
+                        .allow_hyphen_values(true)
+                        .long("range")
+                        .short('r')
+                        .help("只校验这些章节，如1-3,5,7-"),
+                )
+                .arg(
+                    Arg::new("offline")
+                        .long("offline")
+                        .help("不访问网络，使用上次获取时保存的漫画信息"),
+                )
         )
         .subcommand(
             Command::new("export")
@@ -85,7 +279,7 @@ async fn main() {
                         .value_name("FORMAT")
                         .short('f')
                         .long("format")
-                        .help("导出的格式，epub | pdf | zip | vol.zip"),
+                        .help("导出的格式，epub | pdf | zip | vol.zip | html"),
                 )
                 // .arg(
                 //     Arg::new("from")
@@ -104,9 +298,10 @@ async fn main() {
                 .arg(
                     Arg::new("range")
                         .value_name("RANGE")
+                        .allow_hyphen_values(true)
                         .long("range")
                         .short('r')
-                        .help("指定导出范围，如1-3,5,7-"),
+                        .help("指定导出范围，如 1-3,5,7- latest:3 !10-12，完整格式见 README"),
                 )
                 .arg(
                     Arg::new("split")
@@ -129,6 +324,102 @@ async fn main() {
                         .value_name("GROUP")
                         .required(false)
                         .help("分组导出每组包含的章节数量"),
+                )
+                .arg(
+                    Arg::new("profile")
+                        .long("profile")
+                        .short('p')
+                        .value_name("PROFILE")
+                        .required(false)
+                        .help("导出时使用的设备配置，如kindle, kobo-clara，或config.toml中自定义的配置"),
+                )
+                .arg(
+                    Arg::new("webtoon")
+                        .long("webtoon")
+                        .short('w')
+                        .value_name("RATIO")
+                        .min_values(0)
+                        .required(false)
+                        .help("条漫模式，将每一话拼接后在分镜间的留白处重新切分为指定高宽比的页面，默认使用设备屏幕或A4的比例"),
+                )
+                .arg(
+                    Arg::new("spread")
+                        .long("spread")
+                        .value_name("SPREAD")
+                        .possible_values(["keep", "split", "rotate"])
+                        .required(false)
+                        .help("跨页图片的处理方式，keep 保持 | split 拆分为两页 | rotate 旋转为纵向"),
+                )
+                .arg(
+                    Arg::new("direction")
+                        .long("direction")
+                        .value_name("DIRECTION")
+                        .possible_values(["ltr", "rtl"])
+                        .required(false)
+                        .help("阅读方向，决定跨页拆分后的页面顺序，ltr 从左到右 | rtl 从右到左"),
+                ),
+        )
+        .subcommand(
+            Command::new("resume")
+                .about("继续被取消或中断的下载、导出任务")
+                .arg(
+                    Arg::new("job")
+                        .value_name("JOB")
+                        .help("任务ID，默认为最近的一个任务"),
+                )
+                .arg(
+                    Arg::new("list")
+                        .short('l')
+                        .long("list")
+                        .help("列出所有未完成的任务"),
+                )
+                .arg(
+                    Arg::new("discard")
+                        .short('d')
+                        .long("discard")
+                        .help("放弃任务，已经下载或导出的文件会保留"),
+                )
+                .arg(
+                    Arg::new("limit")
+                        .long("limit")
+                        .value_name("LIMIT")
+                        .help("下载限速，如 2M 512K，0 为不限速，会覆盖配置文件中的限速"),
+                ),
+        )
+        .subcommand(
+            Command::new("serve")
+                .about("启动本地漫画库服务器，提供网页阅读器和下载、导出接口")
+                .arg(
+                    Arg::new("host")
+                        .long("host")
+                        .value_name("HOST")
+                        .default_value("127.0.0.1")
+                        .help("监听的地址"),
+                )
+                .arg(
+                    Arg::new("port")
+                        .long("port")
+                        .value_name("PORT")
+                        .default_value("3030")
+                        .help("监听的端口"),
+                ),
+        )
+        .subcommand(
+            Command::new("opds")
+                .about("只启动 OPDS 目录，供 KOReader、Panels 等阅读器浏览和下载本地漫画")
+                .arg(
+                    Arg::new("host")
+                        .long("host")
+                        .value_name("HOST")
+                        .default_value("127.0.0.1")
+                        .help("监听的地址"),
+                )
+                .arg(
+                    Arg::new("port")
+                        .long("port")
+                        .value_name("PORT")
+                        .default_value("3030")
+                        .help("监听的端口"),
                 ),
         );
     let matches = cmd.get_matches();
@@ -141,26 +432,74 @@ async fn main() {
 
             if matches.is_present("sessdata") {
                 if let Some(sessdata) = matches.value_of("sessdata") {
-                    lib::login(lib::LoginMethod::SESSDATA(sessdata.to_string())).await
+                    cli::login(cli::LoginMethod::SESSDATA(sessdata.to_string())).await
                 } else {
                     log.error("缺少SESSDATA参数");
                     log.info("使用bcdown login -s <SESSDATA> 来登录");
                 }
             } else if matches.is_present("qrcode") {
-                lib::login(lib::LoginMethod::QRCODE).await
+                cli::login(cli::LoginMethod::QRCODE).await
             } else {
-                lib::show_login_info().await
+                cli::show_login_info().await
             }
         }
         Some(("info", _)) => {
-            lib::info().await;
+            cli::info().await;
         }
-        Some(("clear", _)) => {
-            lib::clear();
+        Some(("clear", matches)) => {
+            match matches.value_of("id_or_link") {
+                Some(id_or_link) => cli::clear_comic(
+                    id_or_link.to_string(),
+                    matches.value_of("range").unwrap_or_default().to_string(),
+                    matches.is_present("keep_meta"),
+                ),
+                None => cli::clear(),
+            }
         }
+        Some(("cache", matches)) => match matches.subcommand() {
+            Some(("prune", matches)) => {
+                let keep = match matches.value_of("keep").map(|keep| keep.parse::<usize>()) {
+                    None => None,
+                    Some(Ok(keep)) => Some(keep),
+                    Some(Err(_)) => {
+                        log.error("保留的话数必须是整数");
+                        return;
+                    }
+                };
+                let exported_days = match matches.value_of("exported_days").map(|days| days.parse::<u64>()) {
+                    None => None,
+                    Some(Ok(days)) => Some(days),
+                    Some(Err(_)) => {
+                        log.error("天数必须是整数");
+                        return;
+                    }
+                };
+                cli::prune(
+                    keep,
+                    exported_days,
+                    matches.is_present("unwatched"),
+                    matches.value_of("max_size").map(str::to_string),
+                    matches.is_present("dry_run"),
+                );
+            }
+            Some(("export", matches)) => cli::export_bundle(
+                matches.value_of("id_or_link").unwrap().to_string(),
+                matches.value_of("range").unwrap_or_default().to_string(),
+                matches.value_of("output").map(Into::into),
+            ),
+            Some(("import", matches)) => {
+                cli::import_bundle(matches.value_of("file").unwrap().into())
+            }
+            _ => {}
+        },
         Some(("check", matches)) => {
             if let Some(id_or_link) = matches.value_of("id_or_link") {
-                lib::check(id_or_link.to_owned()).await;
+                cli::check(
+                    id_or_link.to_owned(),
+                    matches.value_of("range").unwrap_or_default().to_string(),
+                    matches.is_present("offline"),
+                )
+                .await;
             } else {
                 log.error("缺少漫画的ID或者链接");
                 log.info("使用bcdown search <ID_OR_LINK> 来搜索漫画");
@@ -171,15 +510,21 @@ async fn main() {
             }
         }
         Some(("list", matches)) => {
-            if let Some(id_or_link) = matches.value_of("id_or_link") {
-                lib::list_by_id(id_or_link.to_owned()).await;
-            } else {
-                lib::list().await;
-            }
+            let options = bcdown::ListOptions {
+                states: matches
+                    .values_of("filter")
+                    .map(|states| states.map(|state| state.parse().unwrap()).collect())
+                    .unwrap_or_default(),
+                sort: matches.value_of("sort").map(|sort| sort.parse().unwrap()).unwrap_or_default(),
+                remote: matches.is_present("remote"),
+                offline: matches.is_present("offline"),
+                ..Default::default()
+            };
+            cli::list(matches.value_of("id_or_link").map(str::to_string), options).await;
         }
         Some(("search", matches)) => {
             if let Some(id_or_link) = matches.value_of("id_or_link") {
-                lib::search(id_or_link.to_owned()).await;
+                cli::search(id_or_link.to_owned(), matches.is_present("offline")).await;
             } else {
                 log.error("缺少漫画的ID或者链接");
                 log.info("使用bcdown search <ID_OR_LINK> 来搜索漫画");
@@ -189,10 +534,22 @@ async fn main() {
                 println!("    3. 29911");
             }
         }
+        Some(("import", matches)) => {
+            cli::import(
+                matches.value_of("id_or_link").unwrap().to_string(),
+                matches.value_of("path").unwrap().into(),
+                matches.is_present("by_order"),
+                matches.value_of("range").unwrap_or_default().to_string(),
+            )
+            .await;
+        }
         Some(("fetch", matches)) => {
             if let Some(id_or_link) = matches.value_of("id_or_link") {
                 let range = matches.value_of("range").unwrap_or("").to_string();
-                lib::fetch(id_or_link.to_owned(), range).await;
+                let limit = matches.value_of("limit").map(str::to_string);
+                let quality = matches.value_of("quality").map(|q| q.parse().unwrap());
+                let format = matches.value_of("image_format").map(|f| f.parse().unwrap());
+                cli::fetch(id_or_link.to_owned(), range, limit, quality, format).await;
             } else {
                 log.error("缺少漫画的ID或者链接");
                 log.info("使用bcdown fetch <ID_OR_LINK> 来保存漫画");
@@ -212,6 +569,7 @@ async fn main() {
                     println!("    2. epub");
                     println!("    3. zip");
                     println!("    4. vol.zip");
+                    println!("    5. html");
                     return;
                 }
                 // let from = matches.value_of("from").unwrap_or("-1").parse::<f64>().unwrap();
@@ -228,18 +586,38 @@ async fn main() {
                     log.error("不能同时使用分组和拆分");
                     return;
                 }
-                let format = matches.value_of("format").unwrap();
-                if format != "epub" && format != "pdf" && format != "zip" && format != "vol.zip" {
-                    log.error("目前只支持导出 epub | pdf | zip | vol.zip 格式");
-                    return;
-                }
-                lib::export(
+                let webtoon = if matches.is_present("webtoon") {
+                    match matches.value_of("webtoon").map(|r| r.parse::<f64>()) {
+                        None => Some(None),
+                        Some(Ok(ratio)) if ratio > 0. => Some(Some(ratio)),
+                        _ => {
+                            log.error("条漫模式的高宽比必须是正数");
+                            return;
+                        }
+                    }
+                } else {
+                    None
+                };
+                let format = match matches.value_of("format").unwrap().parse() {
+                    Ok(format) => format,
+                    Err(e) => {
+                        log.error(e);
+                        return;
+                    }
+                };
+                cli::export(
                     id_or_link.to_owned(),
-                    range,
-                    grouping,
-                    split,
-                    matches.value_of("output"),
-                    format.to_owned(),
+                    bcdown::ExportOptions {
+                        range,
+                        grouping,
+                        split_episodes: split,
+                        export_dir: matches.value_of("output").map(Into::into),
+                        format,
+                        profile: matches.value_of("profile").map(str::to_string),
+                        webtoon,
+                        spread: matches.value_of("spread").map(|s| s.parse().unwrap()),
+                        direction: matches.value_of("direction").map(|s| s.parse().unwrap()),
+                    },
                 );
             } else {
                 log.error("缺少漫画的ID或者链接");
@@ -250,10 +628,38 @@ async fn main() {
                 println!("    3. 29911");
             }
         }
+        Some(("resume", matches)) => {
+            let job = match matches.value_of("job").map(|job| job.parse::<u64>()) {
+                None => None,
+                Some(Ok(job)) => Some(job),
+                Some(Err(_)) => {
+                    log.error("无效的任务ID");
+                    log.info("使用bcdown resume -l 查看未完成的任务");
+                    return;
+                }
+            };
+            cli::resume(
+                job,
+                matches.is_present("list"),
+                matches.is_present("discard"),
+                matches.value_of("limit").map(str::to_string),
+            )
+            .await;
+        }
+        Some((name @ ("serve" | "opds"), matches)) => {
+            let host = matches.value_of("host").unwrap();
+            let port = matches.value_of("port").unwrap();
+            match format!("{}:{}", host, port).parse() {
+                Ok(addr) => cli::serve(addr, name == "opds").await,
+                Err(_) => {
+                    log.error(format!("无效的监听地址：{}:{}", host, port));
+                }
+            }
+        }
         Some((_, _)) => {}
         None => {
             log.error("需要指定一个子命令!");
-            log.info("例如：\n\tbcdown login -q\t使用二维码登录\n\tbcdown info\t查看工具信息\n\tbcdown clear\t清理下载缓存\n\tbcdown list\t查看本地漫画列表\n\tbcdown search mc29911\t搜索漫画\n\tbcdown fetch mc29911\t下载漫画\n\tbcdown export mc29911 -f epub\t导出本地漫画");
+            log.info("例如：\n\tbcdown login -q\t使用二维码登录\n\tbcdown info\t查看工具信息\n\tbcdown clear\t清理下载缓存\n\tbcdown cache prune --keep 20\t按条件清理缓存\n\tbcdown list\t查看本地漫画列表\n\tbcdown search mc29911\t搜索漫画\n\tbcdown fetch mc29911\t下载漫画\n\tbcdown export mc29911 -f epub\t导出本地漫画\n\tbcdown resume\t继续未完成的任务\n\tbcdown serve\t启动本地漫画库\n\tbcdown opds\t启动OPDS目录");
         }
     }
 }